@@ -0,0 +1,74 @@
+use std::{
+    hint::black_box,
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix_web_idempotency::{IdempotencyStore, MemoryStore, Reservation};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::FutureExt;
+
+const THREADS: [usize; 3] = [1, 4, 16];
+
+// Reserves, looks up and releases `ops` distinct keys on each of `threads` threads at once,
+// returning how long the slowest thread took.
+fn run(store: &Arc<MemoryStore>, threads: usize, ops: u64) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads));
+
+    let workers: Vec<_> = (0..threads)
+        .map(|thread| {
+            let store = Arc::clone(store);
+            let barrier = Arc::clone(&barrier);
+
+            thread::spawn(move || {
+                let reservations: Vec<_> = (0..ops)
+                    .map(|op| Reservation::new(format!("{thread}-{op}")))
+                    .collect();
+
+                barrier.wait();
+                let start = Instant::now();
+                for reservation in &reservations {
+                    // the memory store never suspends, so its futures are ready right away
+                    black_box(store.reserve(reservation).now_or_never().unwrap().unwrap());
+                    black_box(
+                        store
+                            .get(reservation.key())
+                            .now_or_never()
+                            .unwrap()
+                            .unwrap(),
+                    );
+                    store.abort(reservation).now_or_never().unwrap().unwrap();
+                }
+                start.elapsed()
+            })
+        })
+        .collect();
+
+    workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap())
+        .max()
+        .unwrap_or_default()
+}
+
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("memory_store");
+
+    for threads in THREADS {
+        group.throughput(Throughput::Elements(threads as u64));
+
+        for (name, shards) in [("single_lock", 1), ("sharded", 16)] {
+            let store = Arc::new(MemoryStore::new().shards(shards));
+
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| run(&store, threads, iters))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
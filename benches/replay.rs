@@ -0,0 +1,161 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix_http::Request;
+use actix_web::{
+    dev::{Service, ServiceResponse},
+    rt, test, web, App, Error, HttpResponse,
+};
+use actix_web_idempotency::Idempotency;
+use criterion::{
+    criterion_group, criterion_main,
+    measurement::{Measurement, ValueFormatter},
+    Criterion, Throughput,
+};
+
+// Counts every byte allocated, so that copies of bodies show up in the figures.
+struct Counting;
+
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Bytes allocated while the benchmark runs, in place of the time it takes.
+struct AllocatedBytes;
+
+impl Measurement for AllocatedBytes {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        ALLOCATED.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl ValueFormatter for AllocatedBytes {
+    fn scale_values(&self, typical: f64, values: &mut [f64]) -> &'static str {
+        if typical < 1024.0 {
+            return "B";
+        }
+
+        for value in values {
+            *value /= 1024.0;
+        }
+        "KiB"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical: f64,
+        _throughput: &Throughput,
+        _values: &mut [f64],
+    ) -> &'static str {
+        "B"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+// A JSON response of a typical size, about 6 KiB.
+fn order() -> String {
+    let items: Vec<_> = (0..128)
+        .map(|item| format!(r#"{{"sku":"item-{item:05}","quantity":1,"price":"19.99"}}"#))
+        .collect();
+
+    format!(r#"{{"id":"order-1","items":[{}]}}"#, items.join(","))
+}
+
+// Sends an order with `key` through `app` and reads the response, as a client would.
+async fn call(app: &impl Service<Request, Response = ServiceResponse, Error = Error>, key: &str) {
+    let req = test::TestRequest::post()
+        .uri("/orders")
+        .insert_header(("Idempotency-Key", key))
+        .to_request();
+    let res = app.call(req).await.unwrap();
+    black_box(test::read_body(res).await);
+}
+
+fn capture_and_replay(c: &mut Criterion<AllocatedBytes>) {
+    let system = rt::System::new();
+    let body = web::Bytes::from(order());
+    let app = system.block_on(test::init_service(
+        App::new().wrap(Idempotency::new()).route(
+            "/orders",
+            web::post().to(move || {
+                let body = body.clone();
+                async move {
+                    HttpResponse::Created()
+                        .content_type("application/json")
+                        .body(body)
+                }
+            }),
+        ),
+    ));
+
+    let mut group = c.benchmark_group("json_response");
+
+    let mut key = 0u64;
+    group.bench_function("capture", |b| {
+        b.iter(|| {
+            key += 1;
+            system.block_on(call(&app, &key.to_string()))
+        })
+    });
+
+    system.block_on(call(&app, "replayed"));
+    group.bench_function("replay", |b| {
+        b.iter(|| system.block_on(call(&app, "replayed")))
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // every iteration allocates alike, which leaves nothing to plot
+    config = Criterion::default().with_measurement(AllocatedBytes).without_plots();
+    targets = capture_and_replay
+}
+criterion_main!(benches);
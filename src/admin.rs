@@ -0,0 +1,211 @@
+//! Administrative endpoints for operating an [`IdempotencyStore`].
+//!
+//! ```no_run
+//! use actix_web::{web, App};
+//! use actix_web_idempotency::{admin, Idempotency};
+//!
+//! let idempotency = Idempotency::new();
+//!
+//! let app = App::new()
+//!     .service(admin::scope("/admin/idempotency", idempotency.store()))
+//!     .service(web::scope("/api").wrap(idempotency));
+//! ```
+//!
+//! These endpoints are not protected by default. Either only mount them where they cannot be
+//! reached by untrusted clients, or put them behind a [`Guard`], such as [`bearer_token`]:
+//!
+//! ```no_run
+//! use actix_web::App;
+//! use actix_web_idempotency::{admin, Idempotency};
+//!
+//! let idempotency = Idempotency::new();
+//!
+//! let app = App::new().service(
+//!     admin::scope("/admin/idempotency", idempotency.store())
+//!         .guard(admin::bearer_token(std::env::var("ADMIN_TOKEN").unwrap())),
+//! );
+//! ```
+//!
+//! The health check alone can also be mounted on its own with [`health`],
+//! e.g. as a Kubernetes readiness probe:
+//!
+//! ```no_run
+//! use actix_web::App;
+//! use actix_web_idempotency::{admin, Idempotency};
+//!
+//! let idempotency = Idempotency::new();
+//!
+//! let app = App::new().service(admin::health("/idempotency/health", idempotency.store()));
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{
+    guard::{Guard, GuardContext},
+    http::header,
+    web, HttpResponse, Resource, Scope,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{IdempotencyStore, StoreError};
+
+/// Builds a scope at `path` serving:
+///
+/// - `GET {path}/maintenance`: whether a maintenance lock is held and for how long
+/// - `POST {path}/maintenance`: takes the lock, with a JSON body like `{"duration_secs": 30}`
+/// - `DELETE {path}/maintenance`: releases the lock early
+/// - `GET {path}/stats`: what the store holds, as a JSON object like `{"entries": 42, "hits": 7,
+///   "hit_ratio": 0.5, "oldest_entry_age_secs": 3600, ...}`. Figures the store does not track
+///   are `null`.
+/// - `GET {path}/health`: whether the store can be reached, see [`health`]
+pub fn scope(path: &str, store: Arc<dyn IdempotencyStore>) -> Scope {
+    web::scope(path)
+        .app_data(web::Data::from(store))
+        .service(
+            web::resource("/maintenance")
+                .route(web::get().to(maintenance_status))
+                .route(web::post().to(maintenance_start))
+                .route(web::delete().to(maintenance_stop)),
+        )
+        .service(web::resource("/stats").route(web::get().to(stats)))
+        .service(web::resource("/health").route(web::get().to(health_status)))
+}
+
+/// Guard admitting only requests carrying `Authorization: Bearer {token}`.
+///
+/// Requests without it are not routed to the guarded scope at all, so they get a `404 Not Found`
+/// rather than revealing that the endpoints exist.
+pub fn bearer_token(token: impl Into<String>) -> impl Guard {
+    let expected = format!("Bearer {}", token.into());
+
+    move |ctx: &GuardContext<'_>| {
+        ctx.head()
+            .headers()
+            .get(header::AUTHORIZATION)
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+    }
+}
+
+// compares without bailing out at the first difference, so that timing does not reveal how much
+// of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Builds a resource at `path` answering `GET` with whether the store can be reached.
+///
+/// Responds `200 OK` with a body like `{"status": "ok", "entries": 42, "maintenance": false}`
+/// while it can, and `503 Service Unavailable` with `{"status": "unavailable", "error": "..."}`
+/// otherwise. A maintenance lock does not fail the check, as requests are then rejected
+/// deliberately rather than because the store is down.
+pub fn health(path: &str, store: Arc<dyn IdempotencyStore>) -> Resource {
+    web::resource(path)
+        .app_data(web::Data::from(store))
+        .route(web::get().to(health_status))
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    duration_secs: u64,
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    locked: bool,
+    remaining_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    entries: usize,
+    reservations: Option<usize>,
+    memory_bytes: Option<usize>,
+    hits: Option<u64>,
+    misses: Option<u64>,
+    hit_ratio: Option<f64>,
+    oldest_entry_age_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn maintenance_status(store: web::Data<dyn IdempotencyStore>) -> HttpResponse {
+    match store.maintenance_remaining().await {
+        Ok(remaining) => HttpResponse::Ok().json(MaintenanceStatus {
+            locked: remaining.is_some(),
+            remaining_secs: remaining.map(crate::retry_after),
+        }),
+        Err(err) => store_error(err),
+    }
+}
+
+async fn maintenance_start(
+    store: web::Data<dyn IdempotencyStore>,
+    body: web::Json<MaintenanceRequest>,
+) -> HttpResponse {
+    let duration = Duration::from_secs(body.duration_secs);
+
+    match store.maintenance_lock(duration).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => store_error(err),
+    }
+}
+
+async fn maintenance_stop(store: web::Data<dyn IdempotencyStore>) -> HttpResponse {
+    match store.maintenance_unlock().await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => store_error(err),
+    }
+}
+
+async fn stats(store: web::Data<dyn IdempotencyStore>) -> HttpResponse {
+    match store.stats().await {
+        Ok(stats) => HttpResponse::Ok().json(Stats {
+            entries: stats.entries,
+            reservations: stats.reservations,
+            memory_bytes: stats.memory_bytes,
+            hits: stats.hits,
+            misses: stats.misses,
+            hit_ratio: stats.hit_ratio(),
+            oldest_entry_age_secs: stats.oldest_entry_age.map(|age| age.as_secs()),
+        }),
+        Err(err) => store_error(err),
+    }
+}
+
+async fn health_status(store: web::Data<dyn IdempotencyStore>) -> HttpResponse {
+    let checked = async {
+        store.health().await?;
+        Ok::<_, StoreError>((store.len().await?, store.maintenance_remaining().await?))
+    };
+
+    match checked.await {
+        Ok((entries, remaining)) => HttpResponse::Ok().json(Health {
+            status: "ok",
+            entries: Some(entries),
+            maintenance: Some(remaining.is_some()),
+            error: None,
+        }),
+        Err(err) => HttpResponse::ServiceUnavailable().json(Health {
+            status: "unavailable",
+            entries: None,
+            maintenance: None,
+            error: Some(err.to_string()),
+        }),
+    }
+}
+
+fn store_error(err: StoreError) -> HttpResponse {
+    match err {
+        StoreError::Unsupported => HttpResponse::NotImplemented().finish(),
+        err => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,123 @@
+use std::io;
+
+use actix_web::web::Bytes;
+use serde::{Deserialize, Serialize};
+
+// Small bodies barely shrink and are not worth the CPU time.
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+const DEFAULT_THRESHOLD: usize = 1024;
+
+/// Algorithm a stored body was compressed with.
+///
+/// Every algorithm is known to every build so that entries stay readable, but decompressing one
+/// requires its cargo feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+/// Compresses response bodies before they are stored, to keep large responses from bloating the
+/// store. Bodies are decompressed transparently when replayed.
+///
+/// Bodies smaller than the [`threshold`](Self::threshold) are stored as they are, as are bodies
+/// that would not get any smaller.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    codec: Codec,
+    level: i32,
+    threshold: usize,
+}
+
+impl Compression {
+    /// Compresses with zstd at `level`, from 1 to 22. Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(level: i32) -> Self {
+        Self {
+            codec: Codec::Zstd,
+            level,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Compresses with gzip at `level`, from 0 to 9. Requires the `gzip` feature.
+    #[cfg(feature = "gzip")]
+    pub fn gzip(level: u32) -> Self {
+        Self {
+            codec: Codec::Gzip,
+            level: i32::try_from(level).unwrap_or(9),
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Size in bytes below which bodies are stored uncompressed. Defaults to 1 KiB.
+    pub fn threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// Compresses `body` if that is worthwhile, returning the codec used if it was.
+    pub(crate) fn compress(&self, body: Bytes) -> (Bytes, Option<Codec>) {
+        if body.len() < self.threshold {
+            return (body, None);
+        }
+
+        match encode(self.codec, self.level, &body) {
+            Ok(compressed) if compressed.len() < body.len() => {
+                (compressed.into(), Some(self.codec))
+            }
+            Ok(_) => (body, None),
+            Err(err) => {
+                log::warn!("failed to compress response body, storing it uncompressed: {err}");
+                (body, None)
+            }
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(unused_variables))]
+fn encode(codec: Codec, level: i32, body: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::stream::encode_all(body, level),
+        #[cfg(feature = "gzip")]
+        Codec::Gzip => {
+            use std::io::Write;
+
+            let level = flate2::Compression::new(u32::try_from(level).unwrap_or(9));
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        #[allow(unreachable_patterns)]
+        codec => Err(unsupported(codec)),
+    }
+}
+
+#[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(unused_variables))]
+pub(crate) fn decompress(codec: Codec, body: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::stream::decode_all(body),
+        #[cfg(feature = "gzip")]
+        Codec::Gzip => {
+            use std::io::Read;
+
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(body).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+        #[allow(unreachable_patterns)]
+        codec => Err(unsupported(codec)),
+    }
+}
+
+// unused once every codec is enabled
+#[allow(dead_code)]
+fn unsupported(codec: Codec) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot decompress {codec:?} bodies, enable the corresponding cargo feature"),
+    )
+}
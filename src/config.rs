@@ -0,0 +1,71 @@
+use std::fmt;
+
+use actix_web::http::StatusCode;
+
+/// Why [`IdempotencyBuilder::build`](crate::IdempotencyBuilder::build) refused a configuration.
+///
+/// These are mistakes such as a zero TTL, which would otherwise only show up once requests
+/// come in and misbehave.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The [`ttl`](crate::IdempotencyBuilder::ttl) is zero, so no response would ever be
+    /// replayed.
+    ZeroTtl,
+    /// The [`gc_interval`](crate::IdempotencyBuilder::gc_interval) is zero.
+    ZeroGcInterval,
+    /// The [`ttl_jitter`](crate::IdempotencyBuilder::ttl_jitter) is 100% or more, which could
+    /// expire responses right away.
+    TtlJitter(u8),
+    /// [`max_pending`](crate::IdempotencyBuilder::max_pending) is zero, so every request with a
+    /// new key would be rejected.
+    ZeroMaxPending,
+    /// The [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout) is zero, so every
+    /// reservation would lapse right away.
+    ZeroPendingTimeout,
+    /// The [`KeyValidator`](crate::KeyValidator) accepts no key at all, as its minimum length is
+    /// above its maximum.
+    KeyLength { min: usize, max: usize },
+    /// The [`conflict_body`](crate::IdempotencyBuilder::conflict_body) template does not come out
+    /// as JSON, with the reason why.
+    ConflictBody(String),
+    /// [`MissingKey::Reject`](crate::MissingKey::Reject) was given a status that is not a client
+    /// or server error.
+    MissingKeyStatus(StatusCode),
+    /// Two options were set of which only one can take effect, by the names of the builder
+    /// methods setting them.
+    Conflicting(&'static str, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroTtl => f.write_str("idempotency ttl must not be zero"),
+            Self::ZeroGcInterval => f.write_str("idempotency gc interval must not be zero"),
+            Self::TtlJitter(percent) => {
+                write!(
+                    f,
+                    "idempotency ttl jitter must be below 100%, not {percent}%"
+                )
+            }
+            Self::ZeroMaxPending => f.write_str("max pending idempotent requests must not be zero"),
+            Self::ZeroPendingTimeout => f.write_str("idempotency pending timeout must not be zero"),
+            Self::KeyLength { min, max } => write!(
+                f,
+                "idempotency keys must be at least {min} and at most {max} characters long"
+            ),
+            Self::ConflictBody(err) => write!(f, "conflict body is not valid JSON: {err}"),
+            Self::MissingKeyStatus(status) => {
+                write!(
+                    f,
+                    "missing idempotency keys must be rejected with an error, not {status}"
+                )
+            }
+            Self::Conflicting(first, second) => {
+                write!(f, "idempotency options `{first}` and `{second}` conflict")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
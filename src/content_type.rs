@@ -0,0 +1,108 @@
+use actix_web::http::header::{self, HeaderMap};
+
+// Responses that are streamed to the client as they are produced, or are downloads that would
+// have to be held in memory as a whole.
+const STREAMING: [&str; 3] = [
+    "text/event-stream",
+    "application/octet-stream",
+    "multipart/x-mixed-replace",
+];
+
+// gRPC over HTTP/2 carries its status in trailers, which actix-web neither exposes to middleware
+// nor lets us send, so a replay would reach the client without one. gRPC-web encodes trailers in
+// the body instead and replays fine.
+const TRAILERS: [&str; 3] = [
+    "application/grpc",
+    "application/grpc+proto",
+    "application/grpc+json",
+];
+
+/// Decides by their `Content-Type` which responses are stored for replay.
+///
+/// Responses that are not stored are passed through to the client as they are, without being
+/// buffered, and their key is released so that a retry executes the request again.
+///
+/// Patterns are media types such as `application/json`, or `type/*` to match every subtype.
+/// Parameters like `charset` are ignored. By default every response is stored except the
+/// streaming types `text/event-stream`, `application/octet-stream` and
+/// `multipart/x-mixed-replace`, and gRPC responses (`application/grpc` with or without a
+/// `+proto` or `+json` suffix), whose trailers cannot be captured. Responses without a
+/// `Content-Type` are always stored.
+#[derive(Clone, Debug)]
+pub struct ContentTypeFilter {
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+}
+
+impl Default for ContentTypeFilter {
+    fn default() -> Self {
+        Self {
+            allow: None,
+            deny: STREAMING
+                .into_iter()
+                .chain(TRAILERS)
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+}
+
+impl ContentTypeFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores responses matching `pattern`, even if they are denied by default.
+    ///
+    /// Once any pattern is allowed, only responses matching an allowed pattern are stored.
+    pub fn allow(mut self, pattern: &str) -> Self {
+        let pattern = pattern.to_ascii_lowercase();
+        self.deny.retain(|denied| *denied != pattern);
+        self.allow.get_or_insert_with(Vec::new).push(pattern);
+        self
+    }
+
+    /// Never stores responses matching `pattern`.
+    pub fn deny(mut self, pattern: &str) -> Self {
+        let pattern = pattern.to_ascii_lowercase();
+        if let Some(allow) = &mut self.allow {
+            allow.retain(|allowed| *allowed != pattern);
+        }
+        self.deny.push(pattern);
+        self
+    }
+
+    /// Whether a response with `headers` should be stored.
+    pub(crate) fn stores(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(header::CONTENT_TYPE) else {
+            return true;
+        };
+
+        // a type we cannot make sense of cannot be one of the streaming ones either
+        let Ok(content_type) = content_type.to_str() else {
+            return self.allow.is_none();
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        !self.deny.iter().any(|pattern| matches(pattern, &essence))
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.iter().any(|pattern| matches(pattern, &essence)))
+    }
+}
+
+fn matches(pattern: &str, essence: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(kind) => essence
+            .split_once('/')
+            .is_some_and(|(essence, _)| essence == kind),
+        None => pattern == essence,
+    }
+}
@@ -0,0 +1,101 @@
+use std::{cell::RefCell, rc::Rc};
+
+use chrono::{DateTime, Utc};
+
+use crate::IdempotencyError;
+
+/// What the middleware decided for a request, see [`IdempotencyContext`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum IdempotencyOutcome {
+    /// The request is being handed to the handler, whose response is not in yet.
+    Executing,
+    /// The handler ran and its response was stored for replays.
+    Stored,
+    /// The handler ran, but its response was not stored, e.g. because it opted out through
+    /// [`NoStore`](crate::NoStore) or the store failed and the middleware fails open.
+    NotStored,
+    /// A stored response was replayed instead of running the handler.
+    Replayed,
+    /// The request was turned away with `error`, e.g. because another one with the same key was
+    /// still in progress.
+    Rejected(IdempotencyError),
+}
+
+#[derive(Debug)]
+struct State {
+    outcome: IdempotencyOutcome,
+    original_created_at: Option<DateTime<Utc>>,
+}
+
+/// What the middleware decided for the current request, for other middleware such as rate
+/// limiters or audit loggers.
+///
+/// The middleware puts it into the request's extensions once it has read the key, before
+/// calling any service wrapped by it, and updates it as the request goes along. Middleware
+/// wrapped by the idempotency middleware sees [`Executing`](IdempotencyOutcome::Executing),
+/// middleware wrapping it reads the final outcome off the response's request:
+///
+/// ```
+/// use actix_web::{dev::Service, App, HttpMessage};
+/// use actix_web_idempotency::{Idempotency, IdempotencyContext, IdempotencyOutcome};
+///
+/// let app = App::new()
+///     .wrap(Idempotency::new())
+///     .wrap_fn(|req, srv| {
+///         let res = srv.call(req);
+///         async move {
+///             let res = res.await?;
+///             if let Some(context) = res.request().extensions().get::<IdempotencyContext>() {
+///                 if matches!(context.outcome(), IdempotencyOutcome::Replayed) {
+///                     log::info!("replayed idempotency key {}", context.key());
+///                 }
+///             }
+///             Ok(res)
+///         }
+///     });
+/// ```
+///
+/// Only requests whose key was read get one.
+#[derive(Clone, Debug)]
+pub struct IdempotencyContext {
+    key: Rc<str>,
+    state: Rc<RefCell<State>>,
+}
+
+impl IdempotencyContext {
+    pub(crate) fn new(key: &str) -> Self {
+        Self {
+            key: key.into(),
+            state: Rc::new(RefCell::new(State {
+                outcome: IdempotencyOutcome::Executing,
+                original_created_at: None,
+            })),
+        }
+    }
+
+    /// The idempotency key sent with the request, or issued for it.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn outcome(&self) -> IdempotencyOutcome {
+        self.state.borrow().outcome.clone()
+    }
+
+    /// When the response that was stored or replayed was first produced.
+    pub fn original_created_at(&self) -> Option<DateTime<Utc>> {
+        self.state.borrow().original_created_at
+    }
+
+    pub(crate) fn finish(
+        &self,
+        outcome: IdempotencyOutcome,
+        original_created_at: Option<DateTime<Utc>>,
+    ) {
+        *self.state.borrow_mut() = State {
+            outcome,
+            original_created_at,
+        };
+    }
+}
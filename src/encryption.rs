@@ -0,0 +1,189 @@
+use std::{fmt, io};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+
+// AES-GCM's standard 96-bit nonce, stored in front of every ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Encrypts stored responses with AES-256-GCM, so that stores never see their headers, bodies,
+/// metadata or request ids in plain text.
+///
+/// Every entry gets a fresh random nonce. What the store needs to manage entries stays readable:
+/// the key, status, timestamps, fingerprint, compression, method and route. It is authenticated
+/// along with the ciphertext, so none of it can be altered, nor entries swapped between keys,
+/// without the encryption key.
+///
+/// Entries in plain text are refused once encryption is enabled, as anyone able to write to the
+/// store could plant them. Those stored before it was enabled can be replayed during a migration
+/// with [`accept_plaintext`](Self::accept_plaintext). Entries that are refused or fail to
+/// decrypt, e.g. because the key changed, are answered with `500 Internal Server Error`.
+///
+/// Requires the `encryption` feature.
+#[derive(Clone)]
+pub struct Encryption {
+    cipher: Aes256Gcm,
+    accept_plaintext: bool,
+}
+
+impl Encryption {
+    /// Encrypts with the 256-bit `key`, which has to be the same on every instance sharing a
+    /// store.
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+            accept_plaintext: false,
+        }
+    }
+
+    /// Replays entries stored in plain text, e.g. before encryption was enabled, rather than
+    /// refusing them. Meant for the transition only: turn it off again once those entries
+    /// expired, as it lets anyone able to write to the store plant responses.
+    pub fn accept_plaintext(mut self, accept: bool) -> Self {
+        self.accept_plaintext = accept;
+        self
+    }
+
+    pub(crate) fn accepts_plaintext(&self) -> bool {
+        self.accept_plaintext
+    }
+
+    // `aad` is authenticated but not encrypted
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| invalid("failed to encrypt stored response"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub(crate) fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid("encrypted response is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| invalid("failed to decrypt stored response"))
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("accept_plaintext", &self.accept_plaintext)
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::{http::StatusCode, web::Bytes, HttpResponse};
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{CacheElement, HeaderFilter};
+
+    fn element() -> CacheElement {
+        let response = HttpResponse::build(StatusCode::CREATED)
+            .insert_header(("location", "/orders/1"))
+            .finish()
+            .drop_body();
+
+        CacheElement::capture(
+            "order-1".to_owned(),
+            &response,
+            Bytes::from_static(b"{\"card\":\"4242\"}"),
+            chrono::Duration::hours(1),
+            &HeaderFilter::default(),
+            Utc::now(),
+        )
+        .with_fingerprint(Some("fingerprint".to_owned()))
+        .with_metadata(HashMap::from([("customer".to_owned(), "alice".into())]))
+    }
+
+    // rewrites a field of a sealed entry the way anyone able to write to the store could
+    fn tampered(sealed: &CacheElement, field: &str, value: serde_json::Value) -> CacheElement {
+        let mut json = serde_json::to_value(sealed).unwrap();
+        json[field] = value;
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn sealed_entries_open_again() {
+        let encryption = Encryption::new(&[7; 32]);
+        let sealed = element().seal(&encryption).unwrap();
+
+        assert!(sealed.is_encrypted());
+        assert!(sealed.headers().is_empty());
+        assert!(sealed.metadata().is_empty());
+        let raw = serde_json::to_string(&sealed).unwrap();
+        assert!(!raw.contains("alice") && !raw.contains("/orders/1"));
+
+        let opened = sealed.open(&encryption).unwrap();
+        let original = element();
+        assert_eq!(opened.body(), original.body());
+        assert_eq!(opened.headers(), original.headers());
+        assert_eq!(opened.metadata(), original.metadata());
+        assert!(!opened.is_encrypted());
+    }
+
+    #[test]
+    fn wrong_key_fails_to_open() {
+        let sealed = element().seal(&Encryption::new(&[7; 32])).unwrap();
+
+        assert!(sealed.open(&Encryption::new(&[8; 32])).is_err());
+    }
+
+    #[test]
+    fn tampered_entries_fail_to_open() {
+        let encryption = Encryption::new(&[7; 32]);
+        let sealed = element().seal(&encryption).unwrap();
+
+        for (field, value) in [
+            ("key", "order-2".into()),
+            ("status", 200.into()),
+            ("fingerprint", "other".into()),
+            ("expires_at", serde_json::to_value(Utc::now()).unwrap()),
+            ("route", "/orders".into()),
+        ] {
+            let tampered = tampered(&sealed, field, value);
+            assert!(tampered.open(&encryption).is_err(), "{field} was not bound");
+        }
+    }
+
+    #[test]
+    fn plaintext_entries_are_refused_unless_accepted() {
+        let planted = element();
+
+        assert!(planted.clone().open(&Encryption::new(&[7; 32])).is_err());
+
+        let migrating = Encryption::new(&[7; 32]).accept_plaintext(true);
+        assert_eq!(planted.open(&migrating).unwrap().body(), element().body());
+    }
+}
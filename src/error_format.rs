@@ -0,0 +1,83 @@
+use actix_web::http::StatusCode;
+
+use crate::IdempotencyError;
+
+/// How the bodies of rejections are rendered, see
+/// [`error_format`](crate::IdempotencyBuilder::error_format).
+///
+/// Every format carries the same code, e.g. `IN_PROGRESS`, and a description of the error. The
+/// cause of store failures is never revealed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorFormat {
+    /// JSON as described at [`IdempotencyError`], e.g. `{"error":"MALFORMED","message":"..."}`.
+    #[default]
+    Json,
+    /// The description alone, preceded by the code, e.g. `IN_PROGRESS: a request with this
+    /// idempotency key is in progress`.
+    PlainText,
+    /// `<error><code>IN_PROGRESS</code><message>...</message></error>`, for clients that
+    /// cannot parse JSON.
+    Xml,
+    /// [Problem details](https://www.rfc-editor.org/rfc/rfc9457) of type
+    /// `application/problem+json`, titled as the IETF draft suggests.
+    ProblemJson,
+}
+
+impl ErrorFormat {
+    /// The body of the response rejecting a request with `error` and `status`, and its content
+    /// type.
+    pub(crate) fn render(
+        self,
+        error: &IdempotencyError,
+        status: StatusCode,
+    ) -> (String, &'static str) {
+        match self {
+            Self::Json => (
+                serde_json::to_string(error).unwrap_or_default(),
+                "application/json",
+            ),
+            Self::PlainText => (
+                format!("{}: {}", code(error), error.public_message()),
+                "text/plain; charset=utf-8",
+            ),
+            Self::Xml => (
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                     <error><code>{}</code><message>{}</message></error>",
+                    code(error),
+                    xml_escape(&error.public_message()),
+                ),
+                "application/xml",
+            ),
+            Self::ProblemJson => (
+                error.problem_details(status).to_string(),
+                "application/problem+json",
+            ),
+        }
+    }
+}
+
+// The code the JSON rendering tags the error with, so that every format agrees on it.
+fn code(error: &IdempotencyError) -> String {
+    serde_json::to_value(error)
+        .ok()
+        .and_then(|value| Some(value.get("error")?.as_str()?.to_owned()))
+        .unwrap_or_default()
+}
+
+// `value` as it has to appear between XML tags.
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
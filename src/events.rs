@@ -0,0 +1,96 @@
+use std::{collections::HashMap, sync::Arc};
+
+use actix_web::{
+    http::{Method, StatusCode},
+    HttpRequest,
+};
+use serde_json::Value;
+
+use crate::{CircuitState, IdempotencyError};
+
+/// What the middleware knows about a request it made a decision on.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct IdempotencyEvent {
+    /// The idempotency key sent with the request, if it was readable at all.
+    pub key: Option<String>,
+    pub method: Method,
+    pub path: String,
+    /// Pattern of the route the request matched, e.g. `/orders/{id}`.
+    pub route: Option<String>,
+    /// Status of the response sent back to the client.
+    pub status: StatusCode,
+    /// What the handler recorded through [`IdempotencyMetadata`](crate::IdempotencyMetadata),
+    /// for stored and replayed responses.
+    pub metadata: HashMap<String, Value>,
+}
+
+impl IdempotencyEvent {
+    pub(crate) fn new(req: &HttpRequest, key: Option<&str>, status: StatusCode) -> Self {
+        Self {
+            key: key.map(str::to_owned),
+            method: req.method().clone(),
+            path: req.path().to_owned(),
+            route: req.match_pattern(),
+            status,
+            metadata: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn with_metadata(mut self, metadata: HashMap<String, Value>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// Hook for auditing the middleware's decisions, e.g. by emitting events to a log pipeline.
+///
+/// Every method defaults to doing nothing, so implementors only override what they need.
+pub trait IdempotencyEvents: Send + Sync {
+    /// The handler ran and its response was stored for replays.
+    fn on_store(&self, _event: &IdempotencyEvent) {}
+
+    /// A stored response was replayed instead of running the handler.
+    fn on_replay(&self, _event: &IdempotencyEvent) {}
+
+    /// The request arrived while another one with the same key was still being processed.
+    fn on_conflict(&self, _event: &IdempotencyEvent) {}
+
+    /// The request was turned away before reaching the handler, e.g. because its key was
+    /// missing or malformed.
+    fn on_reject(&self, _event: &IdempotencyEvent, _error: &IdempotencyError) {}
+
+    /// The request's body was too large to be fingerprinted, so only its headers were, see
+    /// [`OversizedBody::HeadersOnly`](crate::OversizedBody::HeadersOnly). Its response is not
+    /// known yet, so the event carries `200 OK`.
+    fn on_fingerprint_fallback(&self, _event: &IdempotencyEvent) {}
+
+    /// A [`CircuitBreakerStore`](crate::CircuitBreakerStore) changed from `from` to `to`.
+    fn on_circuit_change(&self, _from: CircuitState, _to: CircuitState) {}
+}
+
+impl<T: IdempotencyEvents + ?Sized> IdempotencyEvents for Arc<T> {
+    fn on_store(&self, event: &IdempotencyEvent) {
+        (**self).on_store(event)
+    }
+
+    fn on_replay(&self, event: &IdempotencyEvent) {
+        (**self).on_replay(event)
+    }
+
+    fn on_conflict(&self, event: &IdempotencyEvent) {
+        (**self).on_conflict(event)
+    }
+
+    fn on_reject(&self, event: &IdempotencyEvent, error: &IdempotencyError) {
+        (**self).on_reject(event, error)
+    }
+
+    fn on_fingerprint_fallback(&self, event: &IdempotencyEvent) {
+        (**self).on_fingerprint_fallback(event)
+    }
+
+    fn on_circuit_change(&self, from: CircuitState, to: CircuitState) {
+        (**self).on_circuit_change(from, to)
+    }
+}
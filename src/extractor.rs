@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    http::header::HeaderName,
+    web::{BytesMut, Query},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::{
+    future::{ready, LocalBoxFuture},
+    StreamExt,
+};
+
+use crate::{IdempotencyError, HEADER_KEY};
+
+// Enough for any JSON request carrying a key alongside a reasonably sized payload.
+const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
+
+/// Finds the idempotency key of a request.
+///
+/// Keys found are still checked by the [`KeyValidator`](crate::KeyValidator). Closures taking
+/// a `&ServiceRequest` implement this trait too, for keys that can be read without touching
+/// the body:
+///
+/// ```
+/// use actix_web::dev::ServiceRequest;
+/// use actix_web_idempotency::{Idempotency, IdempotencyError};
+///
+/// let idempotency = Idempotency::builder()
+///     .key_extractor(|req: &ServiceRequest| {
+///         let key = req.match_info().get("order_id").map(str::to_owned);
+///         Ok::<_, IdempotencyError>(key)
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub trait KeyExtractor: Send + Sync {
+    /// Returns the key of `req`, or `None` if it has none, which is rejected with
+    /// [`IdempotencyError::Missing`].
+    ///
+    /// Extractors reading the body have to hand it back with
+    /// [`set_payload`](ServiceRequest::set_payload), so that the handler still gets to see it.
+    fn extract<'a>(
+        &'a self,
+        req: &'a mut ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<String>, IdempotencyError>>;
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&ServiceRequest) -> Result<Option<String>, IdempotencyError> + Send + Sync,
+{
+    fn extract<'a>(
+        &'a self,
+        req: &'a mut ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<String>, IdempotencyError>> {
+        Box::pin(ready(self(req)))
+    }
+}
+
+/// Reads the key from a request header, `Idempotency-Key` by default.
+///
+/// Further headers can be accepted with [`or`](Self::or), e.g. for legacy clients sending
+/// `X-Request-Id` instead. The first of them present in a request is used, and recorded as the
+/// request's [`KeyHeader`] and in the stored [`CacheElement`](crate::CacheElement).
+///
+/// ```
+/// use actix_web::http::header::HeaderName;
+/// use actix_web_idempotency::{HeaderKey, Idempotency};
+///
+/// let idempotency = Idempotency::builder()
+///     .key_extractor(HeaderKey::default().or(HeaderName::from_static("x-request-id")))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Requests sending the header used more than once are rejected with
+/// [`IdempotencyError::DuplicateHeader`].
+#[derive(Clone, Debug)]
+pub struct HeaderKey {
+    names: Vec<HeaderName>,
+}
+
+impl Default for HeaderKey {
+    fn default() -> Self {
+        Self::new(HeaderName::from_static(HEADER_KEY))
+    }
+}
+
+impl HeaderKey {
+    pub fn new(name: HeaderName) -> Self {
+        Self { names: vec![name] }
+    }
+
+    /// Also accepts the key in `name`, for requests lacking every header accepted so far.
+    pub fn or(mut self, name: HeaderName) -> Self {
+        self.names.push(name);
+        self
+    }
+}
+
+impl KeyExtractor for HeaderKey {
+    fn extract<'a>(
+        &'a self,
+        req: &'a mut ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<String>, IdempotencyError>> {
+        let Some(name) = self
+            .names
+            .iter()
+            .find(|name| req.headers().contains_key(*name))
+        else {
+            return Box::pin(ready(Ok(None)));
+        };
+
+        let mut keys = req.headers().get_all(name);
+
+        let key = match (keys.next(), keys.next()) {
+            (None, _) => Ok(None),
+            (Some(_), Some(_)) => Err(IdempotencyError::DuplicateHeader),
+            (Some(key), None) => key.to_str().map(|key| Some(key.to_owned())).map_err(|_| {
+                IdempotencyError::Malformed(
+                    "idempotency key must only contain visible ASCII characters".to_owned(),
+                )
+            }),
+        };
+
+        req.extensions_mut().insert(KeyHeader(name.clone()));
+        Box::pin(ready(key))
+    }
+}
+
+/// The header a [`HeaderKey`] read the request's key from, put into the request's extensions.
+///
+/// Handlers can extract it to tell which of several accepted headers a client used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyHeader(HeaderName);
+
+impl KeyHeader {
+    pub fn name(&self) -> &HeaderName {
+        &self.0
+    }
+}
+
+impl FromRequest for KeyHeader {
+    type Error = Error;
+
+    type Future = futures_util::future::Ready<Result<Self, Self::Error>>;
+
+    // also fails for requests whose key came from elsewhere, which `Option<KeyHeader>` tolerates
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let header = req.extensions().get::<Self>().cloned();
+        ready(header.ok_or_else(|| IdempotencyError::Missing.into()))
+    }
+}
+
+/// Reads the key from a query parameter, e.g. `?idempotency_key=...`, for clients that cannot
+/// set headers.
+#[derive(Clone, Debug)]
+pub struct QueryKey {
+    name: String,
+}
+
+impl QueryKey {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl KeyExtractor for QueryKey {
+    fn extract<'a>(
+        &'a self,
+        req: &'a mut ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<String>, IdempotencyError>> {
+        let key = Query::<HashMap<String, String>>::from_query(req.query_string())
+            .map(|query| query.into_inner().remove(&self.name))
+            .map_err(|_| IdempotencyError::Malformed("query string is malformed".to_owned()));
+
+        Box::pin(ready(key))
+    }
+}
+
+/// Reads the key from a JSON request body, at a [JSON pointer] like `/idempotency_key`.
+///
+/// The body is buffered to be searched and handed on to the handler as it arrived. Bodies
+/// larger than [`limit`](Self::limit) are rejected with [`IdempotencyError::TooLarge`], and
+/// bodies that are not JSON are treated as carrying no key.
+///
+/// [JSON pointer]: https://www.rfc-editor.org/rfc/rfc6901
+#[derive(Clone, Debug)]
+pub struct JsonBodyKey {
+    pointer: String,
+    limit: usize,
+}
+
+impl JsonBodyKey {
+    pub fn new(pointer: impl Into<String>) -> Self {
+        Self {
+            pointer: pointer.into(),
+            limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// Largest body searched for a key, in bytes. Defaults to 64 KiB.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+}
+
+impl KeyExtractor for JsonBodyKey {
+    fn extract<'a>(
+        &'a self,
+        req: &'a mut ServiceRequest,
+    ) -> LocalBoxFuture<'a, Result<Option<String>, IdempotencyError>> {
+        Box::pin(async move {
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|_| {
+                    IdempotencyError::Malformed("request body could not be read".to_owned())
+                })?;
+                if body.len() + chunk.len() > self.limit {
+                    return Err(IdempotencyError::TooLarge);
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            let body = body.freeze();
+            let key = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|json| match json.pointer(&self.pointer)? {
+                    serde_json::Value::String(key) => Some(key.clone()),
+                    serde_json::Value::Number(key) => Some(key.to_string()),
+                    _ => None,
+                });
+
+            req.set_payload(Payload::from(body));
+
+            Ok(key)
+        })
+    }
+}
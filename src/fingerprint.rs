@@ -0,0 +1,301 @@
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    http::header,
+    web::Bytes,
+    Error, HttpMessage,
+};
+use futures_util::{stream, StreamExt};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+/// Hashes requests into the fingerprints stored alongside their responses.
+///
+/// Fingerprints of stored responses are compared as they are, so switching to another hasher
+/// makes retries of requests fingerprinted before the switch fail with
+/// [`IdempotencyError::Mismatch`](crate::IdempotencyError::Mismatch) until those expire.
+pub trait FingerprintHasher: Send + Sync {
+    /// Hashes `parts`, the request's method, path with query string and body in that order,
+    /// into a string.
+    fn hash(&self, parts: &[&[u8]]) -> String;
+
+    /// Starts hashing a request piece by piece, so that its body is hashed as it arrives. The
+    /// result must be the same [`hash`](Self::hash) gives for the same parts.
+    ///
+    /// Defaults to collecting the parts and hashing them in one go once they are complete.
+    fn digest(&self) -> Box<dyn FingerprintDigest + '_> {
+        Box::new(Collecting {
+            hasher: self,
+            parts: vec![Vec::new()],
+        })
+    }
+}
+
+/// Hash of a request in progress, see [`FingerprintHasher::digest`].
+pub trait FingerprintDigest: Send {
+    /// Adds `bytes` to the part being hashed.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Completes the part being hashed, starting the next one.
+    fn next_part(&mut self);
+
+    fn finish(self: Box<Self>) -> String;
+}
+
+struct Collecting<'a, H: ?Sized> {
+    hasher: &'a H,
+    parts: Vec<Vec<u8>>,
+}
+
+impl<H: FingerprintHasher + ?Sized> FingerprintDigest for Collecting<'_, H> {
+    fn update(&mut self, bytes: &[u8]) {
+        if let Some(part) = self.parts.last_mut() {
+            part.extend_from_slice(bytes);
+        }
+    }
+
+    fn next_part(&mut self) {
+        self.parts.push(Vec::new());
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        let parts: Vec<&[u8]> = self.parts.iter().map(Vec::as_slice).collect();
+        self.hasher.hash(&parts)
+    }
+}
+
+// Parts are separated by NUL bytes, which occur in neither method nor path.
+struct Sha<D>(D);
+
+impl<D: Digest + Send> FingerprintDigest for Sha<D>
+where
+    sha2::digest::Output<D>: std::fmt::LowerHex,
+{
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn next_part(&mut self) {
+        self.0.update([0]);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
+
+/// Hex encoded SHA-256, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha256Hasher;
+
+impl FingerprintHasher for Sha256Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        format!("{:x}", digest::<Sha256>(parts))
+    }
+
+    fn digest(&self) -> Box<dyn FingerprintDigest + '_> {
+        Box::new(Sha(Sha256::new()))
+    }
+}
+
+/// Hex encoded SHA-384, e.g. for environments restricted to FIPS approved algorithms that
+/// require more than 128 bits of collision resistance.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha384Hasher;
+
+impl FingerprintHasher for Sha384Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        format!("{:x}", digest::<Sha384>(parts))
+    }
+
+    fn digest(&self) -> Box<dyn FingerprintDigest + '_> {
+        Box::new(Sha(Sha384::new()))
+    }
+}
+
+/// Hex encoded SHA-512.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha512Hasher;
+
+impl FingerprintHasher for Sha512Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        format!("{:x}", digest::<Sha512>(parts))
+    }
+
+    fn digest(&self) -> Box<dyn FingerprintDigest + '_> {
+        Box::new(Sha(Sha512::new()))
+    }
+}
+
+/// Hex encoded 128-bit XXH3, several times faster than SHA-256 on large bodies.
+///
+/// It is not a cryptographic hash, so a client can craft two different requests with the same
+/// fingerprint. That only lets clients replay responses to their own keys, but keep to a SHA
+/// hasher where fingerprints must hold up against deliberate collisions.
+///
+/// Requires the `xxhash` feature.
+#[cfg(feature = "xxhash")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Xxh3Hasher;
+
+#[cfg(feature = "xxhash")]
+impl FingerprintHasher for Xxh3Hasher {
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                hasher.update(&[0]);
+            }
+            hasher.update(part);
+        }
+
+        format!("{:032x}", hasher.digest128())
+    }
+
+    fn digest(&self) -> Box<dyn FingerprintDigest + '_> {
+        Box::new(Xxh3(xxhash_rust::xxh3::Xxh3::new()))
+    }
+}
+
+#[cfg(feature = "xxhash")]
+struct Xxh3(xxhash_rust::xxh3::Xxh3);
+
+#[cfg(feature = "xxhash")]
+impl FingerprintDigest for Xxh3 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn next_part(&mut self) {
+        self.0.update(&[0]);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:032x}", self.0.digest128())
+    }
+}
+
+fn digest<D: Digest>(parts: &[&[u8]]) -> sha2::digest::Output<D> {
+    let mut hasher = D::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hasher.update([0]);
+        }
+        hasher.update(part);
+    }
+
+    hasher.finalize()
+}
+
+/// What [`fingerprint`](crate::IdempotencyBuilder::fingerprint) does with request bodies
+/// larger than it buffers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OversizedBody {
+    /// Rejects the request with `413 Payload Too Large` and
+    /// [`IdempotencyError::TooLarge`](crate::IdempotencyError::TooLarge). The default.
+    #[default]
+    Reject,
+    /// Fingerprints the request by its method, path with query string, media type and
+    /// `Content-Length` alone, and streams the body on to the handler as it arrives, e.g. for
+    /// large multipart uploads.
+    ///
+    /// Parameters of the media type, such as the boundary of a multipart body, are left out, as
+    /// they differ between retries of the same upload. Every fallback is logged and reported to
+    /// [`IdempotencyEvents::on_fingerprint_fallback`](crate::IdempotencyEvents::on_fingerprint_fallback).
+    HeadersOnly,
+}
+
+// The path along with the query string, which may hold as much of a request as its body does.
+// Requests without one are hashed by their path alone, as before queries were included.
+fn target(req: &ServiceRequest) -> &str {
+    req.uri()
+        .path_and_query()
+        .map_or_else(|| req.path(), |target| target.as_str())
+}
+
+/// Outcome of hashing a request.
+pub(crate) enum Fingerprint {
+    /// The request's method, path with query string and body as hashed by the configured
+    /// [`FingerprintHasher`].
+    Hash(String),
+    /// The body exceeded the configured limit, so the request was hashed without it.
+    HeadersOnly(String),
+    /// The body exceeded the configured limit and was not hashed.
+    TooLarge,
+}
+
+/// Hashes the request body as it arrives and hands it back to the request, so that extractors
+/// further down still see the payload exactly as it arrived.
+///
+/// Bodies have to be buffered to be handed back, which is why anything above `limit` bytes is
+/// refused or, with [`OversizedBody::HeadersOnly`], passed on unhashed.
+pub(crate) async fn fingerprint(
+    req: &mut ServiceRequest,
+    limit: usize,
+    hasher: &dyn FingerprintHasher,
+    oversized: OversizedBody,
+) -> Result<Fingerprint, Error> {
+    let mut payload = req.take_payload();
+    let mut digest = hasher.digest();
+    digest.update(req.method().as_str().as_bytes());
+    digest.next_part();
+    digest.update(target(req).as_bytes());
+    digest.next_part();
+
+    // kept as they came, rather than copied into one buffer
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut len = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        len += chunk.len();
+
+        if len > limit {
+            chunks.push(chunk);
+            if oversized == OversizedBody::Reject {
+                return Ok(Fingerprint::TooLarge);
+            }
+
+            // what was read already goes first, the rest follows straight from the client
+            let read = stream::iter(chunks.into_iter().map(Ok));
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(read.chain(payload)),
+            });
+
+            return Ok(Fingerprint::HeadersOnly(headers_only(req, hasher)));
+        }
+        digest.update(&chunk);
+        chunks.push(chunk);
+    }
+
+    let body = stream::iter(chunks.into_iter().map(Ok));
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(body),
+    });
+
+    Ok(Fingerprint::Hash(digest.finish()))
+}
+
+// The fingerprint of a request whose body is left out, told apart from any hashed with its body
+// by an extra part.
+fn headers_only(req: &ServiceRequest, hasher: &dyn FingerprintHasher) -> String {
+    let media_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+
+    hasher.hash(&[
+        req.method().as_str().as_bytes(),
+        target(req).as_bytes(),
+        b"headers-only",
+        media_type.as_bytes(),
+        length,
+    ])
+}
@@ -0,0 +1,102 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    dev::Payload, error::ErrorInternalServerError, Error, FromRequest, HttpMessage, HttpRequest,
+};
+
+/// Proof that the current request holds the reservation of its idempotency key, so that it is
+/// the first to execute with it while that reservation lasts.
+///
+/// The middleware puts it into the request's extensions only once the store
+/// [reserved](crate::IdempotencyStore::reserve) the key for this request, which stores do
+/// atomically. Any other request with the same key, on this instance or another one sharing the
+/// store, is replayed the response, rejected or kept waiting instead for as long as the
+/// reservation is held, so handlers can rely on it before performing side effects that must not
+/// happen twice, such as sending an email.
+///
+/// Extracting it fails with `500 Internal Server Error` if it is missing, e.g. because the store
+/// failed and the middleware [fails open](crate::FailureMode::Open), or the request is not
+/// covered by the middleware at all. Extract an `Option<FirstExecution>` to handle that instead.
+///
+/// ```
+/// use std::{
+///     sync::atomic::{AtomicUsize, Ordering},
+///     time::Duration,
+/// };
+///
+/// use actix_web::{rt::time::sleep, test, web, App, HttpResponse};
+/// use actix_web_idempotency::{FirstExecution, Idempotency};
+///
+/// static EMAILS_SENT: AtomicUsize = AtomicUsize::new(0);
+///
+/// async fn send_email(_first: FirstExecution) -> HttpResponse {
+///     sleep(Duration::from_millis(50)).await;
+///     EMAILS_SENT.fetch_add(1, Ordering::SeqCst);
+///     HttpResponse::Accepted().finish()
+/// }
+///
+/// # actix_web::rt::System::new().block_on(async {
+/// let app = test::init_service(
+///     App::new()
+///         .wrap(Idempotency::new())
+///         .route("/emails", web::post().to(send_email)),
+/// )
+/// .await;
+///
+/// let request = || {
+///     test::TestRequest::post()
+///         .uri("/emails")
+///         .insert_header(("Idempotency-Key", "welcome-42"))
+///         .to_request()
+/// };
+/// // duplicates arriving while the first one is still executing
+/// let (first, duplicate) = futures_util::join!(
+///     test::call_service(&app, request()),
+///     test::call_service(&app, request()),
+/// );
+/// let retry = test::call_service(&app, request()).await;
+///
+/// assert_eq!(first.status(), 202);
+/// assert_eq!(duplicate.status(), 409);
+/// assert_eq!(retry.status(), 202);
+/// assert_eq!(EMAILS_SENT.load(Ordering::SeqCst), 1);
+/// # });
+/// ```
+///
+/// The guarantee covers a single execution per reservation, not per key. If the handler fails
+/// or its response is not stored, e.g. because of [`NoStore`](crate::NoStore), the key is
+/// released again and the next request with it executes as the first one once more. The same
+/// happens when a handler outlives its
+/// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout), or when a store such as
+/// `MokaStore` evicts the reservation under memory pressure, so keep side effects that must
+/// never repeat idempotent on their own as well.
+#[derive(Clone, Debug)]
+pub struct FirstExecution {
+    key: Rc<str>,
+}
+
+impl FirstExecution {
+    pub(crate) fn new(key: &str) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// The idempotency key that was reserved.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl FromRequest for FirstExecution {
+    type Error = Error;
+
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Self>().cloned().ok_or_else(|| {
+            ErrorInternalServerError("request holds no reservation of its idempotency key")
+        }))
+    }
+}
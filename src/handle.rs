@@ -0,0 +1,186 @@
+use std::{borrow::Cow, sync::Arc};
+
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "key-hashing")]
+use crate::KeyHashing;
+use crate::{
+    CacheElement, Clock, HeaderFilter, IdempotencyStore, Reservation, StoreError, SystemClock,
+    DEFAULT_TTL,
+};
+
+// Records kept by application code live apart from stored responses. Idempotency keys are
+// visible ASCII, so they can never collide with these.
+const RECORD: &str = "\0record:";
+
+/// Cloneable handle for inspecting and purging entries of an [`IdempotencyStore`], e.g. from
+/// admin endpoints or support tooling.
+///
+/// Obtain one through [`Idempotency::handle`](crate::Idempotency::handle) to operate on the
+/// store the middleware uses, or wrap a store directly with [`IdempotencyHandle::new`].
+///
+/// Handlers can also use it to make their own side effects exactly-once, beyond the response
+/// the middleware stores. Records are kept apart from stored responses, so a handler may claim
+/// the very key of the request it is handling:
+///
+/// ```
+/// use actix_web::{web, HttpRequest, HttpResponse};
+/// use actix_web_idempotency::{IdempotencyError, IdempotencyHandle};
+///
+/// async fn pay(
+///     req: HttpRequest,
+///     handle: web::Data<IdempotencyHandle>,
+/// ) -> Result<HttpResponse, IdempotencyError> {
+///     let key = req.headers().get("Idempotency-Key").and_then(|key| key.to_str().ok());
+///     let key = key.ok_or(IdempotencyError::Missing)?;
+///
+///     let order = if let Some(reservation) = handle.reserve(key).await? {
+///         let order = 123; // charge the card and create the order
+///         handle.complete(&reservation, &order).await?;
+///         order
+///     } else {
+///         match handle.lookup::<u64>(key).await? {
+///             Some(order) => order,
+///             None => return Ok(HttpResponse::Conflict().finish()),
+///         }
+///     };
+///
+///     Ok(HttpResponse::Ok().json(order))
+/// }
+/// ```
+///
+/// Register it with `App::app_data(web::Data::new(idempotency.handle()))`.
+#[derive(Clone)]
+pub struct IdempotencyHandle {
+    store: Arc<dyn IdempotencyStore>,
+    ttl: chrono::Duration,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "key-hashing")]
+    key_hashing: Option<KeyHashing>,
+}
+
+impl IdempotencyHandle {
+    pub fn new(store: impl IdempotencyStore + 'static) -> Self {
+        Self::from(Arc::new(store) as Arc<dyn IdempotencyStore>)
+    }
+
+    pub(crate) fn with_ttl(mut self, ttl: chrono::Duration, clock: Arc<dyn Clock>) -> Self {
+        self.ttl = ttl;
+        self.clock = clock;
+        self
+    }
+
+    #[cfg(feature = "key-hashing")]
+    pub(crate) fn with_key_hashing(mut self, hashing: Option<KeyHashing>) -> Self {
+        self.key_hashing = hashing;
+        self
+    }
+
+    // what `key` is stored under, hashed like the middleware hashes it
+    fn name<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        #[cfg(feature = "key-hashing")]
+        if let Some(hashing) = &self.key_hashing {
+            return Cow::Owned(hashing.hash(key));
+        }
+
+        Cow::Borrowed(key)
+    }
+
+    fn record(&self, key: &str) -> String {
+        self.name(&format!("{RECORD}{key}")).into_owned()
+    }
+
+    /// The response stored for `key`, if it has not expired yet.
+    pub async fn get(&self, key: &str) -> Result<Option<CacheElement>, StoreError> {
+        self.store.get(&self.name(key)).await
+    }
+
+    /// Forgets `key`, so that the next request using it is executed again, e.g. after a
+    /// refund reversed the operation it performed.
+    ///
+    /// Returns whether anything was stored for it.
+    pub async fn invalidate(&self, key: &str) -> Result<bool, StoreError> {
+        self.store.remove(&self.name(key)).await
+    }
+
+    /// Number of entries in the store, see [`IdempotencyStore::len`].
+    pub async fn len(&self) -> Result<usize, StoreError> {
+        self.store.len().await
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, StoreError> {
+        self.store.is_empty().await
+    }
+
+    /// Drops every entry in the store.
+    pub async fn clear(&self) -> Result<(), StoreError> {
+        self.store.clear().await
+    }
+
+    /// Claims `key` for a side effect about to be performed, e.g. charging a card.
+    ///
+    /// Returns the claim to [`complete`](Self::complete) or [`release`](Self::release) it with,
+    /// or `None` if the key was claimed already, in which case the side effect must not be
+    /// performed. Its outcome can be looked up with [`lookup`](Self::lookup) once it completed.
+    pub async fn reserve(&self, key: &str) -> Result<Option<Reservation>, StoreError> {
+        let reservation = Reservation::new(self.record(key));
+
+        Ok(self
+            .store
+            .reserve(&reservation)
+            .await?
+            .then_some(reservation))
+    }
+
+    /// Records the outcome of the side effect performed under `reservation`, e.g. the id of the
+    /// order it created. It is kept as long as the middleware keeps responses.
+    ///
+    /// Returns `false` if an outcome was recorded already, which is then kept.
+    pub async fn complete(
+        &self,
+        reservation: &Reservation,
+        metadata: &impl Serialize,
+    ) -> Result<bool, StoreError> {
+        let metadata = serde_json::to_vec(metadata).map_err(StoreError::backend)?;
+        let element = CacheElement::capture(
+            reservation.key().to_owned(),
+            &HttpResponse::with_body(StatusCode::OK, ()),
+            metadata.into(),
+            self.ttl,
+            &HeaderFilter::default(),
+            self.clock.now(),
+        );
+
+        self.store.commit(reservation, element).await
+    }
+
+    /// Gives up `reservation` without recording an outcome, e.g. because the side effect failed
+    /// and may be attempted again.
+    pub async fn release(&self, reservation: &Reservation) -> Result<(), StoreError> {
+        self.store.abort(reservation).await
+    }
+
+    /// The outcome recorded for `key`, if any.
+    pub async fn lookup<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StoreError> {
+        let Some(element) = self.store.get(&self.record(key)).await? else {
+            return Ok(None);
+        };
+
+        serde_json::from_slice(element.body())
+            .map(Some)
+            .map_err(StoreError::backend)
+    }
+}
+
+impl From<Arc<dyn IdempotencyStore>> for IdempotencyHandle {
+    fn from(store: Arc<dyn IdempotencyStore>) -> Self {
+        Self {
+            store,
+            ttl: chrono::Duration::from_std(DEFAULT_TTL).unwrap_or(chrono::Duration::MAX),
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "key-hashing")]
+            key_hashing: None,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+
+use actix_web::http::header::{self, HeaderMap, HeaderName};
+
+// Connection-level headers (RFC 9110, section 7.6.1). They describe the hop the original
+// response travelled on and are never stored, whatever the filter says.
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+// Headers tied to the moment or the client the response was first produced for, which are dropped
+// by default. `Content-Length` is recomputed from the stored body on replay.
+const VOLATILE: [HeaderName; 3] = [header::DATE, header::SET_COOKIE, header::CONTENT_LENGTH];
+
+/// Whether `name` only applies to a single connection. Such headers must not be forwarded, and
+/// are even a protocol error over HTTP/2.
+pub(crate) fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP.contains(name)
+}
+
+/// How the headers a [`HeaderFilter`] keeps are written down. Either way values are stored as
+/// raw bytes, so values that are not UTF-8 replay unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HeaderCanonicalization {
+    /// Every value is stored as a header of its own, in the order the handler set the values of
+    /// each name, and replayed exactly so.
+    #[default]
+    Exact,
+    /// Headers are sorted by name and the values of a header set more than once joined with
+    /// `, `, so that responses differing only in how their headers were set are stored alike.
+    /// `Set-Cookie` values are never joined, as they may contain commas themselves.
+    Combined,
+}
+
+/// Decides which response headers are stored alongside a cached response and replayed with it.
+///
+/// Hop-by-hop headers, including any named in the response's `Connection` header, are always
+/// stripped. On top of that `Date`, `Set-Cookie` and `Content-Length` are denied by default.
+#[derive(Clone, Debug)]
+pub struct HeaderFilter {
+    allow: Option<HashSet<HeaderName>>,
+    deny: HashSet<HeaderName>,
+    canonicalization: HeaderCanonicalization,
+}
+
+impl Default for HeaderFilter {
+    fn default() -> Self {
+        Self {
+            allow: None,
+            deny: VOLATILE.into_iter().collect(),
+            canonicalization: HeaderCanonicalization::default(),
+        }
+    }
+}
+
+impl HeaderFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores and replays `name`, even if it is denied by default.
+    ///
+    /// Once any header is allowed, only allowed headers are stored.
+    pub fn allow(mut self, name: HeaderName) -> Self {
+        self.deny.remove(&name);
+        self.allow.get_or_insert_with(HashSet::new).insert(name);
+        self
+    }
+
+    /// Never stores `name`.
+    pub fn deny(mut self, name: HeaderName) -> Self {
+        if let Some(allow) = &mut self.allow {
+            allow.remove(&name);
+        }
+        self.deny.insert(name);
+        self
+    }
+
+    /// How the headers kept are stored. Defaults to [`HeaderCanonicalization::Exact`].
+    pub fn canonicalization(mut self, canonicalization: HeaderCanonicalization) -> Self {
+        self.canonicalization = canonicalization;
+        self
+    }
+
+    /// The headers of `headers` that should be stored, as they are stored.
+    pub(crate) fn capture(&self, headers: &HeaderMap) -> Vec<(String, Vec<u8>)> {
+        let kept = self
+            .filter(headers)
+            .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()));
+
+        match self.canonicalization {
+            HeaderCanonicalization::Exact => kept.collect(),
+            HeaderCanonicalization::Combined => {
+                let mut kept: Vec<_> = kept.collect();
+                // stable, so the values of each header keep their order
+                kept.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+                let mut combined: Vec<(String, Vec<u8>)> = Vec::with_capacity(kept.len());
+                for (name, value) in kept {
+                    match combined.last_mut() {
+                        Some((last, values))
+                            if *last == name && name != header::SET_COOKIE.as_str() =>
+                        {
+                            values.extend_from_slice(b", ");
+                            values.extend(value);
+                        }
+                        _ => combined.push((name, value)),
+                    }
+                }
+                combined
+            }
+        }
+    }
+
+    /// The headers of `headers` that should be stored.
+    fn filter<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> impl Iterator<Item = (&'a HeaderName, &'a header::HeaderValue)> {
+        let connection: Vec<HeaderName> = headers
+            .get_all(header::CONNECTION)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+            .collect();
+
+        headers.iter().filter(move |(name, _)| {
+            !is_hop_by_hop(name)
+                && !connection.contains(name)
+                && !self.deny.contains(*name)
+                && self
+                    .allow
+                    .as_ref()
+                    .is_none_or(|allow| allow.contains(*name))
+        })
+    }
+}
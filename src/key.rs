@@ -0,0 +1,154 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use uuid::Uuid;
+
+use crate::ConfigError;
+
+// Generous enough for any UUID, ULID or hash clients are likely to send, while keeping a bound on
+// what ends up in the store.
+const DEFAULT_MAX_LENGTH: usize = 255;
+
+/// Decides which idempotency keys are accepted.
+///
+/// By default keys must be between 1 and 255 characters long and consist of visible ASCII
+/// characters only. Keys failing validation are rejected with [`IdempotencyError::Malformed`],
+/// whose message tells the client what was wrong.
+///
+/// [`IdempotencyError::Malformed`]: crate::IdempotencyError::Malformed
+#[derive(Clone)]
+pub struct KeyValidator {
+    min_length: usize,
+    max_length: usize,
+    allowed: Arc<dyn Fn(char) -> bool + Send + Sync>,
+    min_entropy: Option<f64>,
+    uuid: bool,
+}
+
+impl Default for KeyValidator {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            max_length: DEFAULT_MAX_LENGTH,
+            allowed: Arc::new(|c| c.is_ascii_graphic()),
+            min_entropy: None,
+            uuid: false,
+        }
+    }
+}
+
+impl KeyValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accepts keys that parse as a UUID, in any of its textual forms.
+    pub fn uuid() -> Self {
+        Self {
+            uuid: true,
+            ..Self::default()
+        }
+    }
+
+    /// Shortest key accepted, in characters. Defaults to 1.
+    pub fn min_length(mut self, length: usize) -> Self {
+        self.min_length = length;
+        self
+    }
+
+    /// Longest key accepted, in characters. Defaults to 255.
+    pub fn max_length(mut self, length: usize) -> Self {
+        self.max_length = length;
+        self
+    }
+
+    /// Characters keys may consist of. Defaults to visible ASCII, i.e. no spaces or control
+    /// characters.
+    pub fn allowed_chars(mut self, allowed: impl Fn(char) -> bool + Send + Sync + 'static) -> Self {
+        self.allowed = Arc::new(allowed);
+        self
+    }
+
+    /// Rejects keys carrying fewer than `bits` of entropy, e.g. `aaaaaaaa` or `12341234`, which
+    /// are likely to be reused by accident.
+    ///
+    /// The entropy is estimated from how often each character occurs in the key, so a random
+    /// UUID comes out at roughly 130 bits.
+    pub fn min_entropy(mut self, bits: f64) -> Self {
+        self.min_entropy = Some(bits);
+        self
+    }
+
+    // fails if no key could ever pass
+    pub(crate) fn check(&self) -> Result<(), ConfigError> {
+        if self.min_length > self.max_length {
+            return Err(ConfigError::KeyLength {
+                min: self.min_length,
+                max: self.max_length,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn validate(&self, key: &str) -> Result<(), String> {
+        let length = key.chars().count();
+
+        if length < self.min_length {
+            return Err(format!(
+                "idempotency key must be at least {} characters long",
+                self.min_length
+            ));
+        }
+
+        if length > self.max_length {
+            return Err(format!(
+                "idempotency key must be at most {} characters long",
+                self.max_length
+            ));
+        }
+
+        if let Some(c) = key.chars().find(|&c| !(self.allowed)(c)) {
+            return Err(format!("idempotency key must not contain {c:?}"));
+        }
+
+        if self.uuid && Uuid::try_parse(key).is_err() {
+            return Err("idempotency key must be a UUID".to_owned());
+        }
+
+        if self.min_entropy.is_some_and(|bits| entropy(key) < bits) {
+            return Err("idempotency key is too predictable".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for KeyValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyValidator")
+            .field("min_length", &self.min_length)
+            .field("max_length", &self.max_length)
+            .field("min_entropy", &self.min_entropy)
+            .field("uuid", &self.uuid)
+            .finish_non_exhaustive()
+    }
+}
+
+// Shannon entropy of the key's character distribution, times its length.
+fn entropy(key: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in key.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+
+    let length = key.chars().count() as f64;
+    let per_char: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / length;
+            -p * p.log2()
+        })
+        .sum();
+
+    per_char * length
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Hashes idempotency keys with HMAC-SHA256 before they are used as store keys, so that keys
+/// embedding e.g. order ids never reach the store.
+///
+/// Keys are hashed the same way on every request, so lookups keep working. Without the secret,
+/// the hashes cannot be traced back to keys, nor guessed from keys that are easy to predict.
+/// Clients still see their own keys, in rejections as well as echoed back.
+///
+/// Entries stored before hashing was enabled, or with another secret, are no longer found.
+///
+/// Hashes share no prefixes, so [`Invalidation::Scope`](crate::Invalidation::Scope) would
+/// match no entry at all. Keys carrying their scope in front, such as the tenant in
+/// `tenant-7:order-42`, can keep it readable with [`scope_separator`](Self::scope_separator):
+///
+/// ```
+/// use actix_web_idempotency::{Idempotency, KeyHashing};
+///
+/// // stored as `tenant-7:` followed by the hash, so `invalidate_scope("tenant-7:")` still works
+/// let idempotency = Idempotency::builder()
+///     .key_hashing(KeyHashing::new(b"server secret").scope_separator(':'))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Requires the `key-hashing` feature.
+#[derive(Clone)]
+pub struct KeyHashing {
+    mac: Hmac<Sha256>,
+    separator: Option<char>,
+}
+
+impl KeyHashing {
+    /// Hashes with `secret`, which has to be the same on every instance sharing a store.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(secret).expect("HMAC accepts secrets of any length"),
+            separator: None,
+        }
+    }
+
+    /// Keeps everything up to and including the first `separator` of a key in front of its hash,
+    /// so that invalidating that scope still finds the key. The scope then reaches the store as
+    /// it is, so it must not be anything the hashing is meant to hide.
+    pub fn scope_separator(mut self, separator: char) -> Self {
+        self.separator = Some(separator);
+        self
+    }
+
+    pub(crate) fn hash(&self, key: &str) -> String {
+        let scope = self
+            .separator
+            .and_then(|separator| key.find(separator).map(|at| at + separator.len_utf8()))
+            .map_or("", |end| &key[..end]);
+
+        // the whole key is hashed, so keys only differing in their scope still differ
+        let mut mac = self.mac.clone();
+        mac.update(key.as_bytes());
+        format!("{scope}{:x}", mac.finalize().into_bytes())
+    }
+}
+
+impl fmt::Debug for KeyHashing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyHashing")
+            .field("separator", &self.separator)
+            .finish_non_exhaustive()
+    }
+}
@@ -1,36 +1,2025 @@
 use std::{
-    collections::{HashMap, LinkedList},
-    future::{ready, Ready},
-    pin::Pin,
+    borrow::Cow,
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    future::{ready, Future, Ready},
+    io,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use actix_web::{
-    body::EitherBody,
+    body::{to_bytes, BodySize, BoxBody, MessageBody, SizedStream},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::StatusCode,
-    Error, HttpRequest, HttpResponse,
+    error::ErrorInternalServerError,
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    rt,
+    web::{self, Bytes},
+    Error, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
 
-use futures_util::future::LocalBoxFuture;
+use fingerprint::{fingerprint, Fingerprint};
+use futures_util::{future::LocalBoxFuture, stream};
+use pending::{Pending, PendingGuard};
+use replays::ReplayCounter;
+use waiters::Waiters;
 
-use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 use uuid::Uuid;
 
+#[cfg(feature = "admin")]
+pub mod admin;
+mod clock;
+mod compression;
+mod config;
+mod content_type;
+mod context;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error_format;
+mod events;
+mod extractor;
+mod fingerprint;
+mod first_execution;
+mod handle;
+mod headers;
+mod key;
+#[cfg(feature = "key-hashing")]
+mod key_hashing;
+mod metadata;
+mod metrics;
+#[cfg(feature = "otel")]
+mod otel;
+mod paths;
+mod pending;
+mod policy;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod replays;
+mod store;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test;
+mod transform;
+mod waiters;
+
+pub use clock::{Clock, SystemClock};
+pub use compression::{Codec, Compression};
+pub use config::ConfigError;
+pub use content_type::ContentTypeFilter;
+pub use context::{IdempotencyContext, IdempotencyOutcome};
+#[cfg(feature = "encryption")]
+pub use encryption::Encryption;
+pub use error_format::ErrorFormat;
+pub use events::{IdempotencyEvent, IdempotencyEvents};
+pub use extractor::{HeaderKey, JsonBodyKey, KeyExtractor, KeyHeader, QueryKey};
+#[cfg(feature = "xxhash")]
+pub use fingerprint::Xxh3Hasher;
+pub use fingerprint::{
+    FingerprintDigest, FingerprintHasher, OversizedBody, Sha256Hasher, Sha384Hasher, Sha512Hasher,
+};
+pub use first_execution::FirstExecution;
+pub use handle::IdempotencyHandle;
+pub use headers::{HeaderCanonicalization, HeaderFilter};
+pub use key::KeyValidator;
+#[cfg(feature = "key-hashing")]
+pub use key_hashing::KeyHashing;
+pub use metadata::IdempotencyMetadata;
+pub use metrics::{IdempotencyMetrics, NoopMetrics};
+pub use paths::PathPattern;
+pub use policy::IdempotencyPolicy;
+#[cfg(feature = "prometheus")]
+pub use prometheus::{idempotency_metrics_handler, PrometheusMetrics};
+#[cfg(feature = "dynamodb")]
+pub use store::DynamoDbStore;
+#[cfg(feature = "memcached")]
+pub use store::MemcachedStore;
+#[cfg(feature = "moka")]
+pub use store::MokaStore;
+#[cfg(feature = "mysql")]
+pub use store::MySqlStore;
+#[cfg(feature = "s3")]
+pub use store::S3BlobStore;
+#[cfg(feature = "scylla")]
+pub use store::ScyllaStore;
+#[cfg(feature = "sled")]
+pub use store::SledStore;
+pub use store::{
+    BlobStore, BodyStream, BusHandler, BusMessage, CacheElement, CircuitBreakerStore, CircuitState,
+    EvictionPolicy, IdempotencyStore, Invalidation, InvalidationBus, MemoryStore, MigratingStore,
+    OffloadStore, Reservation, RetryStore, StoreError, StoreFuture, StoreStats, TieredStore,
+    WireFormat,
+};
+#[cfg(feature = "redis")]
+pub use store::{RedisInvalidationBus, RedisStore};
+pub use transform::{Replay, ReplayTransformer};
+
 // The header to use. Defaults to 'Idempotency-Key' as defined in this IETF memo:
 //
 // https://www.ietf.org/archive/id/draft-ietf-httpapi-idempotency-key-header-01.html
-const HEADER_KEY: &str = "Idempotency-Key";
+//
+// Spelled in lowercase, as `HeaderName::from_static` only accepts lowercase names.
+const HEADER_KEY: &str = "idempotency-key";
+
+// How long responses are kept around for replays unless configured otherwise.
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How often requests waiting for completion look for a response stored by another instance.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// When clients turned away for too many pending requests are asked to try again.
+const PENDING_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// The draft `SpecCompliance::DraftV6` follows, which problem details of its errors point to.
+const DRAFT_V6: &str =
+    "https://www.ietf.org/archive/id/draft-ietf-httpapi-idempotency-key-header-06.html";
+
+// Largest body `SpecCompliance::DraftV6` fingerprints unless told otherwise.
+const DRAFT_FINGERPRINT_LIMIT: usize = 1024 * 1024;
+
+// When `SpecCompliance::DraftV6` asks clients to retry a request that is still in progress.
+const DRAFT_CONFLICT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// The idempotency middleware.
+///
+/// Build it once and clone it into the `HttpServer` factory so that all workers share the same
+/// store:
+///
+/// ```no_run
+/// use actix_web::{App, HttpServer};
+/// use actix_web_idempotency::Idempotency;
+///
+/// # async fn run() -> std::io::Result<()> {
+/// let idempotency = Idempotency::new();
+///
+/// HttpServer::new(move || App::new().wrap(idempotency.clone()))
+///     .bind(("127.0.0.1", 8080))?
+///     .run()
+///     .await
+/// # }
+/// ```
+///
+/// # `HEAD` requests
+///
+/// A `HEAD` request carrying a key that was used before, on the same route, is answered with the
+/// stored status and headers, and a `Content-Length` matching the stored body it leaves out.
+/// `HEAD` requests never reserve a key nor store a response, so one with an unused key simply
+/// goes through to the handler.
+///
+/// ```
+/// use actix_web::{
+///     body::{BodySize, MessageBody},
+///     http::Method,
+///     test, web, App, HttpResponse,
+/// };
+/// use actix_web_idempotency::Idempotency;
+///
+/// # #[actix_web::main]
+/// # async fn main() {
+/// let app = test::init_service(
+///     App::new().wrap(Idempotency::new()).route(
+///         "/orders",
+///         web::post().to(|| async { HttpResponse::Created().body("order placed") }),
+///     ),
+/// )
+/// .await;
+///
+/// let order = test::TestRequest::post()
+///     .uri("/orders")
+///     .insert_header(("Idempotency-Key", "order-1"))
+///     .to_request();
+/// test::call_service(&app, order).await;
+///
+/// let head = test::TestRequest::default()
+///     .method(Method::HEAD)
+///     .uri("/orders")
+///     .insert_header(("Idempotency-Key", "order-1"))
+///     .to_request();
+/// let res = test::call_service(&app, head).await;
+/// assert_eq!(res.status(), 201);
+/// assert_eq!(res.response().body().size(), BodySize::Sized(12));
+/// assert!(test::read_body(res).await.is_empty());
+/// # }
+/// ```
+///
+/// # Conditional replays
+///
+/// A replay of a successful response carrying an `ETag` honors `If-None-Match`: if one of the
+/// tags sent matches the stored one, weakly as for `GET` requests, or `*` was sent, the replay is
+/// answered with `304 Not Modified` and the stored headers, but no body. This lets clients that
+/// kept the response from an earlier attempt retry without downloading it again.
+///
+/// ```
+/// use actix_web::{test, web, App, HttpResponse};
+/// use actix_web_idempotency::Idempotency;
+///
+/// # #[actix_web::main]
+/// # async fn main() {
+/// let app = test::init_service(
+///     App::new().wrap(Idempotency::new()).route(
+///         "/orders",
+///         web::post().to(|| async {
+///             HttpResponse::Created()
+///                 .insert_header(("ETag", "\"order-1-v1\""))
+///                 .body("order placed")
+///         }),
+///     ),
+/// )
+/// .await;
+///
+/// let order = test::TestRequest::post()
+///     .uri("/orders")
+///     .insert_header(("Idempotency-Key", "order-1"))
+///     .to_request();
+/// test::call_service(&app, order).await;
+///
+/// let retry = test::TestRequest::post()
+///     .uri("/orders")
+///     .insert_header(("Idempotency-Key", "order-1"))
+///     .insert_header(("If-None-Match", "\"order-1-v1\""))
+///     .to_request();
+/// let res = test::call_service(&app, retry).await;
+/// assert_eq!(res.status(), 304);
+/// assert_eq!(res.headers().get("ETag").unwrap(), "\"order-1-v1\"");
+/// assert!(test::read_body(res).await.is_empty());
+/// # }
+/// ```
+///
+/// # What is stored
+///
+/// Only responses the handler produced are stored, once it is done with them:
+///
+/// - Rejections by the middleware itself, e.g. of a missing or malformed key or of a request
+///   whose key is still in progress, are never stored, and leave the key as it was.
+/// - A handler returning `Err(_)`, or any response carrying an error, such as one an extractor
+///   or a middleware inside produced, releases the key without storing anything, so a retry
+///   executes again.
+/// - So does a response that [opts out](NoStore), is not of a
+///   [stored content type](IdempotencyBuilder::content_types), or fails to be read.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// use actix_web::{error::ErrorServiceUnavailable, test, web, App, Error, HttpResponse};
+/// use actix_web_idempotency::Idempotency;
+///
+/// static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+///
+/// async fn create_order() -> Result<HttpResponse, Error> {
+///     match ATTEMPTS.fetch_add(1, Ordering::SeqCst) {
+///         0 => Err(ErrorServiceUnavailable("payment provider is down")),
+///         _ => Ok(HttpResponse::Created().body("order placed")),
+///     }
+/// }
+///
+/// # #[actix_web::main]
+/// # async fn main() {
+/// let app = test::init_service(
+///     App::new()
+///         .wrap(Idempotency::new())
+///         .route("/orders", web::post().to(create_order)),
+/// )
+/// .await;
+///
+/// let order = || {
+///     test::TestRequest::post()
+///         .uri("/orders")
+///         .insert_header(("Idempotency-Key", "order-1"))
+///         .to_request()
+/// };
+///
+/// // the failure is not stored, so the retry executes
+/// assert_eq!(test::call_service(&app, order()).await.status(), 503);
+/// assert_eq!(test::call_service(&app, order()).await.status(), 201);
+///
+/// // from then on the stored response is replayed
+/// assert_eq!(test::call_service(&app, order()).await.status(), 201);
+/// assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+/// # }
+/// ```
+///
+/// # Combining with other middleware
+///
+/// Responses come out as a plain [`BoxBody`], so the middleware nests with any other, on an
+/// `App` as well as on a `Scope` with its own data. The last `wrap` ends up outermost. Where
+/// the neighbours go matters:
+///
+/// - `NormalizePath` goes outside, so that [`include`](IdempotencyBuilder::include) and
+///   [`exclude`](IdempotencyBuilder::exclude) see the normalized path.
+/// - `Compress` goes outside too, so that responses are stored uncompressed and every replay is
+///   encoded for the client asking, rather than for the one that asked first.
+/// - `from_fn` middleware outside sees replays and rejections as well, while middleware
+///   inside only runs when the handler does, and whatever it adds to the response is stored.
+///
+/// ```
+/// use actix_web::{
+///     body::MessageBody,
+///     dev::{ServiceRequest, ServiceResponse},
+///     http::header::{self, HeaderValue},
+///     middleware::{from_fn, Compress, Next, NormalizePath},
+///     test, web, App, Error, HttpResponse,
+/// };
+/// use actix_web_idempotency::Idempotency;
+///
+/// async fn stamp(
+///     req: ServiceRequest,
+///     next: Next<impl MessageBody>,
+/// ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+///     let mut res = next.call(req).await?;
+///     res.headers_mut()
+///         .insert(header::SERVER, HeaderValue::from_static("orders"));
+///     Ok(res)
+/// }
+///
+/// # #[actix_web::main]
+/// # async fn main() {
+/// let app = test::init_service(
+///     App::new()
+///         .service(
+///             web::scope("/orders")
+///                 .app_data(web::Data::new(String::from("eu-west")))
+///                 .wrap(Idempotency::new())
+///                 .wrap(from_fn(stamp))
+///                 .route(
+///                     "",
+///                     web::post().to(|region: web::Data<String>| async move {
+///                         HttpResponse::Created().body(format!("order placed in {}", *region))
+///                     }),
+///                 ),
+///         )
+///         .wrap(Compress::default())
+///         .wrap(NormalizePath::trim()),
+/// )
+/// .await;
+///
+/// let order = |uri: &str| {
+///     test::TestRequest::post()
+///         .uri(uri)
+///         .insert_header(("Idempotency-Key", "order-1"))
+///         .insert_header((header::ACCEPT_ENCODING, "gzip"))
+///         .to_request()
+/// };
+///
+/// let res = test::call_service(&app, order("/orders")).await;
+/// assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+///
+/// // the trailing slash is trimmed before the key is looked up, and the replay is compressed
+/// // and stamped all over again
+/// let res = test::call_service(&app, order("/orders/")).await;
+/// assert!(res.headers().contains_key(header::AGE));
+/// assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+/// assert_eq!(res.headers().get(header::SERVER).unwrap(), "orders");
+/// # }
+/// ```
+///
+/// # Threading
+///
+/// The middleware is `Send` and `Sync`, so one instance can be shared by every worker, and the
+/// futures of [`IdempotencyStore`]s are `Send`, so stores may hand their work to other threads.
+/// The futures of the service it wraps around are not `Send`, and cannot be: like those of every
+/// actix-web middleware, they hold on to the `ServiceRequest`, which actix-web builds on `Rc`s.
+/// Layers that require `Send` futures, such as tower's, have to go around the server as a whole
+/// rather than in between actix-web services.
+#[derive(Clone)]
+pub struct Idempotency {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: Arc<dyn IdempotencyStore>,
+    store_from_app_data: bool,
+    metrics: Arc<dyn IdempotencyMetrics>,
+    events: Option<Arc<dyn IdempotencyEvents>>,
+    clock: Arc<dyn Clock>,
+    ttl: chrono::Duration,
+    ttl_header: Option<HeaderName>,
+    ttl_jitter: u8,
+    no_store_header: Option<HeaderName>,
+    header_filter: HeaderFilter,
+    content_types: ContentTypeFilter,
+    request_id_header: Option<HeaderName>,
+    key_extractor: Arc<dyn KeyExtractor>,
+    key_validator: KeyValidator,
+    key_reuse: KeyReuse,
+    conflict_location: Option<LocationResolver>,
+    failure_mode: FailureMode,
+    fingerprint_limit: Option<usize>,
+    oversized_body: OversizedBody,
+    fingerprint_hasher: Arc<dyn FingerprintHasher>,
+    compression: Option<Compression>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption>,
+    #[cfg(feature = "key-hashing")]
+    key_hashing: Option<KeyHashing>,
+    include: Vec<PathPattern>,
+    exclude: Vec<PathPattern>,
+    skip_if: Vec<SkipPredicate>,
+    route_policies: HashMap<String, IdempotencyPolicy>,
+    replay_limit: Option<u64>,
+    replays: ReplayCounter,
+    replay_transformer: Option<Arc<dyn ReplayTransformer>>,
+    max_pending: Option<usize>,
+    pending: Pending,
+    pending_timeout: Option<Duration>,
+    wait_timeout: Option<Duration>,
+    waiters: Waiters,
+    conflict_status: ConflictStatus,
+    conflict_body: Option<String>,
+    conflict_retry_after: Option<Duration>,
+    error_format: ErrorFormat,
+    safe_methods_optional: bool,
+    missing_key: MissingKey,
+    echo_key: bool,
+    issue_keys: bool,
+    gc_interval: Option<Duration>,
+    gc_started: AtomicBool,
+}
+
+// Finds the resource a stored response created, see `IdempotencyBuilder::conflict_location`.
+type LocationResolver = Arc<dyn Fn(&CacheElement) -> Option<String> + Send + Sync>;
+
+// Picks requests to leave alone, see `IdempotencyBuilder::skip_if`.
+type SkipPredicate = Arc<dyn Fn(&ServiceRequest) -> bool + Send + Sync>;
+
+/// Marks a response that must not be stored, so that a retry executes the request again, e.g.
+/// because it failed validation and the client is expected to correct the request.
+///
+/// Handlers insert it into the extensions of the request or of the response:
+///
+/// ```
+/// use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+/// use actix_web_idempotency::NoStore;
+///
+/// async fn create_order(req: HttpRequest) -> HttpResponse {
+///     req.extensions_mut().insert(NoStore);
+///     HttpResponse::UnprocessableEntity().finish()
+/// }
+/// ```
+///
+/// Handlers that cannot reach either can send a header instead, see
+/// [`no_store_header`](IdempotencyBuilder::no_store_header).
+#[derive(Clone, Copy, Debug)]
+pub struct NoStore;
+
+/// What to do with a request whose key already has a completed response.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyReuse {
+    /// Replay the stored response without running the handler.
+    #[default]
+    Replay,
+    /// Turn the request away with `409 Conflict` and [`IdempotencyError::AlreadyExists`].
+    ///
+    /// If the original response carried a `Location` header, e.g. because it created a resource,
+    /// the rejection points there as well.
+    Reject,
+}
+
+/// What to do with a request when the store fails before its key could be claimed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Reject the request with `500 Internal Server Error` and [`IdempotencyError::Store`], so
+    /// that nothing is executed without the guarantee of being executed once.
+    #[default]
+    Closed,
+    /// Pass the request on to the handler as if it carried no key, and do not store its
+    /// response. Keeps the API available at the risk of executing retries twice while the store
+    /// is down. Reported through [`IdempotencyMetrics::record_fail_open`].
+    Open,
+}
+
+/// Status of the response to a request whose key is still held by another request in progress.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictStatus {
+    /// `409 Conflict`, as the IETF draft on idempotency keys specifies.
+    #[default]
+    Conflict,
+    /// `425 Too Early`, telling clients that the same request may well succeed if retried later.
+    TooEarly,
+}
+
+impl From<ConflictStatus> for StatusCode {
+    fn from(status: ConflictStatus) -> Self {
+        match status {
+            ConflictStatus::Conflict => StatusCode::CONFLICT,
+            ConflictStatus::TooEarly => StatusCode::from_u16(425).unwrap(),
+        }
+    }
+}
+
+/// What happens to requests arriving without an idempotency key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingKey {
+    /// Reject the request with [`IdempotencyError::Missing`] and the given status, e.g.
+    /// `428 Precondition Required` as the IETF draft suggests for endpoints mandating keys.
+    Reject(StatusCode),
+    /// Pass the request on to the handler without idempotency, e.g. while clients are still
+    /// being updated to send keys.
+    PassThrough,
+}
+
+impl Default for MissingKey {
+    /// Rejects the request with `400 Bad Request`.
+    fn default() -> Self {
+        Self::Reject(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Presets bundling everything a version of the IETF draft on the `Idempotency-Key` header
+/// recommends, see [`spec_compliance`](IdempotencyBuilder::spec_compliance).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SpecCompliance {
+    /// [draft-ietf-httpapi-idempotency-key-header-06](https://www.ietf.org/archive/id/draft-ietf-httpapi-idempotency-key-header-06.html).
+    DraftV6,
+}
+
+// Outcome of looking up a request's key.
+enum Claim<'a> {
+    // the body is left in the store if it can be streamed from there
+    Cached(Box<CacheElement>, Option<BodyStream>),
+    Reserved(Reservation, PendingGuard<'a>),
+    InProgress,
+    TooManyPending,
+}
+
+impl Idempotency {
+    /// Middleware with the default configuration, backed by a fresh [`MemoryStore`].
+    pub fn new() -> Self {
+        Self::builder()
+            .build()
+            .expect("the default configuration is valid")
+    }
+
+    pub fn builder() -> IdempotencyBuilder {
+        IdempotencyBuilder::default()
+    }
+
+    /// The store this middleware reads and writes, e.g. to hand to administrative endpoints.
+    pub fn store(&self) -> Arc<dyn IdempotencyStore> {
+        Arc::clone(&self.inner.store)
+    }
+
+    /// A handle for inspecting and purging the entries of this middleware's store.
+    pub fn handle(&self) -> IdempotencyHandle {
+        let handle = IdempotencyHandle::from(self.store())
+            .with_ttl(self.inner.ttl, Arc::clone(&self.inner.clock));
+
+        #[cfg(feature = "key-hashing")]
+        let handle = handle.with_key_hashing(self.inner.key_hashing.clone());
+
+        handle
+    }
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures an [`Idempotency`] middleware.
+pub struct IdempotencyBuilder {
+    store: Option<Arc<dyn IdempotencyStore>>,
+    store_from_app_data: bool,
+    metrics: Option<Arc<dyn IdempotencyMetrics>>,
+    events: Option<Arc<dyn IdempotencyEvents>>,
+    clock: Option<Arc<dyn Clock>>,
+    ttl: Duration,
+    ttl_header: Option<HeaderName>,
+    ttl_jitter: u8,
+    no_store_header: Option<HeaderName>,
+    header_filter: HeaderFilter,
+    content_types: ContentTypeFilter,
+    request_id_header: Option<HeaderName>,
+    key_extractor: Option<Arc<dyn KeyExtractor>>,
+    key_validator: KeyValidator,
+    key_reuse: KeyReuse,
+    conflict_location: Option<LocationResolver>,
+    failure_mode: FailureMode,
+    fingerprint_limit: Option<usize>,
+    oversized_body: OversizedBody,
+    fingerprint_hasher: Option<Arc<dyn FingerprintHasher>>,
+    compression: Option<Compression>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption>,
+    #[cfg(feature = "key-hashing")]
+    key_hashing: Option<KeyHashing>,
+    include: Vec<PathPattern>,
+    exclude: Vec<PathPattern>,
+    skip_if: Vec<SkipPredicate>,
+    route_policies: HashMap<String, IdempotencyPolicy>,
+    replay_limit: Option<u64>,
+    replay_transformer: Option<Arc<dyn ReplayTransformer>>,
+    max_pending: Option<usize>,
+    pending_timeout: Option<Duration>,
+    wait_timeout: Option<Duration>,
+    conflict_status: ConflictStatus,
+    conflict_body: Option<String>,
+    conflict_retry_after: Option<Duration>,
+    error_format: ErrorFormat,
+    safe_methods_optional: bool,
+    missing_key: MissingKey,
+    echo_key: bool,
+    issue_keys: bool,
+    gc_interval: Option<Duration>,
+}
+
+impl Default for IdempotencyBuilder {
+    fn default() -> Self {
+        Self {
+            store: None,
+            store_from_app_data: false,
+            metrics: None,
+            events: None,
+            clock: None,
+            ttl: DEFAULT_TTL,
+            ttl_header: None,
+            ttl_jitter: 0,
+            no_store_header: None,
+            header_filter: HeaderFilter::default(),
+            content_types: ContentTypeFilter::default(),
+            request_id_header: None,
+            key_extractor: None,
+            key_validator: KeyValidator::default(),
+            key_reuse: KeyReuse::default(),
+            conflict_location: None,
+            failure_mode: FailureMode::default(),
+            fingerprint_limit: None,
+            oversized_body: OversizedBody::default(),
+            fingerprint_hasher: None,
+            compression: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "key-hashing")]
+            key_hashing: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            skip_if: Vec::new(),
+            route_policies: HashMap::new(),
+            replay_limit: None,
+            replay_transformer: None,
+            max_pending: None,
+            pending_timeout: None,
+            wait_timeout: None,
+            conflict_status: ConflictStatus::default(),
+            conflict_body: None,
+            conflict_retry_after: None,
+            error_format: ErrorFormat::default(),
+            safe_methods_optional: false,
+            echo_key: false,
+            missing_key: MissingKey::default(),
+            issue_keys: false,
+            gc_interval: None,
+        }
+    }
+}
+
+impl IdempotencyBuilder {
+    /// Store used to keep responses. Defaults to a [`MemoryStore`].
+    pub fn store(mut self, store: impl IdempotencyStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Looks the store up in the app data of every request, where it is registered as
+    /// `web::Data<Arc<dyn IdempotencyStore>>`, instead of using the one given to
+    /// [`store`](Self::store). Handlers and administrative endpoints then share one instance with
+    /// the middleware without it having to be passed around. Off by default.
+    ///
+    /// Requests reaching the middleware without such app data fail with `500 Internal Server
+    /// Error`. [`Idempotency::store`], [`Idempotency::handle`] and
+    /// [`gc_interval`](Self::gc_interval) keep using the store given to [`store`](Self::store).
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use actix_web::{test, web, App, HttpResponse};
+    /// use actix_web_idempotency::{Idempotency, IdempotencyStore, MemoryStore};
+    ///
+    /// async fn stored(store: web::Data<Arc<dyn IdempotencyStore>>) -> HttpResponse {
+    ///     match store.get("order-1").await {
+    ///         Ok(Some(_)) => HttpResponse::Ok().finish(),
+    ///         _ => HttpResponse::NotFound().finish(),
+    ///     }
+    /// }
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() {
+    /// let store: Arc<dyn IdempotencyStore> = Arc::new(MemoryStore::new());
+    ///
+    /// let app = test::init_service(
+    ///     App::new()
+    ///         .app_data(web::Data::new(store))
+    ///         .route("/stored", web::get().to(stored))
+    ///         .service(
+    ///             web::scope("/orders")
+    ///                 .wrap(
+    ///                     Idempotency::builder()
+    ///                         .store_from_app_data(true)
+    ///                         .build()
+    ///                         .unwrap(),
+    ///                 )
+    ///                 .route("", web::post().to(HttpResponse::Created)),
+    ///         ),
+    /// )
+    /// .await;
+    ///
+    /// let order = test::TestRequest::post()
+    ///     .uri("/orders")
+    ///     .insert_header(("Idempotency-Key", "order-1"))
+    ///     .to_request();
+    /// test::call_service(&app, order).await;
+    ///
+    /// let res = test::call_service(&app, test::TestRequest::get().uri("/stored").to_request()).await;
+    /// assert_eq!(res.status(), 200);
+    /// # }
+    /// ```
+    pub fn store_from_app_data(mut self, from_app_data: bool) -> Self {
+        self.store_from_app_data = from_app_data;
+        self
+    }
+
+    /// Hook receiving counters about replays, misses and garbage collection.
+    pub fn metrics(mut self, metrics: impl IdempotencyMetrics + 'static) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Hook notified whenever a response is stored or replayed and whenever a request is turned
+    /// away.
+    pub fn events(mut self, events: impl IdempotencyEvents + 'static) -> Self {
+        self.events = Some(Arc::new(events));
+        self
+    }
+
+    /// Clock that stamps stored responses and computes the `Age` of replays. Defaults to
+    /// [`SystemClock`].
+    ///
+    /// Entries expire according to the store's notion of time, so a store supporting clocks
+    /// should be given the same one.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// How long a response stays replayable. Defaults to 24 hours.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Spreads the expiry of stored responses by up to `percent` of their TTL either way, so that
+    /// responses stored in a burst do not all expire at once. Off by default.
+    ///
+    /// With a [`ttl`](Self::ttl) of 24 hours and a jitter of 10%, responses expire anywhere
+    /// between 21.6 and 26.4 hours after they were stored. The jitter applies to TTLs requested
+    /// through the [`ttl_header`](Self::ttl_header) as well, and must be below 100%.
+    pub fn ttl_jitter(mut self, percent: u8) -> Self {
+        self.ttl_jitter = percent;
+        self
+    }
+
+    /// Response header through which handlers override the [`ttl`](Self::ttl) of their
+    /// response, in seconds, e.g. `X-Idempotency-TTL: 3600`.
+    ///
+    /// The header is removed before the response is stored or sent. Values that are not a whole
+    /// number of seconds are logged and ignored.
+    pub fn ttl_header(mut self, name: HeaderName) -> Self {
+        self.ttl_header = Some(name);
+        self
+    }
+
+    /// Response header through which handlers keep their response from being stored, like
+    /// [`NoStore`] does, e.g. `X-Idempotency-No-Store: 1`. Any value counts.
+    ///
+    /// The header is removed before the response is sent.
+    pub fn no_store_header(mut self, name: HeaderName) -> Self {
+        self.no_store_header = Some(name);
+        self
+    }
+
+    /// Which response headers are stored and replayed. Defaults to [`HeaderFilter::default`].
+    pub fn header_filter(mut self, filter: HeaderFilter) -> Self {
+        self.header_filter = filter;
+        self
+    }
+
+    /// Which responses are stored, by their `Content-Type`. Defaults to
+    /// [`ContentTypeFilter::default`], which leaves out streaming and gRPC responses.
+    pub fn content_types(mut self, filter: ContentTypeFilter) -> Self {
+        self.content_types = filter;
+        self
+    }
+
+    /// Request header carrying a request id, e.g. `X-Request-Id`.
+    ///
+    /// The id of the request that first produced a response is stored with it and sent back
+    /// as `Idempotency-Original-Request-Id` whenever that response is replayed.
+    pub fn original_request_id_header(mut self, name: HeaderName) -> Self {
+        self.request_id_header = Some(name);
+        self
+    }
+
+    /// Where the idempotency key of a request is read from. Defaults to the `Idempotency-Key`
+    /// header, see [`HeaderKey`].
+    pub fn key_extractor(mut self, extractor: impl KeyExtractor + 'static) -> Self {
+        self.key_extractor = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Which idempotency keys are accepted. Defaults to [`KeyValidator::default`].
+    pub fn key_validator(mut self, validator: KeyValidator) -> Self {
+        self.key_validator = validator;
+        self
+    }
+
+    /// How requests reusing the key of a completed one are answered. Defaults to
+    /// [`KeyReuse::Replay`].
+    pub fn key_reuse(mut self, key_reuse: KeyReuse) -> Self {
+        self.key_reuse = key_reuse;
+        self
+    }
+
+    /// Maps the stored response of a key to the resource it created, sent as `Location` when a
+    /// later request with that key is rejected, i.e. with [`IdempotencyError::AlreadyExists`]
+    /// under [`KeyReuse::Reject`] or with [`IdempotencyError::Mismatch`].
+    ///
+    /// By default the `Location` header of the stored response is used, if it kept one. Return
+    /// `None` to send no `Location`.
+    ///
+    /// ```
+    /// use actix_web_idempotency::{CacheElement, Idempotency};
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .conflict_location(|original: &CacheElement| {
+    ///         let order: serde_json::Value = serde_json::from_slice(original.body()).ok()?;
+    ///         Some(format!("/orders/{}", order["id"].as_str()?))
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn conflict_location(
+        mut self,
+        resolve: impl Fn(&CacheElement) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.conflict_location = Some(Arc::new(resolve));
+        self
+    }
+
+    /// How requests are answered when the store fails, e.g. because it is unreachable or did not
+    /// answer before the deadline of a [`RetryStore`]. Defaults to [`FailureMode::Closed`].
+    pub fn failure_mode(mut self, mode: FailureMode) -> Self {
+        self.failure_mode = mode;
+        self
+    }
+
+    /// Fingerprints requests by hashing their method, path with query string and body, with
+    /// SHA-256 unless another [`fingerprint_hasher`](Self::fingerprint_hasher) is set.
+    ///
+    /// A request reusing a key with a different fingerprint is rejected with
+    /// `422 Unprocessable Entity` and [`IdempotencyError::Mismatch`] instead of being replayed
+    /// the response of another request. Handlers still receive the body intact.
+    ///
+    /// Bodies are hashed as they arrive, but buffered to be handed on, so those larger than
+    /// `max_body_size` bytes are rejected with `413 Payload Too Large` and
+    /// [`IdempotencyError::TooLarge`], unless [`oversized_body`](Self::oversized_body) says
+    /// otherwise.
+    ///
+    /// ```
+    /// use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    /// use actix_web_idempotency::Idempotency;
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() {
+    /// let app = test::init_service(
+    ///     App::new()
+    ///         .wrap(Idempotency::builder().fingerprint(64 * 1024).build().unwrap())
+    ///         .route("/transfers", web::post().to(|| async { HttpResponse::Created().finish() })),
+    /// )
+    /// .await;
+    ///
+    /// let transfer = |uri| {
+    ///     test::TestRequest::post()
+    ///         .uri(uri)
+    ///         .insert_header(("Idempotency-Key", "transfer-1"))
+    ///         .to_request()
+    /// };
+    /// let res = test::call_service(&app, transfer("/transfers?amount=10")).await;
+    /// assert_eq!(res.status(), StatusCode::CREATED);
+    ///
+    /// // the query string is part of the request, like its body
+    /// let res = test::call_service(&app, transfer("/transfers?amount=1000")).await;
+    /// assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    /// # }
+    /// ```
+    pub fn fingerprint(mut self, max_body_size: usize) -> Self {
+        self.fingerprint_limit = Some(max_body_size);
+        self
+    }
+
+    /// What [`fingerprint`](Self::fingerprint) does with bodies larger than it buffers.
+    /// Defaults to [`OversizedBody::Reject`].
+    ///
+    /// ```
+    /// use actix_web_idempotency::{Idempotency, OversizedBody};
+    ///
+    /// // uploads above 1 MiB are fingerprinted by their headers
+    /// let idempotency = Idempotency::builder()
+    ///     .fingerprint(1024 * 1024)
+    ///     .oversized_body(OversizedBody::HeadersOnly)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn oversized_body(mut self, oversized: OversizedBody) -> Self {
+        self.oversized_body = oversized;
+        self
+    }
+
+    /// Hash [`fingerprint`](Self::fingerprint) uses. Defaults to [`Sha256Hasher`].
+    pub fn fingerprint_hasher(mut self, hasher: impl FingerprintHasher + 'static) -> Self {
+        self.fingerprint_hasher = Some(Arc::new(hasher));
+        self
+    }
+
+    /// Compresses stored response bodies, see [`Compression`]. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Encrypts stored response headers and bodies, see [`Encryption`]. Off by default.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Hashes idempotency keys before they are used as store keys, see [`KeyHashing`]. Off by
+    /// default.
+    #[cfg(feature = "key-hashing")]
+    pub fn key_hashing(mut self, hashing: KeyHashing) -> Self {
+        self.key_hashing = Some(hashing);
+        self
+    }
+
+    /// Only handles requests whose path matches `pattern`, or any other included pattern.
+    ///
+    /// By default every request passing through the middleware is handled. Requests that are
+    /// not go straight to the handler, without needing an idempotency key.
+    pub fn include(mut self, pattern: PathPattern) -> Self {
+        self.include.push(pattern);
+        self
+    }
+
+    /// Leaves requests whose path matches `pattern` alone, e.g. health checks or metrics, even
+    /// if they are included.
+    pub fn exclude(mut self, pattern: PathPattern) -> Self {
+        self.exclude.push(pattern);
+        self
+    }
+
+    /// Leaves requests alone for which `predicate`, or any other one given, returns `true`,
+    /// e.g. those sent by an internal batch processor. They go straight to the handler, without
+    /// needing an idempotency key.
+    ///
+    /// ```
+    /// use actix_web_idempotency::Idempotency;
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .skip_if(|req| req.headers().contains_key("x-batch-job"))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn skip_if(
+        mut self,
+        predicate: impl Fn(&ServiceRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.skip_if.push(Arc::new(predicate));
+        self
+    }
+
+    /// Applies `policy` to requests matching the route `pattern`, as registered with `App`,
+    /// e.g. `/orders/{id}`, instead of the middleware's own settings, see [`IdempotencyPolicy`].
+    ///
+    /// A policy registered as app data of the matched scope or resource takes precedence.
+    pub fn route_policy(mut self, pattern: impl Into<String>, policy: IdempotencyPolicy) -> Self {
+        self.route_policies.insert(pattern.into(), policy);
+        self
+    }
+
+    /// Caps how often a stored response is replayed. Further requests reusing its key are
+    /// rejected with `429 Too Many Requests` and [`IdempotencyError::TooManyReplays`] until the
+    /// entry expires.
+    ///
+    /// This protects the store from clients stuck retrying the same request. Replays are counted
+    /// per process and reported through [`IdempotencyMetrics::record_replay_count`].
+    pub fn replay_limit(mut self, max: u64) -> Self {
+        self.replay_limit = Some(max);
+        self
+    }
+
+    /// Rewrites stored responses before they are replayed, see [`ReplayTransformer`].
+    ///
+    /// Bodies are then read into memory in full, even if the store could stream them.
+    pub fn replay_transformer(mut self, transformer: impl ReplayTransformer + 'static) -> Self {
+        self.replay_transformer = Some(Arc::new(transformer));
+        self
+    }
+
+    /// Caps how many requests this process executes at once while holding a reservation.
+    ///
+    /// Requests with new keys beyond the limit are rejected with `503 Service Unavailable`,
+    /// [`IdempotencyError::TooManyPending`] and a `Retry-After` header, so that a retry storm
+    /// cannot pile up reservations until memory or store connections run out. Replays are not
+    /// affected.
+    pub fn max_pending(mut self, limit: usize) -> Self {
+        self.max_pending = Some(limit);
+        self
+    }
+
+    /// Lets the reservation of a key lapse once `timeout` has passed without its request
+    /// completing, so that a retry executes again instead of being rejected with
+    /// [`IdempotencyError::InProgress`] for as long as the store holds on to the reservation.
+    ///
+    /// Without one, a request whose handler hangs, or whose process dies, keeps its key reserved
+    /// until the store lets go of it: after its `lock_timeout` for shared stores, never for the
+    /// [`MemoryStore`]. Stores that cannot expire reservations ignore it, see
+    /// [`IdempotencyStore::reserve_for`].
+    ///
+    /// This has to comfortably exceed the slowest handler, otherwise a retry arriving while the
+    /// original request is still running gets executed a second time. A request outliving its
+    /// reservation can neither release nor overwrite the reservation of a retry that took the
+    /// key over since, and only stores its response if nothing else claimed the key in between.
+    ///
+    /// ```
+    /// use std::{
+    ///     future,
+    ///     sync::atomic::{AtomicUsize, Ordering},
+    ///     time::Duration,
+    /// };
+    ///
+    /// use actix_web::{rt::time::{sleep, timeout}, test, web, App, HttpResponse};
+    /// use actix_web_idempotency::Idempotency;
+    ///
+    /// static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// async fn create_order() -> HttpResponse {
+    ///     if ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+    ///         // stuck on a payment provider that never answers
+    ///         future::pending::<()>().await;
+    ///     }
+    ///     HttpResponse::Created().finish()
+    /// }
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() {
+    /// let idempotency = Idempotency::builder()
+    ///     .pending_timeout(Duration::from_millis(100))
+    ///     .build()
+    ///     .unwrap();
+    /// let app = test::init_service(
+    ///     App::new()
+    ///         .wrap(idempotency)
+    ///         .route("/orders", web::post().to(create_order)),
+    /// )
+    /// .await;
+    ///
+    /// let order = || {
+    ///     test::TestRequest::post()
+    ///         .uri("/orders")
+    ///         .insert_header(("Idempotency-Key", "order-1"))
+    ///         .to_request()
+    /// };
+    ///
+    /// // the client gives up on the first attempt, which still holds the key
+    /// let first = timeout(Duration::from_millis(10), test::call_service(&app, order())).await;
+    /// assert!(first.is_err());
+    /// assert_eq!(test::call_service(&app, order()).await.status(), 409);
+    ///
+    /// // until its reservation lapses
+    /// sleep(Duration::from_millis(100)).await;
+    /// assert_eq!(test::call_service(&app, order()).await.status(), 201);
+    /// # }
+    /// ```
+    pub fn pending_timeout(mut self, timeout: Duration) -> Self {
+        self.pending_timeout = Some(timeout);
+        self
+    }
+
+    /// Lets a request whose key is still being processed by another request wait up to `timeout`
+    /// for that request to complete and then replays its response, instead of rejecting it right
+    /// away with [`conflict_status`](Self::conflict_status) and [`IdempotencyError::InProgress`].
+    ///
+    /// Requests waiting on the same process are woken as soon as the response is stored. The
+    /// store is also polled every 100 ms to notice requests completing on other instances. If the
+    /// original request fails instead, a waiting request takes over and executes itself.
+    pub fn wait_for_completion(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = Some(timeout);
+        self
+    }
+
+    /// Status of the response to a request whose key is still being processed by another
+    /// request. Defaults to [`ConflictStatus::Conflict`].
+    ///
+    /// Requests reusing the key of a completed request under [`KeyReuse::Reject`] are still
+    /// answered with `409 Conflict`.
+    pub fn conflict_status(mut self, status: ConflictStatus) -> Self {
+        self.conflict_status = status;
+        self
+    }
+
+    /// JSON body of the response to a request whose key is still being processed by another
+    /// request, instead of the one every [`IdempotencyError`] is rendered as.
+    ///
+    /// `{key}` and `{message}` in the template are replaced by the request's key and a
+    /// description of the error, escaped to fit into JSON strings.
+    ///
+    /// ```
+    /// use actix_web_idempotency::{ConflictStatus, Idempotency};
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .conflict_status(ConflictStatus::TooEarly)
+    ///     .conflict_body(r#"{"code":"request_in_flight","key":"{key}"}"#)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn conflict_body(mut self, template: impl Into<String>) -> Self {
+        self.conflict_body = Some(template.into());
+        self
+    }
+
+    /// Asks clients turned away because their key is still being processed to retry after
+    /// `delay`, through a `Retry-After` header. Off by default.
+    pub fn conflict_retry_after(mut self, delay: Duration) -> Self {
+        self.conflict_retry_after = Some(delay);
+        self
+    }
+
+    /// How the bodies of rejections, e.g. of requests with a missing or malformed key, are
+    /// rendered. Defaults to [`ErrorFormat::Json`].
+    ///
+    /// ```
+    /// use actix_web_idempotency::{ErrorFormat, Idempotency};
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .error_format(ErrorFormat::Xml)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// A [`conflict_body`](Self::conflict_body) still takes precedence.
+    pub fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = format;
+        self
+    }
+
+    /// Renders rejections as [problem details](https://www.rfc-editor.org/rfc/rfc9457) of type
+    /// `application/problem+json`, titled as the IETF draft suggests, instead of the JSON
+    /// described at [`IdempotencyError`]. Off by default.
+    ///
+    /// Shorthand for [`error_format`](Self::error_format) with [`ErrorFormat::ProblemJson`], or
+    /// [`ErrorFormat::Json`] if `false`.
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.error_format = if problem_details {
+            ErrorFormat::ProblemJson
+        } else {
+            ErrorFormat::Json
+        };
+        self
+    }
+
+    /// Lets requests with a safe method, i.e. `GET`, `HEAD`, `OPTIONS` or `TRACE`, through to
+    /// the handler when they carry no key, instead of rejecting them with
+    /// [`IdempotencyError::Missing`]. Off by default.
+    ///
+    /// Safe requests carrying a key are still handled like any other.
+    pub fn optional_for_safe_methods(mut self, optional: bool) -> Self {
+        self.safe_methods_optional = optional;
+        self
+    }
+
+    /// Turns on everything the given version of the IETF draft recommends:
+    ///
+    /// - [`optional_for_safe_methods`](Self::optional_for_safe_methods), as keys only matter
+    ///   for requests that are not idempotent already.
+    /// - [`fingerprint`](Self::fingerprint)ing bodies up to 1 MiB, unless enabled already, so
+    ///   that reusing a key for a different request is rejected with `422 Unprocessable Entity`.
+    /// - `409 Conflict` for requests whose key is still being processed, with a `Retry-After` of
+    ///   one second unless [`conflict_retry_after`](Self::conflict_retry_after) was set.
+    /// - [`ErrorFormat::ProblemJson`] as error bodies.
+    ///
+    /// Settings made after this call override the preset.
+    ///
+    /// ```
+    /// use actix_web_idempotency::{Idempotency, SpecCompliance};
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .spec_compliance(SpecCompliance::DraftV6)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn spec_compliance(mut self, spec: SpecCompliance) -> Self {
+        match spec {
+            SpecCompliance::DraftV6 => {
+                self.safe_methods_optional = true;
+                self.fingerprint_limit
+                    .get_or_insert(DRAFT_FINGERPRINT_LIMIT);
+                self.conflict_status = ConflictStatus::Conflict;
+                self.conflict_retry_after
+                    .get_or_insert(DRAFT_CONFLICT_RETRY_AFTER);
+                self.error_format = ErrorFormat::ProblemJson;
+            }
+        }
+
+        self
+    }
+
+    /// What to do with requests arriving without an idempotency key. Defaults to rejecting them
+    /// with `400 Bad Request`.
+    ///
+    /// Does not apply to requests let through by
+    /// [`optional_for_safe_methods`](Self::optional_for_safe_methods) or given a key by
+    /// [`issue_missing_keys`](Self::issue_missing_keys).
+    ///
+    /// ```
+    /// use actix_web::http::StatusCode;
+    /// use actix_web_idempotency::{Idempotency, MissingKey};
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .missing_key(MissingKey::Reject(StatusCode::PRECONDITION_REQUIRED))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn missing_key(mut self, missing: MissingKey) -> Self {
+        self.missing_key = missing;
+        self
+    }
+
+    /// Copies the request's `Idempotency-Key` into the headers of its response, whether it was
+    /// produced by the handler, replayed or a rejection. Off by default.
+    ///
+    /// Useful for clients correlating responses by key. Requests whose key is missing or
+    /// malformed are answered without one.
+    pub fn echo_key(mut self, echo: bool) -> Self {
+        self.echo_key = echo;
+        self
+    }
+
+    /// Gives requests arriving without an idempotency key one, instead of rejecting them with
+    /// [`IdempotencyError::Missing`]. Off by default.
+    ///
+    /// The key is a random UUID, sent back in the `Idempotency-Key` header of the response
+    /// whether or not [`echo_key`](Self::echo_key) is on, so that clients unable to come up with
+    /// keys themselves can retry with it.
+    pub fn issue_missing_keys(mut self, issue: bool) -> Self {
+        self.issue_keys = issue;
+        self
+    }
+
+    /// Periodically sweeps expired entries out of the store.
+    ///
+    /// The task is spawned on the runtime of the first worker that starts the middleware and
+    /// reports every sweep through [`IdempotencyMetrics::record_reclaimed`].
+    pub fn gc_interval(mut self, interval: Duration) -> Self {
+        self.gc_interval = Some(interval);
+        self
+    }
+
+    /// Builds the middleware, unless the configuration is inconsistent.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use actix_web_idempotency::{ConfigError, Idempotency};
+    ///
+    /// let built = Idempotency::builder().ttl(Duration::ZERO).build();
+    /// assert_eq!(built.err(), Some(ConfigError::ZeroTtl));
+    /// ```
+    pub fn build(self) -> Result<Idempotency, ConfigError> {
+        self.check()?;
+
+        Ok(Idempotency {
+            inner: Arc::new(Inner {
+                store: self.store.unwrap_or_else(|| Arc::new(MemoryStore::new())),
+                store_from_app_data: self.store_from_app_data,
+                metrics: self.metrics.unwrap_or_else(|| Arc::new(NoopMetrics)),
+                events: self.events,
+                clock: self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+                ttl: chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::MAX),
+                ttl_header: self.ttl_header,
+                ttl_jitter: self.ttl_jitter,
+                no_store_header: self.no_store_header,
+                header_filter: self.header_filter,
+                content_types: self.content_types,
+                request_id_header: self.request_id_header,
+                key_extractor: self
+                    .key_extractor
+                    .unwrap_or_else(|| Arc::new(HeaderKey::default())),
+                key_validator: self.key_validator,
+                key_reuse: self.key_reuse,
+                conflict_location: self.conflict_location,
+                failure_mode: self.failure_mode,
+                fingerprint_limit: self.fingerprint_limit,
+                oversized_body: self.oversized_body,
+                fingerprint_hasher: self
+                    .fingerprint_hasher
+                    .unwrap_or_else(|| Arc::new(Sha256Hasher)),
+                compression: self.compression,
+                #[cfg(feature = "encryption")]
+                encryption: self.encryption,
+                #[cfg(feature = "key-hashing")]
+                key_hashing: self.key_hashing,
+                include: self.include,
+                exclude: self.exclude,
+                skip_if: self.skip_if,
+                route_policies: self.route_policies,
+                replay_limit: self.replay_limit,
+                replays: ReplayCounter::default(),
+                replay_transformer: self.replay_transformer,
+                max_pending: self.max_pending,
+                pending: Pending::default(),
+                pending_timeout: self.pending_timeout,
+                wait_timeout: self.wait_timeout,
+                waiters: Waiters::default(),
+                conflict_status: self.conflict_status,
+                conflict_body: self.conflict_body,
+                conflict_retry_after: self.conflict_retry_after,
+                error_format: self.error_format,
+                safe_methods_optional: self.safe_methods_optional,
+                echo_key: self.echo_key,
+                missing_key: self.missing_key,
+                issue_keys: self.issue_keys,
+                gc_interval: self.gc_interval,
+                gc_started: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    // Catches settings that are invalid on their own or defeat one another.
+    fn check(&self) -> Result<(), ConfigError> {
+        if self.ttl.is_zero() {
+            return Err(ConfigError::ZeroTtl);
+        }
+        if self.gc_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err(ConfigError::ZeroGcInterval);
+        }
+        if self.max_pending == Some(0) {
+            return Err(ConfigError::ZeroMaxPending);
+        }
+        if self
+            .pending_timeout
+            .is_some_and(|timeout| timeout.is_zero())
+        {
+            return Err(ConfigError::ZeroPendingTimeout);
+        }
+        if self.ttl_jitter >= 100 {
+            return Err(ConfigError::TtlJitter(self.ttl_jitter));
+        }
+
+        self.key_validator.check()?;
+
+        if let Some(template) = &self.conflict_body {
+            let body = template
+                .replace("{key}", "key")
+                .replace("{message}", "message");
+            if let Err(err) = serde_json::from_str::<serde_json::Value>(&body) {
+                return Err(ConfigError::ConflictBody(err.to_string()));
+            }
+        }
+
+        let route_missing_keys = self
+            .route_policies
+            .values()
+            .filter_map(|policy| policy.missing_key);
+        for missing_key in [self.missing_key].into_iter().chain(route_missing_keys) {
+            if let MissingKey::Reject(status) = missing_key {
+                if !status.is_client_error() && !status.is_server_error() {
+                    return Err(ConfigError::MissingKeyStatus(status));
+                }
+            }
+        }
+
+        // issued keys leave no request without one to pass through
+        if self.issue_keys && self.missing_key == MissingKey::PassThrough {
+            return Err(ConfigError::Conflicting(
+                "issue_missing_keys",
+                "missing_key",
+            ));
+        }
+
+        // nothing is ever replayed under `KeyReuse::Reject`
+        if self.key_reuse == KeyReuse::Reject {
+            if self.replay_limit.is_some() {
+                return Err(ConfigError::Conflicting("replay_limit", "key_reuse"));
+            }
+            if self.replay_transformer.is_some() {
+                return Err(ConfigError::Conflicting("replay_transformer", "key_reuse"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Inner {
+    // whether `req` is subject to idempotency at all
+    fn applies(&self, req: &ServiceRequest) -> bool {
+        let path = req.path();
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(path));
+
+        included
+            && !self.exclude.iter().any(|pattern| pattern.matches(path))
+            && !self.skip_if.iter().any(|skip| skip(req))
+            && self
+                .policy(req.request())
+                .is_none_or(|policy| policy.enabled)
+    }
+
+    // the policy of the route `req` matched, if any
+    fn policy(&self, req: &HttpRequest) -> Option<IdempotencyPolicy> {
+        if let Some(policy) = req.app_data::<IdempotencyPolicy>() {
+            return Some(policy.clone());
+        }
+        if self.route_policies.is_empty() {
+            return None;
+        }
+
+        self.route_policies.get(&req.match_pattern()?).cloned()
+    }
+
+    fn missing_key_for(&self, req: &HttpRequest) -> MissingKey {
+        self.policy(req)
+            .and_then(|policy| policy.missing_key)
+            .unwrap_or(self.missing_key)
+    }
+
+    // the store to use for `req`, unless it was to be registered as app data but is not
+    fn store_for(&self, req: &ServiceRequest) -> Option<Arc<dyn IdempotencyStore>> {
+        if !self.store_from_app_data {
+            return Some(Arc::clone(&self.store));
+        }
+
+        req.app_data::<web::Data<Arc<dyn IdempotencyStore>>>()
+            .map(|store| Arc::clone(store.get_ref()))
+    }
+
+    async fn commit(
+        self: &Arc<Self>,
+        store: &Arc<dyn IdempotencyStore>,
+        reservation: Reservation,
+        element: CacheElement,
+        event: Option<IdempotencyEvent>,
+    ) {
+        match store.maintenance_remaining().await {
+            Ok(Some(_)) => {
+                // hold the write back until maintenance is over instead of racing it
+                let inner = Arc::clone(self);
+                let store = Arc::clone(store);
+                rt::spawn(async move {
+                    while let Ok(Some(remaining)) = store.maintenance_remaining().await {
+                        rt::time::sleep(remaining).await;
+                    }
+
+                    inner.write(&*store, &reservation, element, event).await;
+                });
+            }
+            _ => self.write(&**store, &reservation, element, event).await,
+        }
+    }
+
+    async fn write(
+        &self,
+        store: &dyn IdempotencyStore,
+        reservation: &Reservation,
+        element: CacheElement,
+        event: Option<IdempotencyEvent>,
+    ) {
+        let key = element.key().to_owned();
+
+        let element = match self.seal(element) {
+            Ok(element) => element,
+            Err(err) => {
+                log::warn!("failed to encrypt response for idempotency key {key}: {err}");
+                self.abort(store, reservation).await;
+                return;
+            }
+        };
+
+        self.metrics.record_body_size(element.body().len());
+
+        // the handler already ran, so a failure to cache must not cost the client its response
+        match self
+            .timed("commit", store.commit(reservation, element))
+            .await
+        {
+            Ok(true) => {
+                if let (Some(events), Some(event)) = (&self.events, event) {
+                    events.on_store(&event);
+                }
+            }
+            Ok(false) => self.metrics.record_double_execution(&key),
+            Err(err) => log::warn!("failed to store response for idempotency key {key}: {err}"),
+        }
+
+        self.waiters.notify(&key);
+    }
+
+    // encrypts an entry before it is handed to the store
+    fn seal(&self, element: CacheElement) -> io::Result<CacheElement> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            return element.seal(encryption);
+        }
+
+        Ok(element)
+    }
+
+    // whether entries read from the store have to be opened, even those in plain text
+    fn encrypts(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        if self.encryption.is_some() {
+            return true;
+        }
+
+        false
+    }
+
+    // what `key` is stored under
+    fn store_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        #[cfg(feature = "key-hashing")]
+        if let Some(hashing) = &self.key_hashing {
+            return Cow::Owned(hashing.hash(key));
+        }
+
+        Cow::Borrowed(key)
+    }
+
+    // decrypts an entry read from the store
+    fn open(&self, element: CacheElement) -> io::Result<CacheElement> {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = &self.encryption {
+            return element.open(encryption);
+        }
+
+        Ok(element)
+    }
+
+    // Answers the request with the response stored for its key, unless that is not allowed.
+    async fn replay(
+        &self,
+        req: ServiceRequest,
+        key: &str,
+        mut element: CacheElement,
+        body: Option<BodyStream>,
+        fingerprint: Option<&str>,
+    ) -> Result<ServiceResponse, Error> {
+        // the key was reused for a different request
+        let conflict = if fingerprint.is_some() && element.fingerprint() != fingerprint {
+            Some(IdempotencyError::Mismatch)
+        } else if self.key_reuse == KeyReuse::Reject {
+            Some(IdempotencyError::AlreadyExists)
+        } else {
+            None
+        };
+
+        if let Some(error) = conflict {
+            // the resolver may want to look at a body still left in the store
+            if let (Some(_), Some(body)) = (&self.conflict_location, body) {
+                match body.collect().await {
+                    Ok(body) => element.set_body(body),
+                    Err(err) => log::warn!(
+                        "failed to read stored response for idempotency key {key}: {err}"
+                    ),
+                }
+            }
+
+            let mut res = self.reject(req, Some(key), error);
+            if let Some(location) = self.original_location(&element) {
+                res.headers_mut().insert(header::LOCATION, location);
+            }
+            return Ok(res);
+        }
+
+        if element.is_tombstone() {
+            return Ok(self.reject(req, Some(key), IdempotencyError::Evicted));
+        }
+
+        if let Some(limit) = self.replay_limit {
+            let count = self.replays.increment(
+                key,
+                element.created_at(),
+                element.expires_at(),
+                self.clock.now(),
+            );
+            self.metrics.record_replay_count(key, count);
+
+            if count > limit {
+                return Ok(self.reject(req, Some(key), IdempotencyError::TooManyReplays));
+            }
+        }
+
+        let mut replay = match element.to_response(self.clock.now(), body) {
+            Ok(replay) => replay,
+            Err(err) => {
+                log::warn!("failed to replay stored response for idempotency key {key}: {err}");
+                return Ok(self.reject(req, Some(key), IdempotencyError::Unreadable));
+            }
+        };
+
+        if let Some(transformer) = &self.replay_transformer {
+            let (res, body) = replay.into_parts();
+            // the body was read into memory already
+            let body = body.try_into_bytes().unwrap_or_default();
+
+            let mut rewritten = Replay::new(res.status(), res.headers().clone(), body);
+            transformer
+                .transform(req.request(), &element, &mut rewritten)
+                .await?;
+            replay = rewritten.into_response();
+        }
+
+        // the client holds the stored response already
+        if is_not_modified(&req, &replay) {
+            *replay.status_mut() = StatusCode::NOT_MODIFIED;
+            replay.headers_mut().remove(header::CONTENT_TYPE);
+            replay = replay.set_body(BoxBody::new(()));
+        } else if req.method() == Method::HEAD {
+            // the body is left out, but its length still announced
+            let len = match replay.body().size() {
+                BodySize::Sized(len) => len,
+                _ => 0,
+            };
+            replay = replay.set_body(BoxBody::new(SizedStream::new(
+                len,
+                stream::empty::<Result<Bytes, Infallible>>(),
+            )));
+        }
 
-pub struct Idempotency;
+        self.metrics.record_hit();
+        #[cfg(feature = "otel")]
+        otel::record(Some(key), otel::Outcome::Replayed);
+
+        let (http_request, _payload) = req.into_parts();
+        finish(
+            &http_request,
+            IdempotencyOutcome::Replayed,
+            Some(element.created_at()),
+        );
+        if let (Some(events), Some(event)) = (
+            &self.events,
+            self.event(&http_request, Some(key), replay.status()),
+        ) {
+            events.on_replay(&event.with_metadata(element.metadata().clone()));
+        }
+
+        Ok(ServiceResponse::new(http_request, replay))
+    }
+
+    // Where the resource created by the request that stored `element` lives.
+    fn original_location(&self, element: &CacheElement) -> Option<HeaderValue> {
+        let Some(resolve) = &self.conflict_location else {
+            return element.location();
+        };
+
+        let location = resolve(element)?;
+        match HeaderValue::try_from(location) {
+            Ok(location) => Some(location),
+            Err(err) => {
+                log::warn!(
+                    "ignoring invalid conflict location for idempotency key {}: {err}",
+                    element.key()
+                );
+                None
+            }
+        }
+    }
+
+    // `ttl` moved by a random share of the jitter.
+    fn jittered(&self, ttl: chrono::Duration) -> chrono::Duration {
+        let spread = ttl.num_milliseconds() / 100 * i64::from(self.ttl_jitter);
+        if spread <= 0 {
+            return ttl;
+        }
+
+        // v4 UUIDs are random enough for this, and save a dependency
+        let random = Uuid::new_v4().as_u128() % (2 * spread as u128 + 1);
+        let offset = random as i64 - spread;
+
+        ttl.checked_add(&chrono::Duration::milliseconds(offset))
+            .unwrap_or(ttl)
+    }
+
+    // The TTL of responses to `req`, unless the handler asks for another one.
+    fn ttl_for(&self, req: &HttpRequest) -> chrono::Duration {
+        match self.policy(req).and_then(|policy| policy.ttl) {
+            Some(ttl) => chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX),
+            None => self.ttl,
+        }
+    }
+
+    // The TTL the handler asked for through the TTL header, which is taken off the response, or
+    // `ttl` if it did not ask.
+    fn requested_ttl(
+        &self,
+        key: &str,
+        ttl: chrono::Duration,
+        headers: &mut header::HeaderMap,
+    ) -> chrono::Duration {
+        let Some(name) = &self.ttl_header else {
+            return ttl;
+        };
+        let Some(value) = headers.remove(name).last() else {
+            return ttl;
+        };
+
+        match value
+            .to_str()
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+        {
+            Some(secs) => chrono::Duration::from_std(Duration::from_secs(secs))
+                .unwrap_or(chrono::Duration::MAX),
+            None => {
+                log::warn!("ignoring invalid {name} header {value:?} for idempotency key {key}");
+                ttl
+            }
+        }
+    }
+
+    // Whether the handler asked for its response not to be stored, taking the no-store header off
+    // the response.
+    fn opted_out<B>(&self, res: &mut ServiceResponse<B>) -> bool {
+        let header = self
+            .no_store_header
+            .as_ref()
+            .is_some_and(|name| res.headers_mut().remove(name).next().is_some());
+
+        header
+            || res.request().extensions().contains::<NoStore>()
+            || res.response().extensions().contains::<NoStore>()
+            || self
+                .policy(res.request())
+                .is_some_and(|policy| !policy.store_responses)
+    }
+
+    // Settles whether the request gets replayed a stored response or executed, waiting for a
+    // concurrent request with the same key to complete if so configured.
+    async fn claim(
+        &self,
+        store: &dyn IdempotencyStore,
+        key: &str,
+    ) -> Result<Claim<'_>, IdempotencyError> {
+        let deadline = self.wait_timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            // subscribed before looking, so that a completion in between is not missed
+            let notify = self.waiters.subscribe(key);
+            let notified = notify.notified();
+
+            let claim = self.try_claim(store, key).await;
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match (claim, remaining) {
+                (Ok(Claim::InProgress), Some(remaining)) if !remaining.is_zero() => {
+                    // requests completing on other instances are only noticed by looking again
+                    let _ = rt::time::timeout(remaining.min(WAIT_POLL_INTERVAL), notified).await;
+                    self.waiters.unsubscribe(key, notify);
+                }
+                (claim, _) => {
+                    drop(notified);
+                    self.waiters.unsubscribe(key, notify);
+                    return claim;
+                }
+            }
+        }
+    }
+
+    async fn try_claim(
+        &self,
+        store: &dyn IdempotencyStore,
+        key: &str,
+    ) -> Result<Claim<'_>, IdempotencyError> {
+        if let Some((element, body)) = self.lookup(store, key).await? {
+            return Ok(Claim::Cached(Box::new(element), body));
+        }
+
+        // held until the response is stored or the reservation released
+        let Some(pending) = self.pending.acquire(self.max_pending) else {
+            return Ok(Claim::TooManyPending);
+        };
+
+        let reservation = Reservation::new(key);
+        let reserve = match self.pending_timeout {
+            Some(timeout) => store.reserve_for(&reservation, timeout),
+            None => store.reserve(&reservation),
+        };
+        let reserved = self.timed("reserve", reserve).await?;
+
+        // another request with the same key is still being processed
+        if !reserved {
+            return Ok(Claim::InProgress);
+        }
+
+        Ok(Claim::Reserved(reservation, pending))
+    }
+
+    // The response stored for `key`, with the body left in the store if it can be streamed from
+    // there.
+    async fn lookup(
+        &self,
+        store: &dyn IdempotencyStore,
+        key: &str,
+    ) -> Result<Option<(CacheElement, Option<BodyStream>)>, IdempotencyError> {
+        let Some((mut element, body)) = self.timed("get", store.get_streamed(key)).await? else {
+            return Ok(None);
+        };
+
+        // bodies that have to be decrypted, or checked to be, or decompressed are needed in full
+        if !body.is_ready()
+            && !self.encrypts()
+            && element.compression().is_none()
+            && self.replay_transformer.is_none()
+        {
+            return Ok(Some((element, Some(body))));
+        }
+
+        element.set_body(body.collect().await?);
+        let element = self.open(element).map_err(|err| {
+            log::warn!("failed to decrypt stored response for idempotency key {key}: {err}");
+            IdempotencyError::Unreadable
+        })?;
+
+        Ok(Some((element, None)))
+    }
+
+    // runs a call to the store, reporting how long it took
+    async fn timed<T>(&self, operation: &'static str, call: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = call.await;
+        self.metrics
+            .record_store_latency(operation, started.elapsed());
+        output
+    }
+
+    // Gives up `reservation`. Should it have lapsed and the key been reserved again by a retry,
+    // the store leaves the retry's reservation in place.
+    async fn abort(&self, store: &dyn IdempotencyStore, reservation: &Reservation) {
+        let key = reservation.key();
+        if let Err(err) = self.timed("abort", store.abort(reservation)).await {
+            log::warn!("failed to release idempotency key {key}: {err}");
+        }
+
+        self.waiters.notify(key);
+    }
+
+    fn emit(
+        &self,
+        req: &HttpRequest,
+        key: Option<&str>,
+        status: StatusCode,
+        emit: impl FnOnce(&dyn IdempotencyEvents, &IdempotencyEvent),
+    ) {
+        if let Some(events) = &self.events {
+            emit(events.as_ref(), &IdempotencyEvent::new(req, key, status));
+        }
+    }
+
+    // only bothers collecting the details if someone is listening
+    fn event(
+        &self,
+        req: &HttpRequest,
+        key: Option<&str>,
+        status: StatusCode,
+    ) -> Option<IdempotencyEvent> {
+        self.events
+            .as_ref()
+            .map(|_| IdempotencyEvent::new(req, key, status))
+    }
+
+    // Hands the request to the handler without idempotency, as the store failed.
+    async fn fail_open<S, B>(
+        &self,
+        service: &S,
+        req: ServiceRequest,
+        key: &str,
+        err: &StoreError,
+    ) -> Result<ServiceResponse, Error>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        B: MessageBody + 'static,
+    {
+        log::warn!("idempotency store failed, executing key {key} without idempotency: {err}");
+        self.metrics.record_fail_open(key);
+
+        let res = service.call(req).await?;
+        finish(res.request(), IdempotencyOutcome::NotStored, None);
+        Ok(res.map_into_boxed_body())
+    }
+
+    // Answers the request with `error` without it ever reaching the handler.
+    fn reject(
+        &self,
+        req: ServiceRequest,
+        key: Option<&str>,
+        error: IdempotencyError,
+    ) -> ServiceResponse {
+        let (http_request, _payload) = req.into_parts();
+        finish(
+            &http_request,
+            IdempotencyOutcome::Rejected(error.clone()),
+            None,
+        );
+
+        if matches!(
+            error,
+            IdempotencyError::AlreadyExists
+                | IdempotencyError::InProgress
+                | IdempotencyError::Evicted
+        ) {
+            self.metrics.record_conflict();
+        }
+
+        #[cfg(feature = "otel")]
+        otel::record(
+            key,
+            match error {
+                IdempotencyError::AlreadyExists
+                | IdempotencyError::InProgress
+                | IdempotencyError::Evicted => otel::Outcome::Conflict,
+                _ => otel::Outcome::Rejected,
+            },
+        );
+
+        let status = match (&error, self.missing_key_for(&http_request)) {
+            (IdempotencyError::InProgress, _) => self.conflict_status.into(),
+            (IdempotencyError::Missing, MissingKey::Reject(status)) => status,
+            _ => error.status_code(),
+        };
+
+        self.emit(&http_request, key, status, |events, event| match &error {
+            IdempotencyError::InProgress => events.on_conflict(event),
+            error => events.on_reject(event, error),
+        });
+
+        let (body, content_type) = match (&error, &self.conflict_body) {
+            (IdempotencyError::InProgress, Some(template)) => {
+                let body = template
+                    .replace("{key}", &json_escape(key.unwrap_or_default()))
+                    .replace("{message}", &json_escape(&error.to_string()));
+                (body, "application/json")
+            }
+            _ => self.error_format.render(&error, status),
+        };
+
+        // keeps the error attached to the response for error handlers further out
+        let mut res = HttpResponse::from_error(error);
+        *res.status_mut() = status;
+        if status == StatusCode::from(ConflictStatus::TooEarly) {
+            // unknown to the `http` crate
+            res.head_mut().reason = Some("Too Early");
+        }
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        let res = res.set_body(BoxBody::new(body));
+
+        ServiceResponse::new(http_request, res)
+    }
+}
+
+fn spawn_gc(inner: Arc<Inner>, interval: Duration) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            // a failed sweep is simply retried on the next tick
+            inner.replays.purge_expired(inner.clock.now());
+
+            match inner.store.purge_expired().await {
+                Ok(reclaimed) => inner.metrics.record_reclaimed(reclaimed),
+                Err(err) => log::warn!("failed to purge expired idempotency entries: {err}"),
+            }
+        }
+    });
+}
 
 impl<S, B> Transform<S, ServiceRequest> for Idempotency
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<EitherBody<B>>;
+    type Response = ServiceResponse<BoxBody>;
 
     type Error = Error;
 
@@ -41,99 +2030,604 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(IdempotencyMiddleware { service }))
-    }
-}
+        if let Some(interval) = self.inner.gc_interval {
+            if !self.inner.gc_started.swap(true, Ordering::SeqCst) {
+                spawn_gc(Arc::clone(&self.inner), interval);
+            }
+        }
 
-pub struct CacheElement {
-    token: Uuid,
-    response: String,
-    created_at: DateTime<Utc>,
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            inner: Arc::clone(&self.inner),
+        }))
+    }
 }
 
 pub struct IdempotencyMiddleware<S> {
-    service: S,
+    service: Rc<S>,
+    inner: Arc<Inner>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "UPPERCASE")]
+/// Why the middleware answered a request itself instead of handing it to the handler.
+///
+/// Rejections are returned like any handler error, so they carry the error to enclosing
+/// middleware such as `ErrorHandlers`, and are rendered by [`ResponseError::error_response`] as
+/// JSON, e.g. `{"error":"MALFORMED","message":"..."}`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "UPPERCASE", tag = "error", content = "message")]
 pub enum IdempotencyError {
     Missing,
-    Malformed,
+    /// The key was rejected by the [`KeyValidator`], for the reason given.
+    Malformed(String),
+    /// The request carried more than one key header, so it is unclear which one applies.
+    #[serde(rename = "DUPLICATE_HEADER")]
+    DuplicateHeader,
     #[serde(rename = "ALREADY_EXISTS")]
     AlreadyExists,
+    #[serde(rename = "IN_PROGRESS")]
+    InProgress,
+    Maintenance,
+    /// The key was already used for a request with a different fingerprint.
+    Mismatch,
+    /// The request body is too large to be fingerprinted, or searched for a key by
+    /// [`JsonBodyKey`].
+    #[serde(rename = "TOO_LARGE")]
+    TooLarge,
+    /// The stored response was already replayed as often as the
+    /// [`replay_limit`](IdempotencyBuilder::replay_limit) allows.
+    #[serde(rename = "TOO_MANY_REPLAYS")]
+    TooManyReplays,
+    /// Too many requests are executing already, see
+    /// [`max_pending`](IdempotencyBuilder::max_pending).
+    #[serde(rename = "TOO_MANY_PENDING")]
+    TooManyPending,
+    /// The store failed. The cause is not revealed to clients.
+    #[serde(rename = "STORE_UNAVAILABLE", serialize_with = "redact")]
+    Store(Arc<StoreError>),
+    /// The stored response could not be replayed, e.g. because it failed to decrypt.
+    Unreadable,
+    /// The key was used before, but its response was evicted since and only a
+    /// [tombstone](MemoryStore::tombstones) is left, so it cannot be replayed.
+    #[serde(serialize_with = "explain_eviction")]
+    Evicted,
+}
+
+impl From<StoreError> for IdempotencyError {
+    fn from(err: StoreError) -> Self {
+        Self::Store(Arc::new(err))
+    }
+}
+
+impl fmt::Display for IdempotencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => f.write_str("missing idempotency key"),
+            Self::Malformed(message) => write!(f, "malformed idempotency key: {message}"),
+            Self::DuplicateHeader => f.write_str("more than one idempotency key header"),
+            Self::AlreadyExists => f.write_str("idempotency key was already used"),
+            Self::InProgress => f.write_str("a request with this idempotency key is in progress"),
+            Self::Maintenance => f.write_str("idempotency store is under maintenance"),
+            Self::Mismatch => f.write_str("idempotency key was used for a different request"),
+            Self::TooLarge => f.write_str("request body is too large to be buffered"),
+            Self::TooManyReplays => f.write_str("stored response was replayed too often"),
+            Self::TooManyPending => f.write_str("too many requests are in progress"),
+            Self::Store(err) => err.fmt(f),
+            Self::Unreadable => f.write_str("stored response could not be replayed"),
+            Self::Evicted => f.write_str(
+                "idempotency key was already used, but its response was evicted and cannot be \
+                 replayed",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdempotencyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Store(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseError for IdempotencyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Missing | Self::Malformed(_) | Self::DuplicateHeader => StatusCode::BAD_REQUEST,
+            Self::AlreadyExists | Self::InProgress | Self::Evicted => StatusCode::CONFLICT,
+            Self::Maintenance | Self::TooManyPending => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Mismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyReplays => StatusCode::TOO_MANY_REQUESTS,
+            Self::Store(_) | Self::Unreadable => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+impl IdempotencyError {
+    // The description of the error that is fit for clients, which leaves out why the store
+    // failed.
+    pub(crate) fn public_message(&self) -> String {
+        match self {
+            Self::Store(_) => "idempotency store failure".to_owned(),
+            error => error.to_string(),
+        }
+    }
+
+    // The error as problem details, titled as the IETF draft suggests for those it describes.
+    pub(crate) fn problem_details(&self, status: StatusCode) -> serde_json::Value {
+        let (title, detail) = match self {
+            Self::Missing => (
+                "Idempotency-Key is missing",
+                "This operation is idempotent and it requires correct usage of Idempotency Key.",
+            ),
+            Self::Mismatch => (
+                "Idempotency-Key is already used",
+                "This operation is idempotent and it requires correct usage of Idempotency Key. \
+                 Idempotency Key MUST not be reused across different payloads of this operation.",
+            ),
+            Self::InProgress => (
+                "A request is outstanding for this Idempotency-Key",
+                "A request with the same Idempotency-Key for the same operation is being \
+                 processed or is outstanding.",
+            ),
+            _ => {
+                return serde_json::json!({
+                    "title": status.canonical_reason().unwrap_or("Error"),
+                    "status": status.as_u16(),
+                    "detail": self.public_message(),
+                });
+            }
+        };
+
+        serde_json::json!({
+            "type": DRAFT_V6,
+            "title": title,
+            "status": status.as_u16(),
+            "detail": detail,
+        })
+    }
+}
+
+// Records the final outcome on the request's `IdempotencyContext`.
+fn finish(
+    req: &HttpRequest,
+    outcome: IdempotencyOutcome,
+    original_created_at: Option<chrono::DateTime<chrono::Utc>>,
+) {
+    if let Some(context) = req.extensions().get::<IdempotencyContext>() {
+        context.finish(outcome, original_created_at);
+    }
+}
+
+// Whether `req` asks not to be sent `replay` again through `If-None-Match`, which only applies to
+// successful responses carrying an `ETag`. Tags are compared weakly, as for `GET` requests.
+fn is_not_modified(req: &ServiceRequest, replay: &HttpResponse) -> bool {
+    if !replay.status().is_success() {
+        return false;
+    }
+    let Some(etag) = replay
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .and_then(|etag| etag.parse::<header::EntityTag>().ok())
+    else {
+        return false;
+    };
+
+    match <header::IfNoneMatch as header::Header>::parse(req) {
+        Ok(header::IfNoneMatch::Any) => true,
+        Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        Err(_) => false,
+    }
+}
+
+// Methods that are idempotent by definition, so need no key.
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn redact<S: Serializer>(_err: &Arc<StoreError>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("idempotency store failure")
 }
 
-#[derive(Serialize)]
-struct IdempotencyErrorWrapper {
-    error: IdempotencyError,
+// clients retrying would otherwise be left wondering why their key no longer works
+fn explain_eviction<S: Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&IdempotencyError::Evicted.to_string())
 }
 
 impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<EitherBody<B>>;
+    type Response = ServiceResponse<BoxBody>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!("Hi from start. You requested: {}", req.path());
-
-        if !req.headers().contains_key(HEADER_KEY) {
-            let (http_request, _payload) = req.into_parts();
-            return Box::pin(async {
-                Ok(ServiceResponse::new(
-                    http_request,
-                    //syntax magic to force the compiler to transform into `HttpResponse`
-                    Into::<HttpResponse>::into(IdempotencyError::Missing).map_into_right_body(),
-                ))
-            });
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !self.inner.applies(&req) {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_boxed_body()) });
         }
 
-        // unwrap/expect is safe as we previously checked for the key existing
-        let token = req
-            .headers()
-            .get(HEADER_KEY)
-            .expect("Couldn't extract idempotency key!");
+        let service = Rc::clone(&self.service);
+        let inner = Arc::clone(&self.inner);
+
+        Box::pin(async move {
+            let (key, issued) = match inner.key_extractor.extract(&mut req).await {
+                Ok(Some(key)) => (key, false),
+                Ok(None) if inner.safe_methods_optional && is_safe(req.method()) => {
+                    return Ok(service.call(req).await?.map_into_boxed_body());
+                }
+                Ok(None) if inner.issue_keys => (Uuid::new_v4().to_string(), true),
+                Ok(None) if inner.missing_key_for(req.request()) == MissingKey::PassThrough => {
+                    return Ok(service.call(req).await?.map_into_boxed_body());
+                }
+                Ok(None) => return Ok(inner.reject(req, None, IdempotencyError::Missing)),
+                Err(error) => return Ok(inner.reject(req, None, error)),
+            };
+
+            req.extensions_mut().insert(IdempotencyContext::new(&key));
+
+            if !issued {
+                if let Err(message) = inner.key_validator.validate(&key) {
+                    let error = IdempotencyError::Malformed(message);
+                    return Ok(inner.reject(req, Some(&key), error));
+                }
+            }
+
+            let echo = HeaderValue::from_str(&key)
+                .ok()
+                .filter(|_| inner.echo_key || issued);
+
+            let Some(store) = inner.store_for(&req) else {
+                log::error!(
+                    "no idempotency store registered as `web::Data<Arc<dyn IdempotencyStore>>`"
+                );
+                return Err(ErrorInternalServerError(
+                    "idempotency store is not configured",
+                ));
+            };
+
+            let handled = async move {
+                let key = key.as_str();
+                // clients only ever see their own key
+                let stored = inner.store_key(key);
+                let stored = stored.as_ref();
+
+                let maintenance = match store.maintenance_remaining().await {
+                    Ok(maintenance) => maintenance,
+                    Err(err) if inner.failure_mode == FailureMode::Open => {
+                        return inner.fail_open(&*service, req, key, &err).await;
+                    }
+                    Err(err) => return Ok(inner.reject(req, Some(key), err.into())),
+                };
+
+                if let Some(remaining) = maintenance {
+                    let mut res = inner.reject(req, Some(key), IdempotencyError::Maintenance);
+                    res.headers_mut()
+                        .insert(header::RETRY_AFTER, retry_after(remaining).into());
+                    return Ok(res);
+                }
+
+                // HEAD requests only look at what was stored, as their empty responses must not
+                // take the place of the one the key was used for
+                if req.method() == Method::HEAD {
+                    let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+
+                    return match inner.lookup(&*store, stored).await {
+                        Ok(Some((element, _)))
+                            if element.route().is_some_and(|stored| stored != route) =>
+                        {
+                            Ok(inner.reject(req, Some(key), IdempotencyError::Mismatch))
+                        }
+                        // there is no body to compare fingerprints of
+                        Ok(Some((element, body))) => {
+                            inner.replay(req, key, element, body, None).await
+                        }
+                        Ok(None) => {
+                            let res = service.call(req).await?;
+                            finish(res.request(), IdempotencyOutcome::NotStored, None);
+                            Ok(res.map_into_boxed_body())
+                        }
+                        Err(IdempotencyError::Store(err))
+                            if inner.failure_mode == FailureMode::Open =>
+                        {
+                            inner.fail_open(&*service, req, key, &err).await
+                        }
+                        Err(error) => Ok(inner.reject(req, Some(key), error)),
+                    };
+                }
+
+                let fingerprint = match inner.fingerprint_limit {
+                    Some(limit) => match fingerprint(
+                        &mut req,
+                        limit,
+                        &*inner.fingerprint_hasher,
+                        inner.oversized_body,
+                    )
+                    .await?
+                    {
+                        Fingerprint::Hash(hash) => Some(hash),
+                        Fingerprint::HeadersOnly(hash) => {
+                            log::warn!(
+                                "request body for idempotency key {key} exceeds {limit} bytes, \
+                                 fingerprinting its headers only"
+                            );
+                            inner.emit(
+                                req.request(),
+                                Some(key),
+                                StatusCode::OK,
+                                |events, event| events.on_fingerprint_fallback(event),
+                            );
+                            Some(hash)
+                        }
+                        Fingerprint::TooLarge => {
+                            return Ok(inner.reject(req, Some(key), IdempotencyError::TooLarge));
+                        }
+                    },
+                    None => None,
+                };
+
+                let claim = match inner.claim(&*store, stored).await {
+                    Ok(claim) => claim,
+                    Err(IdempotencyError::Store(err))
+                        if inner.failure_mode == FailureMode::Open =>
+                    {
+                        return inner.fail_open(&*service, req, key, &err).await;
+                    }
+                    Err(error) => return Ok(inner.reject(req, Some(key), error)),
+                };
+                let (reservation, _pending) = match claim {
+                    Claim::Reserved(reservation, pending) => (reservation, pending),
+                    Claim::Cached(element, body) => {
+                        return inner
+                            .replay(req, key, *element, body, fingerprint.as_deref())
+                            .await;
+                    }
+                    Claim::InProgress => {
+                        let mut res = inner.reject(req, Some(key), IdempotencyError::InProgress);
+                        if let Some(delay) = inner.conflict_retry_after {
+                            res.headers_mut()
+                                .insert(header::RETRY_AFTER, retry_after(delay).into());
+                        }
+                        return Ok(res);
+                    }
+                    Claim::TooManyPending => {
+                        let mut res =
+                            inner.reject(req, Some(key), IdempotencyError::TooManyPending);
+                        res.headers_mut()
+                            .insert(header::RETRY_AFTER, retry_after(PENDING_RETRY_AFTER).into());
+                        return Ok(res);
+                    }
+                };
+
+                inner.metrics.record_miss();
+
+                let metadata = IdempotencyMetadata::default();
+                req.extensions_mut().insert(metadata.clone());
+                // only ever handed out here, once the store reserved the key for this request
+                req.extensions_mut().insert(FirstExecution::new(key));
+
+                let context = req.extensions().get::<IdempotencyContext>().cloned();
+                let mut res = match service.call(req).await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        if let Some(context) = &context {
+                            context.finish(IdempotencyOutcome::NotStored, None);
+                        }
+                        inner.abort(&*store, &reservation).await;
+                        return Err(err);
+                    }
+                };
+                let ttl = inner.ttl_for(res.request());
+                let ttl = inner.jittered(inner.requested_ttl(key, ttl, res.headers_mut()));
+                let opted_out = inner.opted_out(&mut res);
+                // the handler returned an `Err`, or a service inside turned the request away,
+                // which a retry should get another chance at
+                let failed = res.response().error().is_some();
+
+                // checked before buffering, so streams are passed on as they are
+                if opted_out || failed || !inner.content_types.stores(res.headers()) {
+                    finish(res.request(), IdempotencyOutcome::NotStored, None);
+                    inner.abort(&*store, &reservation).await;
+                    return Ok(res.map_into_boxed_body());
+                }
+
+                let (http_request, res) = res.into_parts();
+                let (res, body) = res.into_parts();
+                let body = match to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let err = Into::<Box<dyn std::error::Error>>::into(err);
+                        log::warn!("failed to buffer response for idempotency key {key}: {err}");
+
+                        finish(&http_request, IdempotencyOutcome::NotStored, None);
+                        inner.abort(&*store, &reservation).await;
+                        return Err(ErrorInternalServerError(err));
+                    }
+                };
+
+                let request_id = inner.request_id_header.as_ref().and_then(|name| {
+                    let value = http_request.headers().get(name)?;
+                    value.to_str().ok().map(str::to_owned)
+                });
+                let key_header = http_request
+                    .extensions()
+                    .get::<KeyHeader>()
+                    .map(|header| header.name().to_string());
+                let route = http_request
+                    .match_pattern()
+                    .unwrap_or_else(|| http_request.path().to_owned());
+
+                let element = CacheElement::capture(
+                    stored.to_owned(),
+                    &res,
+                    body.clone(),
+                    ttl,
+                    &inner.header_filter,
+                    inner.clock.now(),
+                )
+                .with_request_id(request_id)
+                .with_fingerprint(fingerprint)
+                .with_key_header(key_header)
+                .with_route(http_request.method(), route)
+                .with_metadata(metadata.take());
+                let element = match &inner.compression {
+                    Some(compression) => element.compress(compression),
+                    None => element,
+                };
+                let event = inner
+                    .event(&http_request, Some(key), res.status())
+                    .map(|event| event.with_metadata(element.metadata().clone()));
+                finish(
+                    &http_request,
+                    IdempotencyOutcome::Stored,
+                    Some(element.created_at()),
+                );
 
-        let token = Uuid::try_from(token.to_str().unwrap());
+                inner.commit(&store, reservation, element, event).await;
+                #[cfg(feature = "otel")]
+                otel::record(Some(key), otel::Outcome::Stored);
 
-        //token is not a valid Uuid token
-        if let Err(_) = token {
-            let (http_request, _payload) = req.into_parts();
-            return Box::pin(async {
                 Ok(ServiceResponse::new(
                     http_request,
-                    //syntax magic to force the compiler to transform into `HttpResponse`
-                    Into::<HttpResponse>::into(IdempotencyError::Malformed).map_into_right_body(),
+                    res.set_body(body).map_into_boxed_body(),
                 ))
-            });
-        }
+            };
 
-        let fut = self.service.call(req);
+            let mut res = handled.await?;
+            if let Some(key) = echo {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(HEADER_KEY), key);
+            }
+            Ok(res)
+        })
+    }
+}
 
-        Box::pin(async move {
-            let res = fut.await?;
+impl From<IdempotencyError> for HttpResponse {
+    fn from(error: IdempotencyError) -> Self {
+        error.error_response()
+    }
+}
 
-            println!("Hi from response");
+// `value` as it has to appear between the quotes of a JSON string.
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or_default()
+        .to_owned()
+}
 
-            Ok(res.map_into_left_body())
-        })
-    }
+// `Retry-After` only carries whole seconds, so round up rather than invite a retry that is too early.
+fn retry_after(remaining: Duration) -> u64 {
+    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
 }
 
-impl Into<HttpResponse> for IdempotencyError {
-    fn into(self) -> HttpResponse {
-        let status = match self {
-            Self::Missing | Self::Malformed => StatusCode::BAD_REQUEST,
-            Self::AlreadyExists => StatusCode::CONFLICT,
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        http::StatusCode,
+        rt::time::sleep,
+        test, web, App, HttpResponse,
+    };
+
+    use crate::{
+        test::{request, TestStore},
+        Idempotency,
+    };
+
+    async fn app(
+        store: &TestStore,
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>
+    {
+        let idempotency = Idempotency::builder()
+            .store(store.clone())
+            .clock(store.clock().clone())
+            .build()
+            .unwrap();
+
+        test::init_service(App::new().wrap(idempotency).route(
+            "/orders",
+            web::post().to(|| async {
+                sleep(Duration::from_millis(50)).await;
+                HttpResponse::Created().body("order placed")
+            }),
+        ))
+        .await
+    }
+
+    #[actix_web::test]
+    async fn missing_key_is_not_stored() {
+        let store = TestStore::new();
+        let app = app(&store).await;
+
+        let res =
+            test::call_service(&app, test::TestRequest::post().uri("/orders").to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(store.inserts(), 0);
+        assert!(store.entries().is_empty());
+
+        let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn malformed_key_is_not_stored() {
+        let store = TestStore::new();
+        let app = app(&store).await;
+
+        let res = test::call_service(&app, request("order 1").uri("/orders").to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(store.inserts(), 0);
+        assert!(store.entries().is_empty());
+        assert!(!store.is_reserved("order 1"));
+
+        let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn conflict_is_not_stored() {
+        let store = TestStore::new();
+        let app = app(&store).await;
+        let order = || request("order-1").uri("/orders").to_request();
+
+        let first = test::call_service(&app, order());
+        let duplicate = async {
+            // arrives once the first one holds the key
+            sleep(Duration::from_millis(10)).await;
+            let res = test::call_service(&app, order()).await;
+            // neither stored nor taking the key from the first one
+            assert_eq!(store.inserts(), 0);
+            assert!(store.is_reserved("order-1"));
+            res
         };
+        let (first, duplicate) = futures_util::join!(first, duplicate);
 
-        HttpResponse::build(status).json(&IdempotencyErrorWrapper { error: self })
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert_eq!(store.inserts(), 1);
+        assert_eq!(
+            store.entry("order-1").unwrap().status(),
+            StatusCode::CREATED
+        );
     }
 }
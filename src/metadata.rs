@@ -0,0 +1,71 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{dev::Payload, Error, FromRequest, HttpMessage, HttpRequest};
+use serde_json::Value;
+
+/// Metadata to store along with the response to the current request, such as the id of the
+/// entity it created or who performed it.
+///
+/// The middleware puts a handle into the extensions of every request it stores the response of,
+/// and handlers extract it to fill it in. It ends up in [`CacheElement::metadata`] and is passed
+/// to [`IdempotencyEvents`](crate::IdempotencyEvents) both when the response is stored and when
+/// it is replayed.
+///
+/// ```
+/// use actix_web::HttpResponse;
+/// use actix_web_idempotency::IdempotencyMetadata;
+///
+/// async fn create_order(metadata: IdempotencyMetadata) -> HttpResponse {
+///     let order = 123; // create the order
+///     metadata.insert("order_id", order);
+///
+///     HttpResponse::Created().finish()
+/// }
+/// ```
+///
+/// Requests the middleware does not store responses for get a handle that goes nowhere. Metadata
+/// is stored in plain text, even if [`encryption`](crate::IdempotencyBuilder::encryption) is on.
+///
+/// [`CacheElement::metadata`]: crate::CacheElement::metadata
+#[derive(Clone, Debug, Default)]
+pub struct IdempotencyMetadata {
+    entries: Rc<RefCell<HashMap<String, Value>>>,
+}
+
+impl IdempotencyMetadata {
+    /// Sets `name` to `value`, replacing what was set before.
+    pub fn insert(&self, name: impl Into<String>, value: impl Into<Value>) {
+        self.entries.borrow_mut().insert(name.into(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.entries.borrow().get(name).cloned()
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Value> {
+        self.entries.borrow_mut().remove(name)
+    }
+
+    pub(crate) fn take(&self) -> HashMap<String, Value> {
+        std::mem::take(&mut self.entries.borrow_mut())
+    }
+}
+
+impl FromRequest for IdempotencyMetadata {
+    type Error = Error;
+
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .extensions()
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_default()))
+    }
+}
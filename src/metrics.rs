@@ -0,0 +1,95 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::CircuitState;
+
+/// Hook for exporting counters about what the middleware did.
+///
+/// Every method defaults to doing nothing, so implementors only override what they record.
+pub trait IdempotencyMetrics: Send + Sync {
+    /// A stored response was replayed.
+    fn record_hit(&self) {}
+
+    /// No response was stored yet, so the request went through to the handler.
+    fn record_miss(&self) {}
+
+    /// The request was turned away because its key was in use by another request, or had
+    /// already been used.
+    fn record_conflict(&self) {}
+
+    /// The handler ran to completion for `key` although a response had already been stored
+    /// for it, i.e. the request was executed twice.
+    ///
+    /// This is the one outcome the middleware exists to prevent, so it is worth alerting on.
+    fn record_double_execution(&self, _key: &str) {}
+
+    /// The response stored for `key` was requested for the `count`th time since it was stored.
+    ///
+    /// Only reported when a [`replay_limit`](crate::IdempotencyBuilder::replay_limit) is
+    /// configured. Counts beyond the limit belong to requests that were turned away.
+    fn record_replay_count(&self, _key: &str, _count: u64) {}
+
+    /// The store failed while handling `key`, so the request went through to the handler
+    /// without idempotency, see [`FailureMode::Open`](crate::FailureMode::Open).
+    fn record_fail_open(&self, _key: &str) {}
+
+    /// A garbage collection sweep removed `count` entries from the store.
+    fn record_reclaimed(&self, _count: usize) {}
+
+    /// A [`CircuitBreakerStore`](crate::CircuitBreakerStore) changed to `state`.
+    fn record_circuit_state(&self, _state: CircuitState) {}
+
+    /// A call to the store took `elapsed`, whether it succeeded or not. `operation` names the
+    /// [`IdempotencyStore`](crate::IdempotencyStore) method, e.g. `reserve`.
+    fn record_store_latency(&self, _operation: &'static str, _elapsed: Duration) {}
+
+    /// A response body of `bytes` was handed to the store, as stored, i.e. after compression
+    /// and encryption.
+    fn record_body_size(&self, _bytes: usize) {}
+}
+
+impl<T: IdempotencyMetrics + ?Sized> IdempotencyMetrics for Arc<T> {
+    fn record_hit(&self) {
+        (**self).record_hit()
+    }
+
+    fn record_miss(&self) {
+        (**self).record_miss()
+    }
+
+    fn record_conflict(&self) {
+        (**self).record_conflict()
+    }
+
+    fn record_double_execution(&self, key: &str) {
+        (**self).record_double_execution(key)
+    }
+
+    fn record_replay_count(&self, key: &str, count: u64) {
+        (**self).record_replay_count(key, count)
+    }
+
+    fn record_fail_open(&self, key: &str) {
+        (**self).record_fail_open(key)
+    }
+
+    fn record_reclaimed(&self, count: usize) {
+        (**self).record_reclaimed(count)
+    }
+
+    fn record_circuit_state(&self, state: CircuitState) {
+        (**self).record_circuit_state(state)
+    }
+
+    fn record_store_latency(&self, operation: &'static str, elapsed: Duration) {
+        (**self).record_store_latency(operation, elapsed)
+    }
+
+    fn record_body_size(&self, bytes: usize) {
+        (**self).record_body_size(bytes)
+    }
+}
+
+/// Metrics hook that discards everything. Used when none is configured.
+pub struct NoopMetrics;
+
+impl IdempotencyMetrics for NoopMetrics {}
@@ -0,0 +1,42 @@
+use opentelemetry::{trace::get_active_span, KeyValue};
+use sha2::{Digest, Sha256};
+
+/// What the middleware did with a request, as recorded on its span.
+#[derive(Clone, Copy)]
+pub(crate) enum Outcome {
+    /// The handler ran and its response was stored.
+    Stored,
+    Replayed,
+    /// The key is in use by another request, or was already used.
+    Conflict,
+    Rejected,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stored => "stored",
+            Self::Replayed => "replayed",
+            Self::Conflict => "conflict",
+            Self::Rejected => "rejected",
+        }
+    }
+}
+
+/// Sets `idempotency.result` and `idempotency.key_hash` on the active span and adds an event
+/// named after the outcome, e.g. `idempotency.replayed`.
+///
+/// Keys are hashed with SHA-256, as they are credentials of sorts that should not end up in
+/// traces.
+pub(crate) fn record(key: Option<&str>, outcome: Outcome) {
+    get_active_span(|span| {
+        let mut attributes = vec![KeyValue::new("idempotency.result", outcome.as_str())];
+        if let Some(key) = key {
+            let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+            attributes.push(KeyValue::new("idempotency.key_hash", hash));
+        }
+
+        span.set_attributes(attributes.iter().cloned());
+        span.add_event(format!("idempotency.{}", outcome.as_str()), attributes);
+    });
+}
@@ -0,0 +1,56 @@
+/// Matches request paths, to restrict which requests the middleware handles.
+///
+/// See [`IdempotencyBuilder::include`](crate::IdempotencyBuilder::include) and
+/// [`IdempotencyBuilder::exclude`](crate::IdempotencyBuilder::exclude).
+#[derive(Clone, Debug)]
+pub struct PathPattern(Kind);
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Exact(String),
+    Prefix(String),
+    Glob(String),
+}
+
+impl PathPattern {
+    /// Matches `path` only.
+    pub fn exact(path: impl Into<String>) -> Self {
+        Self(Kind::Exact(path.into()))
+    }
+
+    /// Matches `prefix` and every path below it, segment by segment: `/metrics` matches
+    /// `/metrics` and `/metrics/jobs`, but not `/metricsd`.
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self(Kind::Prefix(prefix.into()))
+    }
+
+    /// Matches paths against a glob, where `?` stands for any single character and `*` for any
+    /// number of them within a segment, while `**` spans segments: `/internal/**` matches every
+    /// path below `/internal/`, `/orders/*/refunds` the refunds of any one order.
+    pub fn glob(pattern: impl Into<String>) -> Self {
+        Self(Kind::Glob(pattern.into()))
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        match &self.0 {
+            Kind::Exact(exact) => path == exact,
+            Kind::Prefix(prefix) => path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+            }),
+            Kind::Glob(pattern) => glob(pattern.as_bytes(), path.as_bytes()),
+        }
+    }
+}
+
+fn glob(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob(rest, &path[i..])),
+        // `*` may swallow anything up to, but not including, the next `/`
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob(rest, &path[i..])),
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && glob(rest, tail)),
+        [c, rest @ ..] => matches!(path, [p, tail @ ..] if p == c && glob(rest, tail)),
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the requests this process is executing while holding a reservation.
+#[derive(Default)]
+pub(crate) struct Pending {
+    count: AtomicUsize,
+}
+
+/// One pending request, which stops counting when dropped.
+pub(crate) struct PendingGuard<'a> {
+    count: &'a AtomicUsize,
+}
+
+impl Pending {
+    /// Counts another pending request, unless `limit` of them are pending already.
+    pub(crate) fn acquire(&self, limit: Option<usize>) -> Option<PendingGuard<'_>> {
+        let previous = self.count.fetch_add(1, Ordering::AcqRel);
+
+        if limit.is_some_and(|limit| previous >= limit) {
+            self.count.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+
+        Some(PendingGuard { count: &self.count })
+    }
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use crate::MissingKey;
+
+/// Settings overriding the middleware's for a single route or scope, so that one middleware
+/// can serve routes with different needs.
+///
+/// Policies are attached to route patterns on the builder with
+/// [`route_policy`](crate::IdempotencyBuilder::route_policy), which works wherever the middleware
+/// is wrapped:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_idempotency::{Idempotency, IdempotencyPolicy};
+///
+/// let idempotency = Idempotency::builder()
+///     .route_policy(
+///         "/payments/{id}",
+///         IdempotencyPolicy::new().ttl(Duration::from_secs(7 * 24 * 60 * 60)),
+///     )
+///     .build()
+///     .unwrap();
+///
+/// let app = App::new()
+///     .wrap(idempotency)
+///     .route("/payments/{id}", web::post().to(HttpResponse::Ok));
+/// ```
+///
+/// Or as app data of a scope or resource, which the middleware only sees if it is wrapped
+/// around that scope or resource, or one inside it, as a middleware wrapping the whole `App`
+/// runs before routing. App data takes precedence.
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_idempotency::{Idempotency, IdempotencyPolicy, MissingKey};
+///
+/// let idempotency = Idempotency::new();
+///
+/// let app = App::new()
+///     .service(
+///         web::scope("/drafts")
+///             .app_data(IdempotencyPolicy::new().missing_key(MissingKey::PassThrough))
+///             .wrap(idempotency.clone())
+///             .route("", web::post().to(HttpResponse::Ok)),
+///     )
+///     .service(
+///         web::scope("/orders")
+///             .wrap(idempotency)
+///             .route("", web::post().to(HttpResponse::Ok)),
+///     );
+/// ```
+///
+/// Everything a policy does not set is left to the middleware's configuration.
+#[derive(Clone, Debug)]
+pub struct IdempotencyPolicy {
+    pub(crate) enabled: bool,
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) missing_key: Option<MissingKey>,
+    pub(crate) store_responses: bool,
+}
+
+impl Default for IdempotencyPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: None,
+            missing_key: None,
+            store_responses: true,
+        }
+    }
+}
+
+impl IdempotencyPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the middleware handles requests to the route at all. Those it does not go
+    /// straight to the handler, without needing an idempotency key.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// How long responses of the route stay replayable, in place of the middleware's
+    /// [`ttl`](crate::IdempotencyBuilder::ttl). A TTL requested through the
+    /// [`ttl_header`](crate::IdempotencyBuilder::ttl_header) still takes precedence.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// What happens to requests to the route that carry no key, in place of the middleware's
+    /// [`missing_key`](crate::IdempotencyBuilder::missing_key).
+    pub fn missing_key(mut self, missing: MissingKey) -> Self {
+        self.missing_key = Some(missing);
+        self
+    }
+
+    /// Whether responses of the route are stored for replays. If not, requests with the same
+    /// key are still kept from executing concurrently, as if every response opted out through
+    /// [`NoStore`](crate::NoStore).
+    pub fn store_responses(mut self, store: bool) -> Self {
+        self.store_responses = store;
+        self
+    }
+}
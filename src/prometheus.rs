@@ -0,0 +1,264 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{http::header::ContentType, web, HttpResponse};
+
+use crate::{CircuitState, IdempotencyMetrics};
+
+// Upper bounds of the buckets store calls are sorted into, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+// Upper bounds of the buckets stored bodies are sorted into, in bytes.
+const SIZE_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+];
+
+const CIRCUIT_STATES: [(CircuitState, &str); 3] = [
+    (CircuitState::Closed, "closed"),
+    (CircuitState::Open, "open"),
+    (CircuitState::HalfOpen, "half_open"),
+];
+
+/// [`IdempotencyMetrics`] keeping counters and histograms in memory, to be scraped by Prometheus
+/// through [`idempotency_metrics_handler`].
+///
+/// Share it between the middleware and the handler through an [`Arc`](std::sync::Arc):
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use actix_web::{web, App};
+/// use actix_web_idempotency::{idempotency_metrics_handler, Idempotency, PrometheusMetrics};
+///
+/// let metrics = Arc::new(PrometheusMetrics::new());
+/// let idempotency = Idempotency::builder()
+///     .metrics(Arc::clone(&metrics))
+///     .build()
+///     .unwrap();
+///
+/// let app = App::new()
+///     .app_data(web::Data::from(metrics))
+///     .route("/metrics", web::get().to(idempotency_metrics_handler))
+///     .service(web::scope("/api").wrap(idempotency));
+/// ```
+///
+/// Requires the `prometheus` feature.
+pub struct PrometheusMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    conflicts: AtomicU64,
+    double_executions: AtomicU64,
+    fail_opens: AtomicU64,
+    evictions: AtomicU64,
+    circuit_state: AtomicU8,
+    store_latency: Mutex<Vec<(&'static str, Histogram)>>,
+    body_size: Mutex<Histogram>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    // observations per bucket, not yet summed up
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    // `labels` come first in every sample, separated from `le` by a comma
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {cumulative}");
+        }
+
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {}", self.count);
+
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{labels}}}"),
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            conflicts: AtomicU64::new(0),
+            double_executions: AtomicU64::new(0),
+            fail_opens: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            circuit_state: AtomicU8::new(0),
+            store_latency: Mutex::new(Vec::new()),
+            body_size: Mutex::new(Histogram::new(SIZE_BUCKETS)),
+        }
+    }
+
+    /// Everything recorded so far, in the Prometheus text exposition format, e.g. to append to
+    /// the output of another registry.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "idempotency_hits_total",
+                "Stored responses that were replayed.",
+                &self.hits,
+            ),
+            (
+                "idempotency_misses_total",
+                "Requests that went through to the handler as no response was stored yet.",
+                &self.misses,
+            ),
+            (
+                "idempotency_conflicts_total",
+                "Requests turned away as their key was in use or already used.",
+                &self.conflicts,
+            ),
+            (
+                "idempotency_double_executions_total",
+                "Requests executed although a response was already stored for their key.",
+                &self.double_executions,
+            ),
+            (
+                "idempotency_fail_open_total",
+                "Requests that went through without idempotency as the store failed.",
+                &self.fail_opens,
+            ),
+            (
+                "idempotency_evictions_total",
+                "Expired entries removed from the store by garbage collection.",
+                &self.evictions,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let name = "idempotency_circuit_state";
+        let current = self.circuit_state.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "# HELP {name} State of the circuit breaker in front of the store."
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (index, (_, state)) in CIRCUIT_STATES.iter().enumerate() {
+            let value = u8::from(usize::from(current) == index);
+            let _ = writeln!(out, "{name}{{state=\"{state}\"}} {value}");
+        }
+
+        let name = "idempotency_store_latency_seconds";
+        let _ = writeln!(out, "# HELP {name} Duration of calls to the store.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (operation, histogram) in self.store_latency.lock().unwrap().iter() {
+            histogram.render(&mut out, name, &format!("operation=\"{operation}\","));
+        }
+
+        let name = "idempotency_body_size_bytes";
+        let _ = writeln!(out, "# HELP {name} Size of response bodies as stored.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        self.body_size.lock().unwrap().render(&mut out, name, "");
+
+        out
+    }
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdempotencyMetrics for PrometheusMetrics {
+    fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_conflict(&self) {
+        self.conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_double_execution(&self, _key: &str) {
+        self.double_executions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_fail_open(&self, _key: &str) {
+        self.fail_opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_reclaimed(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn record_circuit_state(&self, state: CircuitState) {
+        if let Some(index) = CIRCUIT_STATES.iter().position(|(known, _)| *known == state) {
+            self.circuit_state.store(index as u8, Ordering::Relaxed);
+        }
+    }
+
+    fn record_store_latency(&self, operation: &'static str, elapsed: Duration) {
+        let mut histograms = self.store_latency.lock().unwrap();
+        let index = match histograms.iter().position(|(known, _)| *known == operation) {
+            Some(index) => index,
+            None => {
+                histograms.push((operation, Histogram::new(LATENCY_BUCKETS)));
+                histograms.len() - 1
+            }
+        };
+
+        histograms[index].1.observe(elapsed.as_secs_f64());
+    }
+
+    fn record_body_size(&self, bytes: usize) {
+        self.body_size.lock().unwrap().observe(bytes as f64);
+    }
+}
+
+/// Handler serving what a [`PrometheusMetrics`] registered as app data recorded, in the
+/// Prometheus text exposition format.
+///
+/// Requires the `prometheus` feature.
+pub async fn idempotency_metrics_handler(metrics: web::Data<PrometheusMetrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType(
+            "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+        ))
+        .body(metrics.render())
+}
@@ -0,0 +1,71 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+
+/// Counts how often each stored response was replayed by this process.
+///
+/// Counts belong to one particular response, so they start over once the key's entry expired
+/// and a new one was stored.
+#[derive(Default)]
+pub(crate) struct ReplayCounter {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    counts: HashMap<String, Count>,
+    // size of `counts` after the last sweep, to sweep whenever it doubled since
+    swept: usize,
+}
+
+struct Count {
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    replays: u64,
+}
+
+impl ReplayCounter {
+    /// Counts a replay of the response stored for `key` at `created_at`, returning how many
+    /// there were so far including this one.
+    pub(crate) fn increment(
+        &self,
+        key: &str,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> u64 {
+        let mut state = self.state.lock().unwrap();
+
+        if state.counts.len() > 2 * state.swept {
+            state.sweep(now);
+        }
+
+        let count = state.counts.entry(key.to_owned()).or_insert(Count {
+            created_at,
+            expires_at,
+            replays: 0,
+        });
+        if count.created_at != created_at {
+            *count = Count {
+                created_at,
+                expires_at,
+                replays: 0,
+            };
+        }
+
+        count.replays += 1;
+        count.replays
+    }
+
+    /// Forgets the counts of responses that expired.
+    pub(crate) fn purge_expired(&self, now: DateTime<Utc>) {
+        self.state.lock().unwrap().sweep(now);
+    }
+}
+
+impl State {
+    fn sweep(&mut self, now: DateTime<Utc>) {
+        self.counts.retain(|_, count| count.expires_at > now);
+        self.swept = self.counts.len();
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{IdempotencyEvents, IdempotencyMetrics};
+
+use super::{
+    BodyStream, CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture,
+    StoreStats,
+};
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+
+const DEFAULT_OPEN_FOR: Duration = Duration::from_secs(10);
+
+const DEFAULT_PROBES: usize = 1;
+
+/// State of a [`CircuitBreakerStore`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Operations reach the store.
+    Closed,
+    /// The store kept failing, so operations fail right away with [`StoreError::CircuitOpen`].
+    Open,
+    /// The store is given another try by a few probing operations, while the others still fail
+    /// right away.
+    HalfOpen,
+}
+
+/// Store that stops calling another store while it keeps failing, instead of adding load to a
+/// backend that is struggling already.
+///
+/// After [`failure_threshold`](Self::failure_threshold) consecutive failures the circuit opens
+/// and operations fail with [`StoreError::CircuitOpen`] without reaching the store, which the
+/// middleware's [`failure_mode`](crate::IdempotencyBuilder::failure_mode) then handles. Once
+/// [`open_for`](Self::open_for) has passed, the circuit turns half-open and lets
+/// [`probes`](Self::probes) operations through: it closes again if they succeed and opens again
+/// if one fails.
+///
+/// Put it around a [`RetryStore`](crate::RetryStore), so that an operation only counts as a
+/// failure once its retries are exhausted, and retries stop as soon as the circuit opens.
+/// Unsupported operations do not count as failures.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_web_idempotency::{CircuitBreakerStore, IdempotencyStore, RetryStore};
+///
+/// fn resilient(shared: impl IdempotencyStore) -> impl IdempotencyStore {
+///     CircuitBreakerStore::new(RetryStore::new(shared))
+///         .failure_threshold(3)
+///         .open_for(Duration::from_secs(30))
+/// }
+/// ```
+pub struct CircuitBreakerStore<S> {
+    inner: S,
+    failure_threshold: u32,
+    open_for: Duration,
+    probes: usize,
+    metrics: Option<Arc<dyn IdempotencyMetrics>>,
+    events: Option<Arc<dyn IdempotencyEvents>>,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    state: Option<Opened>,
+    // consecutive failures while closed
+    failures: u32,
+}
+
+// What an open circuit is up to.
+enum Opened {
+    Open { until: Instant },
+    HalfOpen { probing: usize },
+}
+
+// Lets a single operation through the circuit, and returns the probe slot it took if dropped
+// before settling, e.g. because the request was cancelled.
+struct Permit<'a, S> {
+    breaker: &'a CircuitBreakerStore<S>,
+    probe: bool,
+}
+
+impl<S> Drop for Permit<'_, S> {
+    fn drop(&mut self) {
+        if self.probe {
+            if let Some(Opened::HalfOpen { probing }) =
+                &mut self.breaker.circuit.lock().unwrap().state
+            {
+                *probing = probing.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl<S: IdempotencyStore> CircuitBreakerStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            open_for: DEFAULT_OPEN_FOR,
+            probes: DEFAULT_PROBES,
+            metrics: None,
+            events: None,
+            circuit: Mutex::default(),
+        }
+    }
+
+    /// How many operations in a row have to fail for the circuit to open. Defaults to 5, and is
+    /// raised to 1 if 0.
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// How long the circuit stays open before probing the store again. Defaults to 10 seconds.
+    pub fn open_for(mut self, duration: Duration) -> Self {
+        self.open_for = duration;
+        self
+    }
+
+    /// How many operations may probe the store at once while the circuit is half-open. Defaults
+    /// to 1, and is raised to 1 if 0.
+    pub fn probes(mut self, probes: usize) -> Self {
+        self.probes = probes.max(1);
+        self
+    }
+
+    /// Hook told about every state change through
+    /// [`IdempotencyMetrics::record_circuit_state`]. Pass an `Arc` to share it with the
+    /// middleware.
+    pub fn metrics(mut self, metrics: impl IdempotencyMetrics + 'static) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Hook told about every state change through
+    /// [`IdempotencyEvents::on_circuit_change`]. Pass an `Arc` to share it with the middleware.
+    pub fn events(mut self, events: impl IdempotencyEvents + 'static) -> Self {
+        self.events = Some(Arc::new(events));
+        self
+    }
+
+    /// The store operations are forwarded to.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.circuit.lock().unwrap().state {
+            None => CircuitState::Closed,
+            Some(Opened::Open { until }) if until <= Instant::now() => CircuitState::HalfOpen,
+            Some(Opened::Open { .. }) => CircuitState::Open,
+            Some(Opened::HalfOpen { .. }) => CircuitState::HalfOpen,
+        }
+    }
+
+    fn run<'a, T: Send + 'a>(
+        &'a self,
+        op: impl FnOnce() -> StoreFuture<'a, T> + Send + 'a,
+    ) -> StoreFuture<'a, T> {
+        Box::pin(async move {
+            let permit = self.admit()?;
+            let result = op().await;
+
+            let failed = matches!(
+                result,
+                Err(StoreError::Backend(_) | StoreError::Timeout | StoreError::CircuitOpen)
+            );
+            self.settle(permit, failed);
+
+            result
+        })
+    }
+
+    fn admit(&self) -> Result<Permit<'_, S>, StoreError> {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        let probe = match &mut circuit.state {
+            None => false,
+            Some(Opened::Open { until }) if *until > Instant::now() => {
+                return Err(StoreError::CircuitOpen);
+            }
+            Some(state @ Opened::Open { .. }) => {
+                *state = Opened::HalfOpen { probing: 1 };
+                drop(circuit);
+                self.report(CircuitState::Open, CircuitState::HalfOpen);
+                true
+            }
+            Some(Opened::HalfOpen { probing }) if *probing < self.probes => {
+                *probing += 1;
+                true
+            }
+            Some(Opened::HalfOpen { .. }) => return Err(StoreError::CircuitOpen),
+        };
+
+        Ok(Permit {
+            breaker: self,
+            probe,
+        })
+    }
+
+    fn settle(&self, mut permit: Permit<'_, S>, failed: bool) {
+        let probe = std::mem::replace(&mut permit.probe, false);
+        let mut circuit = self.circuit.lock().unwrap();
+
+        let change = match (&circuit.state, failed) {
+            (None, false) => {
+                circuit.failures = 0;
+                None
+            }
+            (None, true) => {
+                circuit.failures += 1;
+                (circuit.failures >= self.failure_threshold).then_some(CircuitState::Closed)
+            }
+            // only probes get to decide, operations let through before the circuit opened do not
+            (Some(Opened::HalfOpen { .. }), true) if probe => Some(CircuitState::HalfOpen),
+            (Some(Opened::HalfOpen { .. }), false) if probe => {
+                *circuit = Circuit::default();
+                drop(circuit);
+                self.report(CircuitState::HalfOpen, CircuitState::Closed);
+                return;
+            }
+            _ => None,
+        };
+
+        if let Some(from) = change {
+            circuit.state = Some(Opened::Open {
+                until: Instant::now() + self.open_for,
+            });
+            circuit.failures = 0;
+            drop(circuit);
+            self.report(from, CircuitState::Open);
+        }
+    }
+
+    fn report(&self, from: CircuitState, to: CircuitState) {
+        log::warn!("idempotency store circuit changed from {from:?} to {to:?}");
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record_circuit_state(to);
+        }
+        if let Some(events) = &self.events {
+            events.on_circuit_change(from, to);
+        }
+    }
+}
+
+impl<S: IdempotencyStore> IdempotencyStore for CircuitBreakerStore<S> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        self.run(move || self.inner.get(key))
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        self.run(move || self.inner.get_streamed(key))
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.reserve(reservation))
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.reserve_for(reservation, ttl))
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        self.run(move || self.inner.abort(reservation))
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.commit(reservation, element))
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.remove(key))
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.len())
+    }
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        self.run(move || self.inner.is_empty())
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.clear())
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.purge_expired())
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.migrate())
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.invalidate(invalidation))
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.maintenance_lock(duration))
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.maintenance_unlock())
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        self.run(move || self.inner.maintenance_remaining())
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        self.run(move || self.inner.stats())
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.health())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{future, FutureExt};
+
+    use super::*;
+    use crate::store::tests::{Faulty, SLOW};
+
+    const OPEN_FOR: Duration = Duration::from_millis(20);
+
+    fn breaker() -> CircuitBreakerStore<Faulty> {
+        CircuitBreakerStore::new(Faulty::default())
+            .failure_threshold(3)
+            .open_for(OPEN_FOR)
+    }
+
+    async fn trip(store: &CircuitBreakerStore<Faulty>) {
+        store.inner().fail(3);
+        for _ in 0..3 {
+            assert!(store.get("key").await.is_err());
+        }
+        assert_eq!(store.state(), CircuitState::Open);
+    }
+
+    #[actix_web::test]
+    async fn opens_after_consecutive_failures() {
+        let store = breaker();
+
+        store.inner().fail(2);
+        assert!(store.get("key").await.is_err());
+        assert!(store.get("key").await.is_err());
+        assert!(store.get("key").await.unwrap().is_none());
+        assert_eq!(store.state(), CircuitState::Closed);
+
+        trip(&store).await;
+        assert!(matches!(
+            store.get("key").await,
+            Err(StoreError::CircuitOpen)
+        ));
+        assert_eq!(store.inner().calls(), 6);
+    }
+
+    #[actix_web::test]
+    async fn half_open_circuit_lets_only_probes_through() {
+        let store = breaker().probes(2);
+        trip(&store).await;
+        actix_web::rt::time::sleep(OPEN_FOR).await;
+        assert_eq!(store.state(), CircuitState::HalfOpen);
+
+        store.inner().slow_down(2);
+        let (first, second, third) =
+            future::join3(store.get("key"), store.get("key"), store.get("key")).await;
+        assert!(first.unwrap().is_none());
+        assert!(second.unwrap().is_none());
+        assert!(matches!(third, Err(StoreError::CircuitOpen)));
+        assert_eq!(store.inner().calls(), 5);
+
+        assert_eq!(store.state(), CircuitState::Closed);
+        assert!(store.get("key").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn dropped_probe_frees_its_slot() {
+        let store = breaker();
+        trip(&store).await;
+        actix_web::rt::time::sleep(OPEN_FOR).await;
+
+        store.inner().slow_down(1);
+        assert!(store.get("key").now_or_never().is_none());
+        assert_eq!(store.state(), CircuitState::HalfOpen);
+
+        assert!(store.get("key").await.unwrap().is_none());
+        assert_eq!(store.state(), CircuitState::Closed);
+    }
+
+    #[actix_web::test]
+    async fn failed_probe_opens_the_circuit_again() {
+        let store = breaker();
+        trip(&store).await;
+        actix_web::rt::time::sleep(OPEN_FOR).await;
+
+        store.inner().fail(1);
+        assert!(matches!(
+            store.get("key").await,
+            Err(StoreError::Backend(_))
+        ));
+        assert_eq!(store.state(), CircuitState::Open);
+        assert!(matches!(
+            store.get("key").await,
+            Err(StoreError::CircuitOpen)
+        ));
+        assert_eq!(store.inner().calls(), 4);
+    }
+
+    #[actix_web::test]
+    async fn operations_admitted_before_opening_do_not_close_it() {
+        let store = breaker().open_for(Duration::from_secs(60));
+
+        store.inner().slow_down(1);
+        let slow = store.get("key");
+        let trips = async {
+            // let the slow operation through while the circuit is still closed
+            actix_web::rt::time::sleep(SLOW / 4).await;
+            trip(&store).await;
+        };
+        let (slow, ()) = future::join(slow, trips).await;
+
+        assert!(slow.unwrap().is_none());
+        assert_eq!(store.state(), CircuitState::Open);
+    }
+}
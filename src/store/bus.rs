@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use actix_web::http::Method;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{Invalidation, StoreFuture};
+
+/// What an instance tells the others to drop from their local caches, see [`InvalidationBus`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BusMessage {
+    /// The response stored for a key was removed.
+    Removed(String),
+    /// The responses matching an [`Invalidation`] were removed.
+    Invalidated(Invalidation),
+    /// Every response was removed.
+    Cleared,
+}
+
+// How messages travel, as JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Wire {
+    Removed { key: String },
+    Scope { prefix: String },
+    Route { method: String, path: String },
+    OlderThan { at: DateTime<Utc> },
+    Cleared,
+}
+
+impl BusMessage {
+    /// The message as published, for buses to send.
+    pub fn encode(&self) -> Vec<u8> {
+        let wire = match self {
+            Self::Removed(key) => Wire::Removed { key: key.clone() },
+            Self::Invalidated(Invalidation::Scope(prefix)) => Wire::Scope {
+                prefix: prefix.clone(),
+            },
+            Self::Invalidated(Invalidation::Route { method, path }) => Wire::Route {
+                method: method.to_string(),
+                path: path.clone(),
+            },
+            Self::Invalidated(Invalidation::OlderThan(at)) => Wire::OlderThan { at: *at },
+            Self::Cleared => Wire::Cleared,
+        };
+
+        serde_json::to_vec(&wire).unwrap_or_default()
+    }
+
+    /// A message [`encode`](Self::encode)d by any instance, or `None` if it is not one, e.g.
+    /// because it was published by a newer version of this crate.
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        let message = match serde_json::from_slice(raw).ok()? {
+            Wire::Removed { key } => Self::Removed(key),
+            Wire::Scope { prefix } => Self::Invalidated(Invalidation::Scope(prefix)),
+            Wire::Route { method, path } => Self::Invalidated(Invalidation::Route {
+                method: Method::from_bytes(method.as_bytes()).ok()?,
+                path,
+            }),
+            Wire::OlderThan { at } => Self::Invalidated(Invalidation::OlderThan(at)),
+            Wire::Cleared => Self::Cleared,
+        };
+
+        Some(message)
+    }
+}
+
+/// Called with every [`BusMessage`] an [`InvalidationBus`] receives.
+pub type BusHandler = Arc<dyn Fn(BusMessage) + Send + Sync>;
+
+/// Channel through which the instances sharing a store tell each other about responses they
+/// removed, so that a [`TieredStore`](crate::TieredStore) on every instance drops its local
+/// copies right away instead of replaying them until its local TTL passes.
+///
+/// Messages are best effort: one that is lost leaves local copies around for no longer than
+/// without a bus. See [`RedisInvalidationBus`](crate::RedisInvalidationBus) for one backed by
+/// Redis pub/sub.
+pub trait InvalidationBus: Send + Sync {
+    /// Sends `message` to every subscribed instance, this one included.
+    fn publish(&self, message: BusMessage) -> StoreFuture<'_, ()>;
+
+    /// Starts passing every message published by any instance to `handler`, for as long as the
+    /// process runs.
+    fn subscribe(&self, handler: BusHandler) -> StoreFuture<'_, ()>;
+}
@@ -0,0 +1,562 @@
+use std::{collections::HashMap, time::Duration};
+
+use aws_sdk_dynamodb::{
+    error::SdkError,
+    primitives::Blob,
+    types::{AttributeValue, Select},
+    Client,
+};
+use chrono::Utc;
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture, WireFormat,
+};
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Attribute names. `expires_at` holds epoch seconds, which is what DynamoDB's TTL expects.
+const KEY: &str = "pk";
+const STATE: &str = "state";
+const OWNER: &str = "owner";
+const ELEMENT: &str = "element";
+const EXPIRES_AT: &str = "expires_at";
+
+const RESERVED: &str = "reserved";
+const COMPLETE: &str = "complete";
+
+// Partition keys of entries start with `key:`, so that no idempotency key can collide with the
+// item recording a maintenance lock.
+const ENTRY: &str = "key:";
+const MAINTENANCE: &str = "maintenance";
+
+type Item = HashMap<String, AttributeValue>;
+
+/// Store keeping responses in a DynamoDB table.
+///
+/// The table needs a string partition key named `pk`. Enable
+/// [TTL](https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/TTL.html) on the
+/// `expires_at` attribute so expired entries get deleted; until DynamoDB gets around to it they
+/// are ignored.
+///
+/// Keys are reserved with conditional writes, so only one instance executes a given request. A
+/// reservation expires after [`lock_timeout`](Self::lock_timeout) in case its holder dies, and
+/// keeps its token in the `owner` attribute, so that a request outliving its reservation cannot
+/// release or overwrite the one that took over.
+///
+/// Requires the `dynamodb` feature.
+pub struct DynamoDbStore {
+    client: Client,
+    table: String,
+    lock_timeout: Duration,
+    format: WireFormat,
+}
+
+impl DynamoDbStore {
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self {
+            client,
+            table: table.into(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            format: WireFormat::default(),
+        }
+    }
+
+    /// How long a reservation is held before another instance may take the key over. Defaults
+    /// to 30 seconds.
+    ///
+    /// This has to comfortably exceed the slowest handler, otherwise a retry arriving while the
+    /// original request is still running gets executed a second time. A middleware's
+    /// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout) takes its place for the
+    /// reservations it makes.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Encoding of the entries written from now on. Defaults to [`WireFormat::Json`].
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    async fn get_item(&self, pk: String) -> Result<Option<Item>, StoreError> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key(KEY, AttributeValue::S(pk))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(StoreError::backend)?;
+
+        // entries past their deadline may linger until DynamoDB's TTL sweep removes them
+        Ok(output
+            .item
+            .filter(|item| number(item, EXPIRES_AT) > Utc::now().timestamp()))
+    }
+
+    // partition keys of every entry and reservation
+    async fn keys(&self, select: Select) -> Result<(usize, Vec<String>), StoreError> {
+        let mut count = 0;
+        let mut keys = Vec::new();
+        let mut start = None;
+
+        loop {
+            let output = self
+                .client
+                .scan()
+                .table_name(&self.table)
+                .select(select.clone())
+                .filter_expression("begins_with(#pk, :entry)")
+                .expression_attribute_names("#pk", KEY)
+                .expression_attribute_values(":entry", AttributeValue::S(ENTRY.into()))
+                .set_exclusive_start_key(start)
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            count += usize::try_from(output.count).unwrap_or_default();
+            keys.extend(
+                output
+                    .items
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|item| item.get(KEY)?.as_s().ok().cloned()),
+            );
+
+            start = output.last_evaluated_key;
+            if start.is_none() {
+                return Ok((count, keys));
+            }
+        }
+    }
+}
+
+fn pk(key: &str) -> AttributeValue {
+    AttributeValue::S(format!("{ENTRY}{key}"))
+}
+
+fn owner(reservation: &Reservation) -> AttributeValue {
+    AttributeValue::S(reservation.token().to_owned())
+}
+
+fn number(item: &Item, name: &str) -> i64 {
+    item.get(name)
+        .and_then(|value| value.as_n().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
+
+fn is_state(item: &Item, state: &str) -> bool {
+    item.get(STATE)
+        .and_then(|value| value.as_s().ok())
+        .map(String::as_str)
+        == Some(state)
+}
+
+// The stored response of `item` rewritten in `format`, unless it is a reservation or written in
+// that format and the current layout already.
+fn upgrade(item: &Item, format: WireFormat) -> Result<Option<Vec<u8>>, StoreError> {
+    let Some(Ok(raw)) = item.get(ELEMENT).map(AttributeValue::as_b) else {
+        return Ok(None);
+    };
+    if !is_state(item, COMPLETE) {
+        return Ok(None);
+    }
+
+    Ok(format.upgrade(raw.as_ref())?.map(|(_, upgraded)| upgraded))
+}
+
+fn seconds(duration: Duration) -> i64 {
+    i64::try_from(duration.as_secs()).unwrap_or(i64::MAX)
+}
+
+// Whether a request failed only because its condition did not hold.
+fn condition_failed<E, R>(err: &SdkError<E, R>) -> bool
+where
+    E: aws_sdk_dynamodb::error::ProvideErrorMetadata,
+{
+    err.as_service_error()
+        .and_then(|err| err.code())
+        .is_some_and(|code| code == "ConditionalCheckFailedException")
+}
+
+impl IdempotencyStore for DynamoDbStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            let Some(item) = self.get_item(format!("{ENTRY}{key}")).await? else {
+                return Ok(None);
+            };
+
+            if !is_state(&item, COMPLETE) {
+                return Ok(None);
+            }
+
+            let Some(Ok(raw)) = item.get(ELEMENT).map(AttributeValue::as_b) else {
+                return Err(StoreError::backend("entry without a stored response"));
+            };
+
+            let element = CacheElement::decode(raw.as_ref())?;
+            Ok(Some(element).filter(|element| !element.is_expired()))
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.reserve_for(reservation, self.lock_timeout)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let now = Utc::now().timestamp();
+
+            // a retry finds its own reservation if the first attempt got through
+            let result = self
+                .client
+                .put_item()
+                .table_name(&self.table)
+                .item(KEY, pk(reservation.key()))
+                .item(STATE, AttributeValue::S(RESERVED.into()))
+                .item(OWNER, owner(reservation))
+                .item(
+                    EXPIRES_AT,
+                    AttributeValue::N((now + seconds(ttl).max(1)).to_string()),
+                )
+                .condition_expression(
+                    "attribute_not_exists(#pk) OR #expires_at <= :now \
+                     OR (#state = :reserved AND #owner = :owner)",
+                )
+                .expression_attribute_names("#pk", KEY)
+                .expression_attribute_names("#expires_at", EXPIRES_AT)
+                .expression_attribute_names("#state", STATE)
+                .expression_attribute_names("#owner", OWNER)
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                .expression_attribute_values(":reserved", AttributeValue::S(RESERVED.into()))
+                .expression_attribute_values(":owner", owner(reservation))
+                .send()
+                .await;
+
+            match result {
+                Ok(_) => Ok(true),
+                Err(err) if condition_failed(&err) => Ok(false),
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let result = self
+                .client
+                .delete_item()
+                .table_name(&self.table)
+                .key(KEY, pk(reservation.key()))
+                .condition_expression("#state = :reserved AND #owner = :owner")
+                .expression_attribute_names("#state", STATE)
+                .expression_attribute_names("#owner", OWNER)
+                .expression_attribute_values(":reserved", AttributeValue::S(RESERVED.into()))
+                .expression_attribute_values(":owner", owner(reservation))
+                .send()
+                .await;
+
+            match result {
+                Ok(_) => Ok(()),
+                // someone else holds the key by now, which is theirs to release
+                Err(err) if condition_failed(&err) => Ok(()),
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let encoded = element.encode(self.format)?;
+            let now = Utc::now().timestamp();
+
+            // Replaces our own reservation, or nothing at all if it timed out in the meantime. A
+            // stored response or another request's reservation wins.
+            let result = self
+                .client
+                .put_item()
+                .table_name(&self.table)
+                .item(KEY, pk(element.key()))
+                .item(STATE, AttributeValue::S(COMPLETE.into()))
+                .item(ELEMENT, AttributeValue::B(Blob::new(encoded)))
+                .item(
+                    EXPIRES_AT,
+                    AttributeValue::N(element.expires_at().timestamp().to_string()),
+                )
+                .condition_expression(
+                    "attribute_not_exists(#pk) OR #expires_at <= :now \
+                     OR (#state = :reserved AND #owner = :owner)",
+                )
+                .expression_attribute_names("#pk", KEY)
+                .expression_attribute_names("#expires_at", EXPIRES_AT)
+                .expression_attribute_names("#state", STATE)
+                .expression_attribute_names("#owner", OWNER)
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                .expression_attribute_values(":reserved", AttributeValue::S(RESERVED.into()))
+                .expression_attribute_values(":owner", owner(reservation))
+                .send()
+                .await;
+
+            match result {
+                Ok(_) => Ok(true),
+                Err(err) if condition_failed(&err) => Ok(false),
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let output = self
+                .client
+                .delete_item()
+                .table_name(&self.table)
+                .key(KEY, pk(key))
+                .return_values(aws_sdk_dynamodb::types::ReturnValue::AllOld)
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(output.attributes.is_some())
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move { Ok(self.keys(Select::Count).await?.0) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let (_, keys) = self.keys(Select::AllAttributes).await?;
+
+            for key in keys {
+                self.client
+                    .delete_item()
+                    .table_name(&self.table)
+                    .key(KEY, AttributeValue::S(key))
+                    .send()
+                    .await
+                    .map_err(StoreError::backend)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut migrated = 0;
+            let mut start = None;
+
+            loop {
+                let output = self
+                    .client
+                    .scan()
+                    .table_name(&self.table)
+                    .filter_expression("begins_with(#pk, :entry) AND #state = :complete")
+                    .projection_expression("#pk, #state, #element")
+                    .expression_attribute_names("#pk", KEY)
+                    .expression_attribute_names("#state", STATE)
+                    .expression_attribute_names("#element", ELEMENT)
+                    .expression_attribute_values(":entry", AttributeValue::S(ENTRY.into()))
+                    .expression_attribute_values(":complete", AttributeValue::S(COMPLETE.into()))
+                    .set_exclusive_start_key(start)
+                    .send()
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                for item in output.items.unwrap_or_default() {
+                    let (Some(pk), Some(raw)) = (item.get(KEY), item.get(ELEMENT)) else {
+                        continue;
+                    };
+                    let Some(upgraded) = upgrade(&item, self.format)? else {
+                        continue;
+                    };
+
+                    // left alone if it changed in the meantime
+                    let result = self
+                        .client
+                        .update_item()
+                        .table_name(&self.table)
+                        .key(KEY, pk.clone())
+                        .update_expression("SET #element = :upgraded")
+                        .condition_expression("#element = :element")
+                        .expression_attribute_names("#element", ELEMENT)
+                        .expression_attribute_values(":element", raw.clone())
+                        .expression_attribute_values(
+                            ":upgraded",
+                            AttributeValue::B(Blob::new(upgraded)),
+                        )
+                        .send()
+                        .await;
+
+                    match result {
+                        Ok(_) => migrated += 1,
+                        Err(err) if condition_failed(&err) => {}
+                        Err(err) => return Err(StoreError::backend(err)),
+                    }
+                }
+
+                start = output.last_evaluated_key;
+                if start.is_none() {
+                    return Ok(migrated);
+                }
+            }
+        })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let prefix = match &invalidation {
+                Invalidation::Scope(scope) => format!("{ENTRY}{scope}"),
+                _ => ENTRY.to_owned(),
+            };
+
+            let mut invalidated = 0;
+            let mut start = None;
+
+            loop {
+                let output = self
+                    .client
+                    .scan()
+                    .table_name(&self.table)
+                    .filter_expression("begins_with(#pk, :prefix) AND #state = :complete")
+                    .projection_expression("#pk, #element")
+                    .expression_attribute_names("#pk", KEY)
+                    .expression_attribute_names("#state", STATE)
+                    .expression_attribute_names("#element", ELEMENT)
+                    .expression_attribute_values(":prefix", AttributeValue::S(prefix.clone()))
+                    .expression_attribute_values(":complete", AttributeValue::S(COMPLETE.into()))
+                    .set_exclusive_start_key(start)
+                    .send()
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                for item in output.items.unwrap_or_default() {
+                    let (Some(pk), Some(Ok(raw))) =
+                        (item.get(KEY), item.get(ELEMENT).map(AttributeValue::as_b))
+                    else {
+                        continue;
+                    };
+                    if !invalidation.matches(&CacheElement::decode(raw.as_ref())?) {
+                        continue;
+                    }
+
+                    // left alone if it changed in the meantime
+                    let result = self
+                        .client
+                        .delete_item()
+                        .table_name(&self.table)
+                        .key(KEY, pk.clone())
+                        .condition_expression("#element = :element")
+                        .expression_attribute_names("#element", ELEMENT)
+                        .expression_attribute_values(":element", AttributeValue::B(raw.clone()))
+                        .send()
+                        .await;
+
+                    match result {
+                        Ok(_) => invalidated += 1,
+                        Err(err) if condition_failed(&err) => {}
+                        Err(err) => return Err(StoreError::backend(err)),
+                    }
+                }
+
+                start = output.last_evaluated_key;
+                if start.is_none() {
+                    return Ok(invalidated);
+                }
+            }
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let until = Utc::now() + chrono::Duration::from_std(duration).unwrap_or_default();
+
+            self.client
+                .put_item()
+                .table_name(&self.table)
+                .item(KEY, AttributeValue::S(MAINTENANCE.into()))
+                .item(EXPIRES_AT, AttributeValue::N(until.timestamp().to_string()))
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.client
+                .delete_item()
+                .table_name(&self.table)
+                .key(KEY, AttributeValue::S(MAINTENANCE.into()))
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            let Some(item) = self.get_item(MAINTENANCE.to_owned()).await? else {
+                return Ok(None);
+            };
+
+            let remaining = number(&item, EXPIRES_AT) - Utc::now().timestamp();
+            Ok(u64::try_from(remaining)
+                .ok()
+                .filter(|&remaining| remaining > 0)
+                .map(Duration::from_secs))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::wire::tests::{assert_same, element, legacy_entries};
+
+    fn item(state: &str, raw: Vec<u8>) -> Item {
+        HashMap::from([
+            (KEY.to_owned(), AttributeValue::S(format!("{ENTRY}order-1"))),
+            (STATE.to_owned(), AttributeValue::S(state.to_owned())),
+            (ELEMENT.to_owned(), AttributeValue::B(Blob::new(raw))),
+        ])
+    }
+
+    #[test]
+    fn migrate_rewrites_entries_of_older_versions() {
+        for (element, raw) in legacy_entries() {
+            let upgraded = upgrade(&item(COMPLETE, raw), WireFormat::Json)
+                .unwrap()
+                .unwrap();
+            assert_eq!(upgraded[..2], [4, b'j']);
+            assert_same(&CacheElement::decode(&upgraded).unwrap(), &element);
+
+            // and leaves them alone from then on
+            assert!(upgrade(&item(COMPLETE, upgraded), WireFormat::Json)
+                .unwrap()
+                .is_none());
+        }
+
+        let current = element("order-1").encode(WireFormat::Json).unwrap();
+        assert!(upgrade(&item(RESERVED, current), WireFormat::Json)
+            .unwrap()
+            .is_none());
+    }
+}
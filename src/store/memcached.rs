@@ -0,0 +1,504 @@
+use std::time::Duration;
+
+use async_memcached::{AsciiProtocol, Client, Error, MetaProtocol, Status};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture, WireFormat,
+};
+
+const DEFAULT_PREFIX: &str = "idempotency:";
+
+// Entries live below `{prefix}key:`, so that no idempotency key can collide with the maintenance
+// lock kept at `{prefix}maintenance`.
+const ENTRY: &str = "key:";
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Reservations are stored under the same key as the response that later replaces them, so that a
+// single `add` decides which instance gets to execute the request. They hold the token of their
+// reservation.
+const RESERVATION: &str = "reserved:";
+
+// Memcached reads expirations beyond 30 days as a Unix timestamp rather than a TTL.
+const MAX_RELATIVE_TTL: i64 = 30 * 24 * 60 * 60;
+
+/// Store keeping responses in memcached, shared by every instance connected to it.
+///
+/// Keys are reserved with `add`, so only one instance executes a given request. A reservation
+/// expires after [`lock_timeout`](Self::lock_timeout) in case its holder dies, and is replaced
+/// by the completed response through a compare-and-swap. Stored responses expire through
+/// memcached's own TTLs, rounded up to whole seconds.
+///
+/// Memcached limits keys to 250 bytes without spaces, so idempotency keys are stored by their
+/// SHA-256 hash. [`len`](IdempotencyStore::len) and [`clear`](IdempotencyStore::clear) enumerate
+/// keys with `lru_crawler metadump`, which requires memcached 1.4.31 or later, as does
+/// [`invalidate`](IdempotencyStore::invalidate).
+///
+/// All operations go through a single connection, one at a time.
+///
+/// Requires the `memcached` feature.
+pub struct MemcachedStore {
+    client: Mutex<Client>,
+    prefix: String,
+    lock_timeout: Duration,
+    format: WireFormat,
+}
+
+impl MemcachedStore {
+    /// Connects to the memcached server at `dsn`, e.g. `tcp://127.0.0.1:11211`.
+    pub async fn open(dsn: &str) -> Result<Self, StoreError> {
+        let client = Client::new(dsn).await.map_err(StoreError::backend)?;
+
+        Ok(Self::new(client))
+    }
+
+    pub fn new(client: Client) -> Self {
+        Self {
+            client: Mutex::new(client),
+            prefix: DEFAULT_PREFIX.to_owned(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            format: WireFormat::default(),
+        }
+    }
+
+    /// Prefix of every key written by this store. Defaults to `idempotency:`.
+    ///
+    /// It must not contain spaces or characters that need URL-encoding, so that the keys listed
+    /// by `lru_crawler metadump` can be told apart.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// How long a reservation is held before another instance may take the key over. Defaults
+    /// to 30 seconds.
+    ///
+    /// This has to comfortably exceed the slowest handler, otherwise a retry arriving while the
+    /// original request is still running gets executed a second time. A middleware's
+    /// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout) takes its place for the
+    /// reservations it makes.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Encoding of the entries written from now on. Defaults to [`WireFormat::Json`].
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{ENTRY}{:x}", self.prefix, Sha256::digest(key.as_bytes()))
+    }
+
+    fn maintenance_key(&self) -> String {
+        format!("{}maintenance", self.prefix)
+    }
+
+    // every key of ours that memcached currently holds, maintenance lock aside
+    async fn keys(&self, client: &mut Client) -> Result<Vec<Vec<u8>>, StoreError> {
+        let prefix = format!("{}{ENTRY}", self.prefix);
+
+        let mut dump = client.dump_keys().await.map_err(StoreError::backend)?;
+        let mut keys = Vec::new();
+        while let Some(meta) = dump.next().await {
+            let meta = meta.map_err(StoreError::backend)?;
+            if meta.key.starts_with(prefix.as_bytes()) {
+                keys.push(meta.key);
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+// Memcached's expiration for something living `duration` from now.
+fn expiration(duration: Duration) -> i64 {
+    let secs = i64::try_from(duration.as_secs()).unwrap_or(i64::MAX);
+    let secs = secs
+        .saturating_add(i64::from(duration.subsec_nanos() > 0))
+        .max(1);
+
+    if secs > MAX_RELATIVE_TTL {
+        Utc::now().timestamp().saturating_add(secs)
+    } else {
+        secs
+    }
+}
+
+// Memcached's expiration for an entry holding `element`.
+fn element_expiration(element: &CacheElement) -> i64 {
+    let remaining = (element.expires_at() - Utc::now())
+        .to_std()
+        .unwrap_or_default();
+    expiration(remaining)
+}
+
+// The stored response `data` rewritten in `format` with the expiration it has left, unless it is
+// a reservation or written in that format and the current layout already.
+fn upgrade(data: &[u8], format: WireFormat) -> Result<Option<(Vec<u8>, i64)>, StoreError> {
+    if data.starts_with(RESERVATION.as_bytes()) {
+        return Ok(None);
+    }
+
+    Ok(format
+        .upgrade(data)?
+        .map(|(element, upgraded)| (upgraded, element_expiration(&element))))
+}
+
+// what is stored under the key while `reservation` holds it
+fn owner(reservation: &Reservation) -> String {
+    format!("{RESERVATION}{}", reservation.token())
+}
+
+fn is_status(err: &Error, status: Status) -> bool {
+    matches!(err, Error::Protocol(found) if *found == status)
+}
+
+impl IdempotencyStore for MemcachedStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            let value = self
+                .client
+                .lock()
+                .await
+                .get(self.key(key))
+                .await
+                .map_err(StoreError::backend)?;
+
+            let Some(data) = value.and_then(|value| value.data) else {
+                return Ok(None);
+            };
+            if data.starts_with(RESERVATION.as_bytes()) {
+                return Ok(None);
+            }
+
+            let element = CacheElement::decode(&data)?;
+            Ok(Some(element).filter(|element| !element.is_expired()))
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.reserve_for(reservation, self.lock_timeout)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key(reservation.key());
+            let owner = owner(reservation);
+            let mut client = self.client.lock().await;
+
+            match client
+                .add(&key, owner.as_str(), Some(expiration(ttl)), None)
+                .await
+            {
+                Ok(()) => return Ok(true),
+                Err(err) if is_status(&err, Status::NotStored) => {}
+                Err(err) => return Err(StoreError::backend(err)),
+            }
+
+            // a retry finds its own reservation if the first attempt got through
+            let current = client
+                .meta_get(&key, false, None, Some(&["v"]))
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(current.is_some_and(|current| current.data.as_deref() == Some(owner.as_bytes())))
+        })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.key(reservation.key());
+            let owner = owner(reservation);
+            let mut client = self.client.lock().await;
+
+            let current = client
+                .meta_get(&key, false, None, Some(&["v", "c"]))
+                .await
+                .map_err(StoreError::backend)?;
+
+            // deletes the reservation only if it is still ours
+            let Some(cas) = current
+                .filter(|current| current.data.as_deref() == Some(owner.as_bytes()))
+                .and_then(|current| current.cas)
+            else {
+                return Ok(());
+            };
+
+            match client
+                .meta_delete(&key, false, None, Some(&[&format!("C{cas}")]))
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(err)
+                    if is_status(&err, Status::Exists) || is_status(&err, Status::NotFound) =>
+                {
+                    Ok(())
+                }
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key(element.key());
+            let owner = owner(reservation);
+            let encoded = element.encode(self.format)?;
+            let expiration = element_expiration(&element);
+
+            let mut client = self.client.lock().await;
+
+            let current = client
+                .meta_get(&key, false, None, Some(&["v", "c"]))
+                .await
+                .map_err(StoreError::backend)?;
+
+            let stored = match current {
+                // our reservation lapsed and nobody claimed the key since
+                None => client
+                    .add(&key, encoded.as_slice(), Some(expiration), None)
+                    .await
+                    .map(|_| true),
+                // replaces our reservation, unless it was taken over in the meantime
+                Some(current) if current.data.as_deref() == Some(owner.as_bytes()) => {
+                    let cas = format!("C{}", current.cas.unwrap_or_default());
+                    let ttl = format!("T{expiration}");
+
+                    client
+                        .meta_set(&key, encoded.as_slice(), false, None, Some(&[&cas, &ttl]))
+                        .await
+                        .map(|_| true)
+                }
+                // a stored response, or the reservation of a request that took over, wins
+                Some(_) => Ok(false),
+            };
+
+            match stored {
+                Ok(stored) => Ok(stored),
+                Err(err)
+                    if is_status(&err, Status::NotStored)
+                        || is_status(&err, Status::Exists)
+                        || is_status(&err, Status::NotFound) =>
+                {
+                    Ok(false)
+                }
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            match self.client.lock().await.delete(self.key(key)).await {
+                Ok(()) => Ok(true),
+                Err(err) if is_status(&err, Status::NotFound) => Ok(false),
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+            Ok(self.keys(&mut client).await?.len())
+        })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+
+            for key in self.keys(&mut client).await? {
+                match client.delete(&key).await {
+                    Ok(()) => {}
+                    // expired or removed since it was listed
+                    Err(err) if is_status(&err, Status::NotFound) => {}
+                    Err(err) => return Err(StoreError::backend(err)),
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+
+            let mut migrated = 0;
+            for key in self.keys(&mut client).await? {
+                let current = client
+                    .meta_get(&key, false, None, Some(&["v", "c"]))
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                let Some((data, cas)) =
+                    current.and_then(|current| Some((current.data?, current.cas?)))
+                else {
+                    continue;
+                };
+                let Some((upgraded, expiration)) = upgrade(&data, self.format)? else {
+                    continue;
+                };
+
+                // left alone if it changed in the meantime
+                let cas = format!("C{cas}");
+                let ttl = format!("T{expiration}");
+                match client
+                    .meta_set(&key, upgraded.as_slice(), false, None, Some(&[&cas, &ttl]))
+                    .await
+                {
+                    Ok(_) => migrated += 1,
+                    Err(err)
+                        if is_status(&err, Status::Exists) || is_status(&err, Status::NotFound) => {
+                    }
+                    Err(err) => return Err(StoreError::backend(err)),
+                }
+            }
+
+            Ok(migrated)
+        })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut client = self.client.lock().await;
+
+            let mut invalidated = 0;
+            for key in self.keys(&mut client).await? {
+                let current = client
+                    .meta_get(&key, false, None, Some(&["v", "c"]))
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                // keys are hashed, so even scopes are only told by the stored entry
+                let Some((data, cas)) =
+                    current.and_then(|current| Some((current.data?, current.cas?)))
+                else {
+                    continue;
+                };
+                if data.starts_with(RESERVATION.as_bytes())
+                    || !invalidation.matches(&CacheElement::decode(&data)?)
+                {
+                    continue;
+                }
+
+                // left alone if it changed in the meantime
+                match client
+                    .meta_delete(&key, false, None, Some(&[&format!("C{cas}")]))
+                    .await
+                {
+                    Ok(_) => invalidated += 1,
+                    Err(err)
+                        if is_status(&err, Status::Exists) || is_status(&err, Status::NotFound) => {
+                    }
+                    Err(err) => return Err(StoreError::backend(err)),
+                }
+            }
+
+            Ok(invalidated)
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.client
+                .lock()
+                .await
+                .set(
+                    self.maintenance_key(),
+                    "locked",
+                    Some(expiration(duration)),
+                    None,
+                )
+                .await
+                .map_err(StoreError::backend)
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            match self
+                .client
+                .lock()
+                .await
+                .delete(self.maintenance_key())
+                .await
+            {
+                Ok(()) => Ok(()),
+                Err(err) if is_status(&err, Status::NotFound) => Ok(()),
+                Err(err) => Err(StoreError::backend(err)),
+            }
+        })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            let lock = self
+                .client
+                .lock()
+                .await
+                .meta_get(self.maintenance_key(), false, None, Some(&["t"]))
+                .await
+                .map_err(StoreError::backend)?;
+
+            // memcached only tracks whole seconds
+            Ok(lock
+                .and_then(|lock| lock.ttl_remaining)
+                .and_then(|ttl| u64::try_from(ttl).ok())
+                .filter(|&ttl| ttl > 0)
+                .map(Duration::from_secs))
+        })
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.client
+                .lock()
+                .await
+                .version()
+                .await
+                .map(|_| ())
+                .map_err(StoreError::backend)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::wire::tests::{assert_same, legacy_entries};
+
+    #[test]
+    fn migrate_rewrites_entries_of_older_versions() {
+        for (element, raw) in legacy_entries() {
+            let (upgraded, expiration) = upgrade(&raw, WireFormat::Json).unwrap().unwrap();
+            assert_eq!(upgraded[..2], [4, b'j']);
+            assert_same(&CacheElement::decode(&upgraded).unwrap(), &element);
+            // the element expires within the hour, and the entry with it
+            assert!((3590..=3600).contains(&expiration));
+
+            // and leaves them alone from then on
+            assert!(upgrade(&upgraded, WireFormat::Json).unwrap().is_none());
+        }
+
+        let reservation = owner(&Reservation::new("order-1"));
+        assert!(upgrade(reservation.as_bytes(), WireFormat::Json)
+            .unwrap()
+            .is_none());
+    }
+}
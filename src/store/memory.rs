@@ -0,0 +1,937 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    fs,
+    hash::BuildHasher,
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{Clock, SystemClock};
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, MaintenanceLock, Reservation, StoreError,
+    StoreFuture, StoreStats, WireFormat,
+};
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// Process-local store backed by `HashMap`s.
+///
+/// Entries are lost when the process exits. Share one instance (e.g. behind an `Arc`) between
+/// workers, otherwise every worker thread ends up with its own cache.
+///
+/// Keys are spread by their hash over [`shards`](Self::shards) maps with a lock each, so that
+/// concurrent requests for different keys rarely wait on one another.
+///
+/// The store grows without bound unless limited with [`max_entries`](Self::max_entries) or
+/// [`max_bytes`](Self::max_bytes), past which responses are evicted according to the
+/// [`EvictionPolicy`]. Evicted responses can leave [`tombstones`](Self::tombstones) behind.
+pub struct MemoryStore {
+    shards: Box<[Mutex<State>]>,
+    hasher: RandomState,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    eviction: EvictionPolicy,
+    tombstones: Option<chrono::Duration>,
+    clock: Arc<dyn Clock>,
+    maintenance: MaintenanceLock,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Which responses a [`MemoryStore`] full up to its [`max_entries`](MemoryStore::max_entries)
+/// or [`max_bytes`](MemoryStore::max_bytes) evicts to make room for new ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The response stored first goes first.
+    #[default]
+    Fifo,
+    /// The response replayed least recently goes first.
+    Lru,
+    /// The response replayed least often goes first, the least recently replayed among those
+    /// replayed equally often.
+    Lfu,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, CacheElement>,
+    reservations: HashMap<String, Claim>,
+    // what is left of evicted entries, which does not count towards the limits
+    tombstones: HashMap<String, CacheElement>,
+    // approximate size of the entries
+    bytes: usize,
+    // eviction order of the entries, only kept while the store is bounded
+    ranks: HashMap<String, Rank>,
+    order: BTreeMap<Rank, String>,
+    tick: u64,
+}
+
+// The token of a reservation, with the deadline it lapses at, if any.
+struct Claim {
+    token: String,
+    deadline: Option<DateTime<Utc>>,
+}
+
+impl Claim {
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.deadline.is_none_or(|deadline| deadline > now)
+    }
+}
+
+// Entries with the lowest rank are evicted first. The second half is a tick unique to each
+// entry, so that ranks never collide.
+type Rank = (u64, u64);
+
+// What a single shard may hold, and how long it remembers what it evicted.
+#[derive(Clone, Copy)]
+struct Limits {
+    entries: Option<usize>,
+    bytes: Option<usize>,
+    // the budget of the whole store, which no single response may exceed
+    max_size: Option<usize>,
+    tombstones: Option<chrono::Duration>,
+}
+
+impl Limits {
+    fn is_bounded(self) -> bool {
+        self.entries.is_some() || self.bytes.is_some()
+    }
+
+    fn exceeded(self, entries: usize, bytes: usize) -> bool {
+        self.entries.is_some_and(|max| entries > max) || self.bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+impl State {
+    // drops the entry for `key` if it has expired, returning the live one otherwise
+    fn live(&mut self, key: &str, now: DateTime<Utc>) -> Option<&CacheElement> {
+        if self.entries.get(key)?.is_expired_at(now) {
+            self.forget(key);
+            return None;
+        }
+
+        self.entries.get(key)
+    }
+
+    // drops the tombstone for `key` if it has expired, returning the live one otherwise
+    fn tombstone(&mut self, key: &str, now: DateTime<Utc>) -> Option<&CacheElement> {
+        if self.tombstones.get(key)?.is_expired_at(now) {
+            self.tombstones.remove(key);
+            return None;
+        }
+
+        self.tombstones.get(key)
+    }
+
+    // drops the reservation of `key` if it has lapsed, returning the token holding it otherwise
+    fn holder(&mut self, key: &str, now: DateTime<Utc>) -> Option<&str> {
+        if !self.reservations.get(key)?.is_live(now) {
+            self.reservations.remove(key);
+            return None;
+        }
+
+        self.reservations.get(key).map(|claim| claim.token.as_str())
+    }
+
+    // Stores `element`, evicting others to make room. Returns `false` if it is too large to be
+    // kept at all.
+    fn put(&mut self, element: CacheElement, limits: Limits, eviction: EvictionPolicy) -> bool {
+        let key = element.key().to_owned();
+        let size = element.size();
+        self.forget(&key);
+        self.tombstones.remove(&key);
+
+        if limits.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+
+        if limits.is_bounded() {
+            // a response larger than the share of its shard is kept once nothing else is left
+            while limits.exceeded(self.entries.len() + 1, self.bytes + size) {
+                let Some((_, evicted)) = self.order.pop_first() else {
+                    break;
+                };
+                if let (Some(ttl), Some(element)) = (limits.tombstones, self.entries.get(&evicted))
+                {
+                    // never gone before the response would have expired
+                    let expires_at = (element.created_at() + ttl).max(element.expires_at());
+                    self.tombstones
+                        .insert(evicted.clone(), element.tombstone(expires_at));
+                }
+                self.forget(&evicted);
+            }
+
+            self.tick += 1;
+            let rank = match eviction {
+                // not replayed yet
+                EvictionPolicy::Lfu => (0, self.tick),
+                _ => (self.tick, self.tick),
+            };
+            self.ranks.insert(key.clone(), rank);
+            self.order.insert(rank, key.clone());
+        }
+
+        self.bytes += size;
+        self.entries.insert(key, element);
+
+        true
+    }
+
+    // moves `key` back in the eviction order after it was replayed
+    fn touch(&mut self, key: &str, eviction: EvictionPolicy) {
+        if eviction == EvictionPolicy::Fifo {
+            return;
+        }
+        let Some(rank) = self.ranks.get_mut(key) else {
+            return;
+        };
+
+        self.tick += 1;
+        let touched = match eviction {
+            EvictionPolicy::Lfu => (rank.0 + 1, self.tick),
+            _ => (self.tick, self.tick),
+        };
+
+        if let Some(key) = self.order.remove(rank) {
+            self.order.insert(touched, key);
+        }
+        *rank = touched;
+    }
+
+    fn forget(&mut self, key: &str) -> bool {
+        if let Some(rank) = self.ranks.remove(key) {
+            self.order.remove(&rank);
+        }
+
+        let Some(element) = self.entries.remove(key) else {
+            return false;
+        };
+        self.bytes -= element.size();
+
+        true
+    }
+}
+
+fn new_shards(shards: usize) -> Box<[Mutex<State>]> {
+    (0..shards).map(|_| Mutex::default()).collect()
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store expiring entries according to `clock` rather than the system clock.
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        Self {
+            shards: new_shards(DEFAULT_SHARDS),
+            hasher: RandomState::new(),
+            max_entries: None,
+            max_bytes: None,
+            eviction: EvictionPolicy::default(),
+            tombstones: None,
+            clock: Arc::new(clock),
+            maintenance: MaintenanceLock::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+        }
+    }
+
+    /// How many maps the keys are spread over. Defaults to 16, and is raised to 1 if 0.
+    ///
+    /// More shards mean less contention between requests running in parallel, at the cost of
+    /// a little memory each. Entries held so far are dropped, so set this before using the
+    /// store.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = new_shards(shards.max(1));
+        self
+    }
+
+    /// Most responses held at once, beyond which the [`eviction`](Self::eviction) policy picks
+    /// which ones make room. Unbounded by default, and raised to 1 if 0.
+    ///
+    /// The limit is split evenly between the [`shards`](Self::shards), so a shard may start
+    /// evicting a little before the store as a whole is full, but the store never holds more. A
+    /// store limited to fewer responses than it has shards only uses as many shards. Reservations
+    /// of requests still executing do not count towards it. Set this before using the store.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries.max(1));
+        self
+    }
+
+    /// Approximate number of bytes the stored responses may take up, beyond which the
+    /// [`eviction`](Self::eviction) policy picks which ones make room. Unbounded by default.
+    ///
+    /// Responses are weighed by their body, headers and bookkeeping, as reported by
+    /// [`stats`](IdempotencyStore::stats). Like [`max_entries`](Self::max_entries), the budget
+    /// is split evenly between the [`shards`](Self::shards). A response larger than a shard's
+    /// share is kept at the expense of every other response in its shard, so the store may
+    /// exceed the budget by up to the size of that response. A response larger than the whole
+    /// budget is not kept at all: [`commit`](IdempotencyStore::commit) fails with
+    /// [`StoreError::TooLarge`] and releases the key, so that a retry executes again. Set this
+    /// before using the store.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Which responses are evicted once [`max_entries`](Self::max_entries) or
+    /// [`max_bytes`](Self::max_bytes) is reached. Defaults to [`EvictionPolicy::Fifo`].
+    pub fn eviction(mut self, eviction: EvictionPolicy) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Keeps a tombstone of every response evicted to make room, until `ttl` after it was first
+    /// stored or until the response would have expired, whichever is later. Off by default.
+    ///
+    /// A tombstone holds the key, status, fingerprint and metadata of the response, but neither
+    /// its headers nor its body, so it stays known which keys were used. Requests retrying such a
+    /// key are rejected with `409 Conflict` and [`IdempotencyError::Evicted`] rather than executed
+    /// again. Tombstones do not count towards [`max_entries`](Self::max_entries) and
+    /// [`max_bytes`](Self::max_bytes), and are not [`save`](Self::save)d.
+    ///
+    /// [`IdempotencyError::Evicted`]: crate::IdempotencyError::Evicted
+    pub fn tombstones(mut self, ttl: Duration) -> Self {
+        self.tombstones = Some(chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX));
+        self
+    }
+
+    // claims the key unless it holds a response, a tombstone or another reservation that has not
+    // lapsed
+    fn reserve_until(&self, reservation: &Reservation, deadline: Option<DateTime<Utc>>) -> bool {
+        let key = reservation.key();
+        let mut state = self.shard(key).lock().unwrap();
+        let now = self.clock.now();
+
+        let reserved = state.live(key, now).is_none()
+            && state.tombstone(key, now).is_none()
+            && state
+                .holder(key, now)
+                .is_none_or(|token| token == reservation.token());
+        if reserved {
+            let token = reservation.token().to_owned();
+            state
+                .reservations
+                .insert(key.to_owned(), Claim { token, deadline });
+        }
+
+        reserved
+    }
+
+    // How many shards keys are spread over. A store bounded to fewer entries than it has shards
+    // only uses as many as it may hold entries, so that each of them can hold at least one.
+    fn used_shards(&self) -> usize {
+        self.shards
+            .len()
+            .min(self.max_entries.unwrap_or(usize::MAX))
+    }
+
+    // The share of the limits of the shard at `index`. Whatever does not divide evenly goes to
+    // the first shards, so that the shares add up to the limits.
+    fn shard_limits(&self, index: usize) -> Limits {
+        let shards = self.used_shards();
+        let share = |max: usize| max / shards + usize::from(index < max % shards);
+
+        Limits {
+            entries: self.max_entries.map(share),
+            bytes: self.max_bytes.map(share),
+            max_size: self.max_bytes,
+            tombstones: self.tombstones,
+        }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        // the remainder is below the shard count, so it fits a usize
+        (self.hasher.hash_one(key) % self.used_shards() as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<State> {
+        &self.shards[self.index(key)]
+    }
+
+    /// Every entry that has not expired. Reservations of requests still executing are left out.
+    pub fn entries(&self) -> Vec<CacheElement> {
+        let now = self.clock.now();
+
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .entries
+                    .values()
+                    .filter(|element| !element.is_expired_at(now))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Copies every entry that has not expired into `store`, e.g. a persistent one the process
+    /// falls back to on shutdown. Returns how many were copied.
+    ///
+    /// Entries `store` already holds for the same key are kept.
+    pub async fn flush_to(&self, store: &dyn IdempotencyStore) -> Result<usize, StoreError> {
+        let mut flushed = 0;
+        for element in self.entries() {
+            let reservation = Reservation::new(element.key());
+            flushed += usize::from(store.commit(&reservation, element).await?);
+        }
+
+        Ok(flushed)
+    }
+
+    /// Writes every entry that has not expired to the file at `path`, to be read back with
+    /// [`load`](Self::load) when the process starts again. Returns how many were written.
+    ///
+    /// The file is replaced atomically, so a crash while saving leaves the previous one intact.
+    /// Call it once the server stopped, so that no response completes after it ran:
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    ///
+    /// use actix_web::{App, HttpServer};
+    /// use actix_web_idempotency::{Idempotency, MemoryStore, WireFormat};
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let store = Arc::new(MemoryStore::new());
+    /// store.load("idempotency.bin")?;
+    ///
+    /// let idempotency = Idempotency::builder()
+    ///     .store(Arc::clone(&store))
+    ///     .build()
+    ///     .unwrap();
+    /// HttpServer::new(move || App::new().wrap(idempotency.clone()))
+    ///     .bind(("127.0.0.1", 8080))?
+    ///     .run()
+    ///     .await?;
+    ///
+    /// store.save("idempotency.bin", WireFormat::Json)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn save(&self, path: impl AsRef<Path>, format: WireFormat) -> Result<usize, StoreError> {
+        let path = path.as_ref();
+        let entries = self.entries();
+
+        let mut staged = path.as_os_str().to_owned();
+        staged.push(".tmp");
+
+        let mut file = io::BufWriter::new(fs::File::create(&staged).map_err(StoreError::backend)?);
+        for element in &entries {
+            let encoded = element.encode(format)?;
+            // every entry is preceded by its length
+            let len = u32::try_from(encoded.len()).map_err(StoreError::backend)?;
+
+            file.write_all(&len.to_be_bytes())
+                .and_then(|()| file.write_all(&encoded))
+                .map_err(StoreError::backend)?;
+        }
+        file.into_inner()
+            .map_err(|err| StoreError::backend(err.into_error()))?
+            .sync_all()
+            .map_err(StoreError::backend)?;
+
+        fs::rename(&staged, path).map_err(StoreError::backend)?;
+
+        Ok(entries.len())
+    }
+
+    /// Adds the entries saved to `path` by [`save`](Self::save), returning how many were added.
+    ///
+    /// Entries that expired in the meantime are skipped, as are keys this store already holds.
+    /// A missing file is not an error, so that the very first start needs no special casing.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<usize, StoreError> {
+        let raw = match fs::read(path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(StoreError::backend(err)),
+        };
+
+        let mut elements = Vec::new();
+        let mut rest = raw.as_slice();
+        while !rest.is_empty() {
+            let truncated = || StoreError::backend("saved idempotency entries are truncated");
+
+            let (len, tail) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+            let len = usize::try_from(u32::from_be_bytes(*len)).map_err(StoreError::backend)?;
+            if tail.len() < len {
+                return Err(truncated());
+            }
+
+            let (encoded, tail) = tail.split_at(len);
+            elements.push(CacheElement::decode(encoded)?);
+            rest = tail;
+        }
+
+        let now = self.clock.now();
+
+        let mut loaded = 0;
+        for element in elements {
+            let index = self.index(element.key());
+            let mut state = self.shards[index].lock().unwrap();
+            if element.is_expired_at(now) || state.live(element.key(), now).is_some() {
+                continue;
+            }
+
+            loaded += usize::from(state.put(element, self.shard_limits(index), self.eviction));
+        }
+
+        Ok(loaded)
+    }
+}
+
+impl IdempotencyStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        let mut state = self.shard(key).lock().unwrap();
+        let now = self.clock.now();
+
+        let mut element = state.live(key, now).cloned();
+        if element.is_some() {
+            state.touch(key, self.eviction);
+        } else {
+            element = state.tombstone(key, now).cloned();
+        }
+        drop(state);
+
+        let counter = if element.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Box::pin(async move { Ok(element) })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        let reserved = self.reserve_until(reservation, None);
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        let deadline = self.clock.now().checked_add_signed(ttl);
+        let reserved = self.reserve_until(
+            reservation,
+            Some(deadline.unwrap_or(DateTime::<Utc>::MAX_UTC)),
+        );
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        let key = reservation.key();
+        let mut state = self.shard(key).lock().unwrap();
+
+        if state
+            .reservations
+            .get(key)
+            .is_some_and(|claim| claim.token == reservation.token())
+        {
+            state.reservations.remove(key);
+        }
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        let index = self.index(element.key());
+        let mut state = self.shards[index].lock().unwrap();
+        let now = self.clock.now();
+
+        let inserted = state
+            .holder(element.key(), now)
+            .is_none_or(|token| token == reservation.token())
+            && state.live(element.key(), now).is_none();
+        if inserted {
+            state.reservations.remove(element.key());
+            if !state.put(element, self.shard_limits(index), self.eviction) {
+                return Box::pin(async { Err(StoreError::TooLarge) });
+            }
+        }
+
+        Box::pin(async move { Ok(inserted) })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        let mut state = self.shard(key).lock().unwrap();
+
+        let removed = state.forget(key)
+            | state.tombstones.remove(key).is_some()
+            | state.reservations.remove(key).is_some();
+
+        Box::pin(async move { Ok(removed) })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        let len = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let state = shard.lock().unwrap();
+                state.entries.len() + state.reservations.len()
+            })
+            .sum();
+
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        for shard in &self.shards {
+            *shard.lock().unwrap() = State::default();
+        }
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        let now = self.clock.now();
+
+        let mut reclaimed = 0;
+        for shard in &self.shards {
+            let mut state = shard.lock().unwrap();
+
+            let expired: Vec<_> = state
+                .entries
+                .values()
+                .filter(|element| element.is_expired_at(now))
+                .map(|element| element.key().to_owned())
+                .collect();
+            for key in &expired {
+                state.forget(key);
+            }
+            reclaimed += expired.len();
+
+            let tombstones = state.tombstones.len();
+            state
+                .tombstones
+                .retain(|_, tombstone| !tombstone.is_expired_at(now));
+            reclaimed += tombstones - state.tombstones.len();
+
+            let reservations = state.reservations.len();
+            state.reservations.retain(|_, claim| claim.is_live(now));
+            reclaimed += reservations - state.reservations.len();
+        }
+
+        Box::pin(async move { Ok(reclaimed) })
+    }
+
+    // entries are kept as values, not in any layout
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        let mut invalidated = 0;
+        for shard in &self.shards {
+            let mut state = shard.lock().unwrap();
+
+            let matching: Vec<_> = state
+                .entries
+                .values()
+                .filter(|element| invalidation.matches(element))
+                .map(|element| element.key().to_owned())
+                .collect();
+            for key in &matching {
+                state.forget(key);
+            }
+            invalidated += matching.len();
+
+            let tombstones = state.tombstones.len();
+            state
+                .tombstones
+                .retain(|_, tombstone| !invalidation.matches(tombstone));
+            invalidated += tombstones - state.tombstones.len();
+        }
+
+        Box::pin(async move { Ok(invalidated) })
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        let now = self.clock.now();
+
+        let (mut entries, mut reservations, mut memory_bytes) = (0, 0, 0);
+        let mut oldest: Option<DateTime<Utc>> = None;
+        for shard in &self.shards {
+            let state = shard.lock().unwrap();
+
+            entries += state.entries.len() + state.reservations.len();
+            reservations += state.reservations.len();
+            memory_bytes += state.bytes
+                + state.reservations.keys().map(String::len).sum::<usize>()
+                + state
+                    .tombstones
+                    .values()
+                    .map(CacheElement::size)
+                    .sum::<usize>();
+
+            for element in state.entries.values() {
+                if !element.is_expired_at(now)
+                    && oldest.is_none_or(|oldest| element.created_at() < oldest)
+                {
+                    oldest = Some(element.created_at());
+                }
+            }
+        }
+
+        let stats = StoreStats {
+            entries,
+            reservations: Some(reservations),
+            memory_bytes: Some(memory_bytes),
+            hits: Some(self.hits.load(Ordering::Relaxed)),
+            misses: Some(self.misses.load(Ordering::Relaxed)),
+            oldest_entry_age: oldest
+                .map(|created_at| (now - created_at).to_std().unwrap_or_default()),
+        };
+
+        Box::pin(async move { Ok(stats) })
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        // nothing to reach, and looking up a probe key would count as a miss
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.maintenance.lock(duration);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.maintenance.unlock();
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        let remaining = self.maintenance.remaining();
+
+        Box::pin(async move { Ok(remaining) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, web::Bytes, HttpResponse};
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::HeaderFilter;
+
+    // a clock that only moves when told to
+    #[derive(Clone)]
+    struct Stopped(Arc<Mutex<DateTime<Utc>>>);
+
+    impl Stopped {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Utc::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += chrono::Duration::from_std(duration).unwrap();
+        }
+    }
+
+    impl Clock for Stopped {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn element(key: &str, body: &'static str, now: DateTime<Utc>) -> CacheElement {
+        CacheElement::capture(
+            key.to_owned(),
+            &HttpResponse::with_body(StatusCode::CREATED, ()),
+            Bytes::from_static(body.as_bytes()),
+            chrono::Duration::hours(1),
+            &HeaderFilter::default(),
+            now,
+        )
+    }
+
+    // the store never suspends, so its futures are ready right away
+    fn ready<T>(future: StoreFuture<'_, T>) -> T {
+        future.now_or_never().unwrap().unwrap()
+    }
+
+    #[test]
+    fn reservation_outlives_crashed_request() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone());
+
+        // the request dies between reserving and committing
+        assert!(ready(store.reserve(&Reservation::new("order-1"))));
+
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        let retry = Reservation::new("order-1");
+        assert!(!ready(store.reserve(&retry)));
+        assert!(ready(store.get("order-1")).is_none());
+    }
+
+    #[test]
+    fn retry_takes_over_lapsed_reservation() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone());
+        let timeout = Duration::from_secs(30);
+
+        assert!(ready(
+            store.reserve_for(&Reservation::new("order-1"), timeout)
+        ));
+
+        let retry = Reservation::new("order-1");
+        assert!(!ready(store.reserve_for(&retry, timeout)));
+
+        clock.advance(timeout);
+        assert!(ready(store.reserve_for(&retry, timeout)));
+        assert!(ready(
+            store.commit(&retry, element("order-1", "retry", clock.now()))
+        ));
+        assert_eq!(ready(store.get("order-1")).unwrap().body(), b"retry");
+    }
+
+    #[test]
+    fn commit_after_lapse_is_stored_unless_taken_over() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone());
+        let timeout = Duration::from_secs(30);
+
+        let slow = Reservation::new("order-1");
+        assert!(ready(store.reserve_for(&slow, timeout)));
+
+        clock.advance(timeout);
+        assert!(ready(
+            store.commit(&slow, element("order-1", "slow", clock.now()))
+        ));
+        assert_eq!(ready(store.get("order-1")).unwrap().body(), b"slow");
+    }
+
+    #[test]
+    fn commit_after_lapse_loses_to_retry() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone());
+        let timeout = Duration::from_secs(30);
+
+        let slow = Reservation::new("order-1");
+        assert!(ready(store.reserve_for(&slow, timeout)));
+        clock.advance(timeout);
+        let retry = Reservation::new("order-1");
+        assert!(ready(store.reserve_for(&retry, timeout)));
+
+        // the original request neither overwrites nor releases the retry's reservation
+        assert!(!ready(
+            store.commit(&slow, element("order-1", "slow", clock.now()))
+        ));
+        ready(store.abort(&slow));
+        assert!(!ready(store.reserve(&Reservation::new("order-1"))));
+
+        assert!(ready(
+            store.commit(&retry, element("order-1", "retry", clock.now()))
+        ));
+        assert_eq!(ready(store.get("order-1")).unwrap().body(), b"retry");
+    }
+
+    #[test]
+    fn reserving_again_recognises_own_claim() {
+        let store = MemoryStore::new();
+        let reservation = Reservation::new("order-1");
+
+        assert!(ready(store.reserve(&reservation)));
+        assert!(ready(store.reserve(&reservation)));
+        assert!(!ready(store.reserve(&Reservation::new("order-1"))));
+    }
+
+    #[test]
+    fn shards_never_hold_more_than_max_entries() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone()).max_entries(20);
+
+        for key in 0..200 {
+            let reservation = Reservation::new(format!("order-{key}"));
+            assert!(ready(store.reserve(&reservation)));
+            let element = element(reservation.key(), "{}", clock.now());
+            assert!(ready(store.commit(&reservation, element)));
+        }
+        assert!(ready(store.len()) <= 20);
+
+        let store = MemoryStore::with_clock(clock.clone()).max_entries(3);
+        for key in 0..50 {
+            let reservation = Reservation::new(format!("order-{key}"));
+            assert!(ready(store.reserve(&reservation)));
+            let element = element(reservation.key(), "{}", clock.now());
+            assert!(ready(store.commit(&reservation, element)));
+        }
+        assert_eq!(ready(store.len()), 3);
+    }
+
+    #[test]
+    fn zero_max_entries_holds_one_response() {
+        let clock = Stopped::new();
+        let store = MemoryStore::with_clock(clock.clone()).max_entries(0);
+
+        for key in 0..5 {
+            let reservation = Reservation::new(format!("order-{key}"));
+            assert!(ready(store.reserve(&reservation)));
+            let element = element(reservation.key(), "{}", clock.now());
+            assert!(ready(store.commit(&reservation, element)));
+        }
+        assert_eq!(ready(store.len()), 1);
+        assert!(ready(store.get("order-4")).is_some());
+    }
+
+    #[test]
+    fn response_larger_than_shard_share_is_kept() {
+        let clock = Stopped::new();
+        let body = "x".repeat(512).leak();
+        let size = element("order-1", body, clock.now()).size();
+        let store = MemoryStore::with_clock(clock.clone()).max_bytes(size * 2);
+
+        let reservation = Reservation::new("order-1");
+        assert!(ready(store.reserve(&reservation)));
+        let response = element("order-1", body, clock.now());
+        assert!(ready(store.commit(&reservation, response)));
+        assert!(ready(store.get("order-1")).is_some());
+    }
+
+    #[test]
+    fn response_larger_than_max_bytes_is_refused_and_key_released() {
+        let clock = Stopped::new();
+        let body = "x".repeat(512).leak();
+        let size = element("order-1", body, clock.now()).size();
+        let store = MemoryStore::with_clock(clock.clone()).max_bytes(size - 1);
+
+        let reservation = Reservation::new("order-1");
+        assert!(ready(store.reserve(&reservation)));
+        let response = element("order-1", body, clock.now());
+        let committed = store.commit(&reservation, response).now_or_never().unwrap();
+        assert!(matches!(committed, Err(StoreError::TooLarge)));
+        assert!(ready(store.get("order-1")).is_none());
+        assert!(ready(store.reserve(&Reservation::new("order-1"))));
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use futures_util::future::try_join;
+
+use super::{
+    BodyStream, CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture,
+    StoreStats,
+};
+
+/// Store for moving from one backend to another without downtime, e.g. from a [`MemoryStore`]
+/// to a shared store such as Redis.
+///
+/// Responses are looked up in the new store first and then in the old one, so keys used before
+/// the cutover are still replayed. Writes go to the new store only, unless
+/// [`dual_write`](Self::dual_write) is on, which keeps the old store complete for instances that
+/// have not switched yet or for rolling back. Removals and maintenance locks always apply to
+/// both.
+///
+/// Once the [`ttl`](crate::IdempotencyBuilder::ttl) has passed since the cutover, the old store
+/// holds nothing worth replaying and the new one can replace this store.
+///
+/// ```
+/// use actix_web_idempotency::{IdempotencyStore, MemoryStore, MigratingStore};
+///
+/// fn cutover(shared: impl IdempotencyStore, local: MemoryStore) -> impl IdempotencyStore {
+///     MigratingStore::new(local, shared).dual_write(true)
+/// }
+/// ```
+///
+/// [`MemoryStore`]: super::MemoryStore
+pub struct MigratingStore<O, N> {
+    old: O,
+    new: N,
+    dual_write: bool,
+}
+
+impl<O: IdempotencyStore, N: IdempotencyStore> MigratingStore<O, N> {
+    pub fn new(old: O, new: N) -> Self {
+        Self {
+            old,
+            new,
+            dual_write: false,
+        }
+    }
+
+    /// Also reserves keys in and writes responses to the old store. Off by default.
+    ///
+    /// Keys are then reserved in both stores, so requests are executed once even across
+    /// instances still using only the old store.
+    pub fn dual_write(mut self, dual_write: bool) -> Self {
+        self.dual_write = dual_write;
+        self
+    }
+
+    /// The store being migrated from.
+    pub fn old_store(&self) -> &O {
+        &self.old
+    }
+
+    /// The store being migrated to.
+    pub fn new_store(&self) -> &N {
+        &self.new
+    }
+
+    // reserves the key in the new store, and in the old one as well if writing to both
+    async fn claim(
+        &self,
+        reservation: &Reservation,
+        ttl: Option<Duration>,
+    ) -> Result<bool, StoreError> {
+        if !reserve(&self.new, reservation, ttl).await? {
+            return Ok(false);
+        }
+        if !self.dual_write {
+            return Ok(true);
+        }
+
+        // the key is held by an instance that still only uses the old store
+        match reserve(&self.old, reservation, ttl).await {
+            Ok(true) => Ok(true),
+            result => {
+                self.new.abort(reservation).await?;
+                result
+            }
+        }
+    }
+}
+
+impl<O: IdempotencyStore, N: IdempotencyStore> IdempotencyStore for MigratingStore<O, N> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            match self.new.get(key).await? {
+                Some(element) => Ok(Some(element)),
+                None => self.old.get(key).await,
+            }
+        })
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        Box::pin(async move {
+            match self.new.get_streamed(key).await? {
+                Some(streamed) => Ok(Some(streamed)),
+                None => self.old.get_streamed(key).await,
+            }
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        Box::pin(self.claim(reservation, None))
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(self.claim(reservation, Some(ttl)))
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            if self.dual_write {
+                try_join(self.new.abort(reservation), self.old.abort(reservation)).await?;
+                return Ok(());
+            }
+
+            self.new.abort(reservation).await
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            if !self.dual_write {
+                return self.new.commit(reservation, element).await;
+            }
+
+            let key = element.key().to_owned();
+            let inserted = self.new.commit(reservation, element.clone()).await?;
+
+            // the new store decides, the old one merely keeps up
+            if let Err(err) = self.old.commit(reservation, element).await {
+                log::warn!(
+                    "failed to copy response for idempotency key {key} to the old store: {err}"
+                );
+            }
+
+            Ok(inserted)
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let (new, old) = try_join(self.new.remove(key), self.old.remove(key)).await?;
+            Ok(new || old)
+        })
+    }
+
+    // counts entries written to both stores twice
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let (new, old) = try_join(self.new.len(), self.old.len()).await?;
+            Ok(new + old)
+        })
+    }
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        Box::pin(async move {
+            let (new, old) = try_join(self.new.is_empty(), self.old.is_empty()).await?;
+            Ok(new && old)
+        })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            try_join(self.new.clear(), self.old.clear()).await?;
+            Ok(())
+        })
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let (new, old) = try_join(self.new.purge_expired(), self.old.purge_expired()).await?;
+            Ok(new + old)
+        })
+    }
+
+    // entries still in the old store are rewritten as they move over anyway
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        self.new.migrate()
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let (new, old) = try_join(
+                self.new.invalidate(invalidation.clone()),
+                self.old.invalidate(invalidation),
+            )
+            .await?;
+            Ok(new + old)
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            try_join(
+                self.new.maintenance_lock(duration),
+                self.old.maintenance_lock(duration),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            try_join(self.new.maintenance_unlock(), self.old.maintenance_unlock()).await?;
+            Ok(())
+        })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            let (new, old) = try_join(
+                self.new.maintenance_remaining(),
+                self.old.maintenance_remaining(),
+            )
+            .await?;
+            Ok(new.max(old))
+        })
+    }
+
+    // the old store only matters until the cutover is complete
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        self.new.stats()
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            try_join(self.new.health(), self.old.health()).await?;
+            Ok(())
+        })
+    }
+}
+
+fn reserve<'a>(
+    store: &'a dyn IdempotencyStore,
+    reservation: &'a Reservation,
+    ttl: Option<Duration>,
+) -> StoreFuture<'a, bool> {
+    match ttl {
+        Some(ttl) => store.reserve_for(reservation, ttl),
+        None => store.reserve(reservation),
+    }
+}
@@ -0,0 +1,1202 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_web::{
+    body::{BoxBody, SizedStream},
+    http::{
+        header::{self, HeaderName, HeaderValue, HttpDate},
+        Method, StatusCode,
+    },
+    web::{Bytes, BytesMut},
+    HttpResponse, HttpResponseBuilder,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    future::BoxFuture,
+    stream::{BoxStream, Stream, StreamExt, TryStreamExt},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+#[cfg(feature = "encryption")]
+use crate::Encryption;
+use crate::{
+    compression::{self, Codec, Compression},
+    headers, HeaderFilter,
+};
+
+mod breaker;
+mod bus;
+#[cfg(feature = "dynamodb")]
+mod dynamodb;
+#[cfg(feature = "memcached")]
+mod memcached;
+mod memory;
+mod migrating;
+#[cfg(feature = "moka")]
+mod moka;
+#[cfg(feature = "mysql")]
+mod mysql;
+mod offload;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "redis")]
+mod redis_bus;
+mod retry;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "scylla")]
+mod scylla;
+#[cfg(feature = "sled")]
+mod sled;
+mod tiered;
+mod wire;
+
+pub use self::breaker::{CircuitBreakerStore, CircuitState};
+pub use self::bus::{BusHandler, BusMessage, InvalidationBus};
+#[cfg(feature = "dynamodb")]
+pub use self::dynamodb::DynamoDbStore;
+#[cfg(feature = "memcached")]
+pub use self::memcached::MemcachedStore;
+#[cfg(feature = "moka")]
+pub use self::moka::MokaStore;
+#[cfg(feature = "mysql")]
+pub use self::mysql::MySqlStore;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+#[cfg(feature = "redis")]
+pub use self::redis_bus::RedisInvalidationBus;
+#[cfg(feature = "s3")]
+pub use self::s3::S3BlobStore;
+#[cfg(feature = "scylla")]
+pub use self::scylla::ScyllaStore;
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+pub use memory::{EvictionPolicy, MemoryStore};
+pub use migrating::MigratingStore;
+pub use offload::{BlobStore, OffloadStore};
+pub use retry::RetryStore;
+pub use tiered::TieredStore;
+pub use wire::WireFormat;
+
+// Looked up by the default health check. No request can send it, as header values cannot contain
+// NUL bytes.
+const HEALTH_PROBE: &str = "\0health";
+
+// Marks an entry as a tombstone. Header names cannot start with a colon, so no stored response can
+// carry it.
+const TOMBSTONE: &str = ":tombstone";
+
+// Carries the id of the request whose response is being replayed.
+const ORIGINAL_REQUEST_ID: &str = "Idempotency-Original-Request-Id";
+
+/// Future returned by every [`IdempotencyStore`] operation.
+pub type StoreFuture<'a, T> = BoxFuture<'a, Result<T, StoreError>>;
+
+/// A request's claim on a key, taken with [`IdempotencyStore::reserve`] and handed back to
+/// [`commit`](IdempotencyStore::commit) or [`abort`](IdempotencyStore::abort) once the request
+/// is done.
+///
+/// Every reservation carries a token of its own, which backends keep along with the claim. A
+/// request that outlived its claim, e.g. because its handler ran past the backend's lock timeout
+/// and a retry reserved the key again, is told apart from that retry by it, so that it can
+/// neither release nor overwrite the claim of the retry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    key: String,
+    token: String,
+}
+
+impl Reservation {
+    /// A fresh reservation of `key`, with a token no other reservation shares.
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            token: Uuid::new_v4().simple().to_string(),
+        }
+    }
+
+    /// The key being claimed.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// What tells this reservation apart from every other one of the same key.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+/// Backend holding the responses the middleware replays.
+///
+/// Each request goes through three phases: the middleware [`reserve`](Self::reserve)s its key
+/// with a fresh [`Reservation`], executes the handler, and then either [`commit`](Self::commit)s
+/// the response or [`abort`](Self::abort)s the reservation. Backends carry out each phase as a single atomic
+/// operation, so that concurrent requests with the same key never both get to execute, and a
+/// response is never stored without replacing the reservation it completes.
+///
+/// If the process dies between the phases, the reservation stays in place: retries are turned
+/// away as in progress until it expires or is removed, rather than executed a second time.
+/// Operations are never forgotten, only held up.
+pub trait IdempotencyStore: Send + Sync {
+    /// Returns the entry stored for `key`, ignoring entries that have already expired.
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>>;
+
+    /// Returns the entry stored for `key` like [`get`](Self::get), but with its body taken out,
+    /// to be read in chunks while it is replayed rather than into memory all at once.
+    ///
+    /// The default reads the whole entry with `get` and hands out its body as a single chunk.
+    /// Backends able to read a body piecemeal, such as `RedisStore` with `streamed_bodies`,
+    /// return a [`BodyStream::new`] instead.
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        Box::pin(async move {
+            Ok(self.get(key).await?.map(|mut element| {
+                let body = element.take_body();
+                (element, BodyStream::ready(body))
+            }))
+        })
+    }
+
+    /// Claims the key of `reservation` for a request that is about to be handed to the
+    /// handler.
+    ///
+    /// Returns `false` if the key is held by another reservation or an unexpired response, in
+    /// which case the request must not be executed. Checking for both and taking the claim
+    /// has to happen atomically, e.g. through a conditional write. Reserving again with a
+    /// reservation that still holds the key succeeds, so that an attempt retried after a failure
+    /// that may have reached the backend recognises its own claim.
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool>;
+
+    /// Claims the key of `reservation` like [`reserve`](Self::reserve), but lets the claim lapse
+    /// once `ttl` has passed without a commit or abort, so that a request whose handler hangs or
+    /// whose process died does not hold the key forever.
+    ///
+    /// Used by the middleware with a
+    /// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout). The default ignores `ttl`,
+    /// leaving reservations to expire however the backend's `reserve` lets them.
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        _ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        self.reserve(reservation)
+    }
+
+    /// Gives up `reservation` without storing a response, e.g. because the handler failed.
+    ///
+    /// Leaves the key alone unless `reservation` still holds it, so that an abort arriving late
+    /// removes neither the response nor the reservation of another request.
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()>;
+
+    /// Stores the response captured for the request holding `reservation`, atomically replacing
+    /// the reservation. `element` is stored under the key of `reservation`.
+    ///
+    /// If the key holds an unexpired response or the reservation of another request, that is
+    /// kept and `false` is returned: the same key has then been executed twice, which the
+    /// middleware reports as a double execution. A reservation that lapsed without anyone
+    /// claiming the key since is replaced all the same.
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool>;
+
+    /// Deletes the entry or reservation held for `key`, returning whether there was one.
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool>;
+
+    /// Number of entries held, including reservations of requests still executing and entries
+    /// that expired but have not been purged yet.
+    fn len(&self) -> StoreFuture<'_, usize>;
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        Box::pin(async move { Ok(self.len().await? == 0) })
+    }
+
+    /// Deletes every entry and reservation.
+    fn clear(&self) -> StoreFuture<'_, ()>;
+
+    /// Removes expired entries and returns how many were reclaimed.
+    ///
+    /// Backends that expire entries on their own can keep the default, which does nothing.
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// Pauses new claims for `duration`, so that maintenance such as migrations or re-keying can
+    /// run without requests writing to the store concurrently.
+    ///
+    /// The lock lapses on its own once `duration` has passed. Locking again replaces the
+    /// previous deadline.
+    fn maintenance_lock(&self, _duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async { Err(StoreError::Unsupported) })
+    }
+
+    /// Lifts a maintenance lock before its deadline.
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async { Err(StoreError::Unsupported) })
+    }
+
+    /// How much longer the current maintenance lock holds, if any.
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Rewrites the entries written by older versions of this crate, or in another
+    /// [`WireFormat`] than the one configured, in the current layout and format. Returns how
+    /// many were rewritten.
+    ///
+    /// Entries of every earlier layout are read without it, so migrating is never required, but
+    /// saves converting them on each read after an upgrade or a change of format. Only run it
+    /// once every instance runs the new version, as older ones cannot read what it writes.
+    /// Entries that change while they are rewritten are left as they are.
+    ///
+    /// Backends keeping entries as values rather than encoded, such as [`MemoryStore`], have
+    /// nothing to migrate and return `0`.
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async { Err(StoreError::Unsupported) })
+    }
+
+    /// Removes every stored response `invalidation` matches, e.g. those stored from corrupted
+    /// data during an incident, and returns how many were removed.
+    ///
+    /// Reservations of requests still executing are left alone. Backends unable to enumerate
+    /// their entries keep the default, which fails with [`StoreError::Unsupported`].
+    fn invalidate(&self, _invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async { Err(StoreError::Unsupported) })
+    }
+
+    /// Removes the responses stored for keys starting with `scope`, see
+    /// [`Invalidation::Scope`].
+    fn invalidate_scope<'a>(&'a self, scope: &'a str) -> StoreFuture<'a, usize> {
+        self.invalidate(Invalidation::Scope(scope.to_owned()))
+    }
+
+    /// Removes the responses to `method` requests for the route `path`, see
+    /// [`Invalidation::Route`].
+    fn invalidate_route<'a>(&'a self, method: &'a Method, path: &'a str) -> StoreFuture<'a, usize> {
+        self.invalidate(Invalidation::Route {
+            method: method.clone(),
+            path: path.to_owned(),
+        })
+    }
+
+    /// Removes the responses stored before `at`, see [`Invalidation::OlderThan`].
+    fn invalidate_older_than(&self, at: DateTime<Utc>) -> StoreFuture<'_, usize> {
+        self.invalidate(Invalidation::OlderThan(at))
+    }
+
+    /// Figures describing what the store holds, for operators.
+    ///
+    /// The default only reports [`len`](Self::len), leaving the figures a backend cannot tell
+    /// cheaply unset.
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        Box::pin(async move {
+            Ok(StoreStats {
+                entries: self.len().await?,
+                ..StoreStats::default()
+            })
+        })
+    }
+
+    /// Checks that the backend can be reached, e.g. for a readiness probe.
+    ///
+    /// The default looks up a key that is never stored, so it fails whenever reads would.
+    /// Backends with a cheaper round trip, such as a ping, should use that instead.
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move { self.get(HEALTH_PROBE).await.map(|_| ()) })
+    }
+}
+
+impl<T: IdempotencyStore + ?Sized> IdempotencyStore for Arc<T> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        (**self).get(key)
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        (**self).get_streamed(key)
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        (**self).reserve(reservation)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        (**self).reserve_for(reservation, ttl)
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        (**self).abort(reservation)
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        (**self).commit(reservation, element)
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        (**self).remove(key)
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        (**self).is_empty()
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        (**self).clear()
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        (**self).purge_expired()
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        (**self).migrate()
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        (**self).invalidate(invalidation)
+    }
+
+    fn invalidate_scope<'a>(&'a self, scope: &'a str) -> StoreFuture<'a, usize> {
+        (**self).invalidate_scope(scope)
+    }
+
+    fn invalidate_route<'a>(&'a self, method: &'a Method, path: &'a str) -> StoreFuture<'a, usize> {
+        (**self).invalidate_route(method, path)
+    }
+
+    fn invalidate_older_than(&self, at: DateTime<Utc>) -> StoreFuture<'_, usize> {
+        (**self).invalidate_older_than(at)
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        (**self).maintenance_lock(duration)
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        (**self).maintenance_unlock()
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        (**self).maintenance_remaining()
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        (**self).stats()
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        (**self).health()
+    }
+}
+
+/// Which stored responses [`IdempotencyStore::invalidate`] removes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Invalidation {
+    /// Responses stored for keys starting with the given prefix, e.g. those of one tenant when
+    /// a [`KeyExtractor`](crate::KeyExtractor) prefixes keys with their tenant. With
+    /// [`KeyHashing`](crate::KeyHashing), only scopes kept by its
+    /// [`scope_separator`](crate::KeyHashing::scope_separator) match.
+    Scope(String),
+    /// Responses to requests with `method` for the [`route`](CacheElement::route) `path`, e.g.
+    /// `/orders/{id}`. Entries stored by earlier versions of this crate never match, as their
+    /// route is unknown.
+    Route { method: Method, path: String },
+    /// Responses stored before the given time.
+    OlderThan(DateTime<Utc>),
+}
+
+impl Invalidation {
+    /// Whether `element` is one of the responses to remove.
+    pub fn matches(&self, element: &CacheElement) -> bool {
+        match self {
+            Self::Scope(scope) => element.key().starts_with(scope.as_str()),
+            Self::Route { method, path } => {
+                element.method() == Some(method.as_str()) && element.route() == Some(path.as_str())
+            }
+            Self::OlderThan(at) => element.created_at() < *at,
+        }
+    }
+}
+
+/// Figures reported by [`IdempotencyStore::stats`]. Those a backend does not track are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct StoreStats {
+    /// Number of entries held, as reported by [`IdempotencyStore::len`].
+    pub entries: usize,
+    /// How many of the entries are reservations of requests still executing.
+    pub reservations: Option<usize>,
+    /// Approximate number of bytes taken up by the entries.
+    pub memory_bytes: Option<usize>,
+    /// Lookups that found a response since the store was created.
+    pub hits: Option<u64>,
+    /// Lookups that found nothing since the store was created.
+    pub misses: Option<u64>,
+    /// Age of the oldest response that has not expired.
+    pub oldest_entry_age: Option<Duration>,
+}
+
+impl StoreStats {
+    /// Share of lookups that found a response, if hits and misses are tracked and there were
+    /// any lookups.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let (hits, misses) = (self.hits?, self.misses?);
+        let lookups = hits + misses;
+
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The backend does not implement the requested operation.
+    Unsupported,
+    /// The backend failed to carry out the operation.
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+    /// The backend did not answer in time, see [`RetryStore`].
+    Timeout,
+    /// The backend kept failing, so the operation was not attempted, see
+    /// [`CircuitBreakerStore`].
+    CircuitOpen,
+    /// The response is larger than the backend keeps, so it was not stored and its key was
+    /// released, see [`MemoryStore::max_bytes`].
+    TooLarge,
+}
+
+impl StoreError {
+    /// Wraps an error raised by a backend's client library.
+    pub fn backend(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self::Backend(err.into())
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported => f.write_str("operation not supported by this idempotency store"),
+            Self::Backend(err) => write!(f, "idempotency store failure: {err}"),
+            Self::Timeout => f.write_str("idempotency store timed out"),
+            Self::CircuitOpen => f.write_str("idempotency store circuit is open"),
+            Self::TooLarge => f.write_str("response is too large for the idempotency store"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Unsupported | Self::Timeout | Self::CircuitOpen | Self::TooLarge => None,
+            Self::Backend(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+/// Body of a stored response returned by [`IdempotencyStore::get_streamed`], read from the store
+/// chunk by chunk as it is sent to the client.
+pub struct BodyStream {
+    len: u64,
+    chunks: Chunks,
+}
+
+enum Chunks {
+    Ready(Option<Bytes>),
+    Stream(BoxStream<'static, Result<Bytes, StoreError>>),
+}
+
+impl BodyStream {
+    /// A body of `len` bytes, read by polling `chunks`.
+    ///
+    /// The stream must yield exactly `len` bytes, or fail if the body vanished from the store
+    /// while it was being read, as the length is sent to the client up front.
+    pub fn new(
+        len: u64,
+        chunks: impl Stream<Item = Result<Bytes, StoreError>> + Send + 'static,
+    ) -> Self {
+        Self {
+            len,
+            chunks: Chunks::Stream(chunks.boxed()),
+        }
+    }
+
+    /// A body already read into memory.
+    pub fn ready(body: impl Into<Bytes>) -> Self {
+        let body = body.into();
+
+        Self {
+            len: body.len() as u64,
+            chunks: Chunks::Ready(Some(body)),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the body is in memory already, so that collecting it costs nothing.
+    pub fn is_ready(&self) -> bool {
+        matches!(self.chunks, Chunks::Ready(_))
+    }
+
+    /// Reads the rest of the body into memory. A body that is in memory already is handed out as
+    /// it is, without being copied.
+    pub async fn collect(self) -> Result<Bytes, StoreError> {
+        match self.chunks {
+            Chunks::Ready(body) => Ok(body.unwrap_or_default()),
+            Chunks::Stream(chunks) => {
+                let capacity = usize::try_from(self.len).unwrap_or_default();
+                let body = chunks
+                    .try_fold(
+                        BytesMut::with_capacity(capacity),
+                        |mut body, chunk| async move {
+                            body.extend_from_slice(&chunk);
+                            Ok(body)
+                        },
+                    )
+                    .await?;
+
+                Ok(body.freeze())
+            }
+        }
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.chunks {
+            Chunks::Ready(body) => Poll::Ready(body.take().map(Ok)),
+            Chunks::Stream(chunks) => chunks.poll_next_unpin(cx),
+        }
+    }
+}
+
+/// Deadline of a maintenance lock, for backends that keep it in process.
+#[derive(Default)]
+pub(crate) struct MaintenanceLock {
+    until: Mutex<Option<Instant>>,
+}
+
+impl MaintenanceLock {
+    pub(crate) fn lock(&self, duration: Duration) {
+        *self.until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    pub(crate) fn unlock(&self) {
+        *self.until.lock().unwrap() = None;
+    }
+
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        let mut until = self.until.lock().unwrap();
+
+        let remaining = until.and_then(|until| until.checked_duration_since(Instant::now()));
+        if remaining.is_none() {
+            *until = None;
+        }
+
+        remaining
+    }
+}
+
+/// A captured response, stored under the idempotency key of the request that produced it.
+///
+/// Only status, headers and body are captured. actix-web does not expose response trailers, so
+/// protocols relying on them, such as gRPC over HTTP/2, cannot be replayed; gRPC-web, which
+/// carries its trailers in the body, can.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheElement {
+    // entries written before keys were free-form strings still call it `token`
+    #[serde(alias = "token")]
+    key: String,
+    #[serde(with = "status_code")]
+    status: StatusCode,
+    headers: Vec<(String, Vec<u8>)>,
+    #[serde(with = "body")]
+    body: Bytes,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    fingerprint: Option<String>,
+    #[serde(default)]
+    compression: Option<Codec>,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default, with = "metadata")]
+    metadata: HashMap<String, Value>,
+    #[serde(default)]
+    key_header: Option<String>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    route: Option<String>,
+}
+
+impl CacheElement {
+    pub(crate) fn capture(
+        key: String,
+        response: &HttpResponse<()>,
+        body: Bytes,
+        ttl: chrono::Duration,
+        filter: &HeaderFilter,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let headers = filter.capture(response.headers());
+        let created_at = now;
+
+        Self {
+            key,
+            status: response.status(),
+            headers,
+            body,
+            created_at,
+            expires_at: created_at + ttl,
+            request_id: None,
+            fingerprint: None,
+            compression: None,
+            encrypted: false,
+            metadata: HashMap::new(),
+            key_header: None,
+            method: None,
+            route: None,
+        }
+    }
+
+    /// Records the id of the request that produced the response.
+    pub(crate) fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// Records the fingerprint of the request that produced the response.
+    pub(crate) fn with_fingerprint(mut self, fingerprint: Option<String>) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Records the header the key was read from.
+    pub(crate) fn with_key_header(mut self, key_header: Option<String>) -> Self {
+        self.key_header = key_header;
+        self
+    }
+
+    /// Records the method and route of the request that produced the response.
+    pub(crate) fn with_route(mut self, method: &Method, route: String) -> Self {
+        self.method = Some(method.as_str().to_owned());
+        self.route = Some(route);
+        self
+    }
+
+    /// Attaches what the handler recorded through [`IdempotencyMetadata`](crate::IdempotencyMetadata).
+    pub(crate) fn with_metadata(mut self, metadata: HashMap<String, Value>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Compresses the body if `compression` finds it worthwhile.
+    pub(crate) fn compress(mut self, compression: &Compression) -> Self {
+        let (body, codec) = compression.compress(std::mem::take(&mut self.body));
+        self.body = body;
+        self.compression = codec;
+        self
+    }
+
+    /// What is left of the entry once its response was evicted, kept until `expires_at`.
+    ///
+    /// The key, status, fingerprint, route and, unless encrypted, metadata remain, headers and
+    /// body are dropped.
+    pub(crate) fn tombstone(&self, expires_at: DateTime<Utc>) -> Self {
+        Self {
+            headers: vec![(TOMBSTONE.to_owned(), Vec::new())],
+            body: Bytes::new(),
+            expires_at,
+            compression: None,
+            encrypted: false,
+            ..self.clone()
+        }
+    }
+
+    /// Whether the entry only records that a response was stored for its key, which was evicted
+    /// since, see [`MemoryStore::tombstones`](crate::MemoryStore::tombstones). It has neither headers nor a body.
+    pub fn is_tombstone(&self) -> bool {
+        self.headers.iter().any(|(name, _)| name == TOMBSTONE)
+    }
+
+    /// Serializes the entry in `format`, for stores keeping entries outside the process.
+    pub fn encode(&self, format: WireFormat) -> Result<Vec<u8>, StoreError> {
+        wire::encode(self, format)
+    }
+
+    /// Reads back an entry written by [`encode`](Self::encode), in whichever format and by
+    /// whichever version of this crate.
+    pub fn decode(raw: &[u8]) -> Result<Self, StoreError> {
+        wire::decode(raw)
+    }
+
+    /// Replaces the headers, body, metadata, request id and key header with a ciphertext of
+    /// them all, authenticating what stays readable along with it.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn seal(mut self, encryption: &Encryption) -> io::Result<Self> {
+        let plaintext = serde_json::to_vec(&(
+            &self.headers,
+            self.body.as_ref(),
+            &self.metadata,
+            &self.request_id,
+            &self.key_header,
+        ))?;
+
+        self.encrypted = true;
+        self.body = encryption.encrypt(&self.bound()?, &plaintext)?.into();
+        self.headers = Vec::new();
+        self.metadata = HashMap::new();
+        self.request_id = None;
+        self.key_header = None;
+        Ok(self)
+    }
+
+    /// Restores what [`seal`](Self::seal) encrypted. Entries in plain text are refused unless
+    /// `encryption` accepts them, except for tombstones, which are never replayed.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn open(mut self, encryption: &Encryption) -> io::Result<Self> {
+        if !self.encrypted {
+            if self.is_tombstone() || encryption.accepts_plaintext() {
+                return Ok(self);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stored response is not encrypted",
+            ));
+        }
+
+        let plaintext = encryption.decrypt(&self.bound()?, &self.body)?;
+
+        let body: Vec<u8>;
+        (
+            self.headers,
+            body,
+            self.metadata,
+            self.request_id,
+            self.key_header,
+        ) = serde_json::from_slice(&plaintext)?;
+        self.body = body.into();
+        self.encrypted = false;
+        Ok(self)
+    }
+
+    // Everything a sealed entry keeps readable, as the associated data of its ciphertext, so
+    // that none of it can be changed without decryption failing. Versioned in case it grows.
+    #[cfg(feature = "encryption")]
+    fn bound(&self) -> io::Result<Vec<u8>> {
+        let bound = serde_json::to_vec(&(
+            1u8,
+            &self.key,
+            self.status.as_u16(),
+            self.created_at,
+            self.expires_at,
+            &self.fingerprint,
+            self.compression,
+            self.encrypted,
+            &self.method,
+            &self.route,
+        ))?;
+
+        Ok(bound)
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Stored headers. Values are kept as raw bytes since they need not be valid UTF-8.
+    pub fn headers(&self) -> &[(String, Vec<u8>)] {
+        &self.headers
+    }
+
+    /// The body as stored, i.e. compressed if [`compression`](Self::compression) says so.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Takes the body out of the entry, leaving it empty, e.g. to hand it out as a
+    /// [`BodyStream`] from [`IdempotencyStore::get_streamed`].
+    pub fn take_body(&mut self) -> Bytes {
+        std::mem::take(&mut self.body)
+    }
+
+    /// Puts back a body read separately from the rest of the entry.
+    pub(crate) fn set_body(&mut self, body: Bytes) {
+        self.body = body;
+    }
+
+    /// Removes the header named `name`, which need not be a valid header name, and returns its
+    /// value.
+    pub(crate) fn take_header(&mut self, name: &str) -> Option<Vec<u8>> {
+        let index = self.headers.iter().position(|(stored, _)| stored == name)?;
+        Some(self.headers.remove(index).1)
+    }
+
+    pub(crate) fn push_header(&mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) {
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// The body as it was sent to the client, shared with the entry unless it had to be
+    /// decompressed.
+    ///
+    /// Fails for encrypted entries, which the middleware decrypts before replaying them.
+    pub fn decoded_body(&self) -> io::Result<Bytes> {
+        if self.encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cannot decode an encrypted response body",
+            ));
+        }
+
+        match self.compression {
+            Some(codec) => compression::decompress(codec, &self.body).map(Bytes::from),
+            None => Ok(self.body.clone()),
+        }
+    }
+
+    /// Whether the entry is encrypted, in which case [`headers`](Self::headers) and
+    /// [`metadata`](Self::metadata) are empty and [`body`](Self::body) holds the ciphertext of
+    /// both, the body, the request id and the key header.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Algorithm the stored body was compressed with, if any.
+    pub fn compression(&self) -> Option<Codec> {
+        self.compression
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// Id of the request that produced the response, if the middleware was configured to record
+    /// one.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// Header the key was read from, if a [`HeaderKey`](crate::HeaderKey) read it from one.
+    pub fn key_header(&self) -> Option<&str> {
+        self.key_header.as_deref()
+    }
+
+    /// Method of the request that produced the response. Unknown for entries stored by earlier
+    /// versions of this crate.
+    pub fn method(&self) -> Option<&str> {
+        self.method.as_deref()
+    }
+
+    /// Pattern of the resource that produced the response, e.g. `/orders/{id}`, or the path of
+    /// the request if no pattern matched it. Unknown for entries stored by earlier versions of
+    /// this crate.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    /// Hash of the request that produced the response, if fingerprinting was enabled.
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    /// What the handler recorded about the request through
+    /// [`IdempotencyMetadata`](crate::IdempotencyMetadata).
+    pub fn metadata(&self) -> &HashMap<String, Value> {
+        &self.metadata
+    }
+
+    /// Approximate number of bytes the entry takes up in memory.
+    pub(crate) fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+
+        std::mem::size_of::<Self>()
+            + self.key.len()
+            + headers
+            + self.body.len()
+            + self.request_id.as_ref().map_or(0, String::len)
+            + self.fingerprint.as_ref().map_or(0, String::len)
+            + self.key_header.as_ref().map_or(0, String::len)
+            + self.method.as_ref().map_or(0, String::len)
+            + self.route.as_ref().map_or(0, String::len)
+            + self
+                .metadata
+                .iter()
+                .map(|(name, value)| name.len() + value.to_string().len())
+                .sum::<usize>()
+    }
+
+    /// The `Location` header of the stored response, if it kept one.
+    pub fn location(&self) -> Option<HeaderValue> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header::LOCATION.as_str()))
+            .and_then(|(_, value)| HeaderValue::from_bytes(value).ok())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+
+    /// Whether the entry has expired by `now`, for stores running on a [`Clock`](crate::Clock)
+    /// of their own.
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+
+    /// Rebuilds the stored response so it can be sent again.
+    ///
+    /// `Date` is set to when the response was originally produced and `Age` to how long ago that
+    /// was as of `now`, so clients can tell how stale a replay is.
+    ///
+    /// The body is streamed from `body` if given, which must hold it uncompressed, and taken from
+    /// the entry otherwise. Fails if the body cannot be decompressed.
+    pub(crate) fn to_response(
+        &self,
+        now: DateTime<Utc>,
+        body: Option<BodyStream>,
+    ) -> io::Result<HttpResponse> {
+        let body = match body {
+            Some(body) => BoxBody::new(SizedStream::new(body.len(), body)),
+            None => BoxBody::new(self.decoded_body()?),
+        };
+        let mut builder = HttpResponseBuilder::new(self.status);
+
+        for (name, value) in &self.headers {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_bytes(value),
+            ) {
+                // entries written by older versions may still hold them, and HTTP/2 clients
+                // reject responses carrying them
+                (Ok(name), Ok(_)) if headers::is_hop_by_hop(&name) => {}
+                (Ok(name), Ok(value)) => {
+                    builder.append_header((name, value));
+                }
+                _ => log::warn!(
+                    "skipping invalid header {name:?} while replaying idempotency key {}",
+                    self.key
+                ),
+            }
+        }
+
+        let age = (now - self.created_at).num_seconds().max(0);
+
+        builder
+            .insert_header((
+                header::DATE,
+                HttpDate::from(SystemTime::from(self.created_at)),
+            ))
+            .insert_header((header::AGE, age));
+
+        if let Some(request_id) = self
+            .request_id
+            .as_deref()
+            .and_then(|id| HeaderValue::from_str(id).ok())
+        {
+            builder.insert_header((ORIGINAL_REQUEST_ID, request_id));
+        }
+
+        Ok(builder.body(body))
+    }
+}
+
+mod status_code {
+    use actix_web::http::StatusCode;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(status.as_u16())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+        StatusCode::from_u16(u16::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+// Bodies are kept as `Bytes` to be shared rather than copied, but stored like the `Vec<u8>` they
+// used to be, so that entries stay readable across versions.
+mod body {
+    use actix_web::web::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(body.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(Bytes::from)
+    }
+}
+
+// Formats that are not self-describing, like bincode, cannot hold arbitrary JSON values, so they
+// get the metadata as a JSON string.
+mod metadata {
+    use std::collections::HashMap;
+
+    use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(
+        metadata: &HashMap<String, Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.collect_map(metadata);
+        }
+
+        let json = serde_json::to_string(metadata).map_err(S::Error::custom)?;
+        serializer.serialize_str(&json)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Value>, D::Error> {
+        if deserializer.is_human_readable() {
+            return HashMap::deserialize(deserializer);
+        }
+
+        let json = String::deserialize(deserializer)?;
+        serde_json::from_str(&json).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use actix_web::rt;
+
+    use super::*;
+
+    // How long a slow operation of `Faulty` takes.
+    pub(in crate::store) const SLOW: Duration = Duration::from_millis(100);
+
+    // A memory store whose next operations fail, or only answer after `SLOW` although they took
+    // effect right away, for as many operations as told to.
+    #[derive(Default)]
+    pub(in crate::store) struct Faulty {
+        pub(in crate::store) inner: MemoryStore,
+        failing: AtomicU32,
+        slow: AtomicU32,
+        calls: AtomicU32,
+    }
+
+    impl Faulty {
+        pub(in crate::store) fn fail(&self, operations: u32) {
+            self.failing.store(operations, Ordering::SeqCst);
+        }
+
+        pub(in crate::store) fn slow_down(&self, operations: u32) {
+            self.slow.store(operations, Ordering::SeqCst);
+        }
+
+        // how many operations reached the store
+        pub(in crate::store) fn calls(&self) -> u32 {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn run<'a, T: Send + 'a>(
+            &'a self,
+            op: impl FnOnce() -> StoreFuture<'a, T> + Send + 'a,
+        ) -> StoreFuture<'a, T> {
+            fn take(count: &AtomicU32) -> bool {
+                count
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok()
+            }
+
+            Box::pin(async move {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                if take(&self.failing) {
+                    return Err(StoreError::backend("injected failure"));
+                }
+
+                let slow = take(&self.slow);
+                let result = op().await;
+                if slow {
+                    rt::time::sleep(SLOW).await;
+                }
+                result
+            })
+        }
+    }
+
+    impl IdempotencyStore for Faulty {
+        fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+            self.run(move || self.inner.get(key))
+        }
+
+        fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+            self.run(move || self.inner.reserve(reservation))
+        }
+
+        fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+            self.run(move || self.inner.abort(reservation))
+        }
+
+        fn commit<'a>(
+            &'a self,
+            reservation: &'a Reservation,
+            element: CacheElement,
+        ) -> StoreFuture<'a, bool> {
+            self.run(move || self.inner.commit(reservation, element))
+        }
+
+        fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+            self.run(move || self.inner.remove(key))
+        }
+
+        fn len(&self) -> StoreFuture<'_, usize> {
+            self.run(move || self.inner.len())
+        }
+
+        fn clear(&self) -> StoreFuture<'_, ()> {
+            self.run(move || self.inner.clear())
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use moka::{
+    ops::compute::{CompResult, Op},
+    sync::Cache,
+    Expiry,
+};
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, MaintenanceLock, Reservation, StoreFuture,
+};
+
+#[derive(Clone)]
+enum Slot {
+    // with the token of the reservation and the deadline it lapses at, if any
+    Reserved {
+        token: Arc<str>,
+        deadline: Option<Instant>,
+    },
+    Complete(Arc<CacheElement>),
+}
+
+impl Slot {
+    fn reserved(reservation: &Reservation, deadline: Option<Instant>) -> Self {
+        Self::Reserved {
+            token: reservation.token().into(),
+            deadline,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            Self::Reserved { deadline, .. } => {
+                deadline.is_none_or(|deadline| deadline > Instant::now())
+            }
+            Self::Complete(element) => !element.is_expired(),
+        }
+    }
+
+    fn is_held_by(&self, reservation: &Reservation) -> bool {
+        matches!(self, Self::Reserved { token, .. } if **token == *reservation.token())
+    }
+}
+
+// Stored responses expire at their own deadline, reservations once they lapse if they do, and
+// otherwise only once they are released or replaced.
+struct SlotExpiry;
+
+impl SlotExpiry {
+    fn remaining(slot: &Slot) -> Option<Duration> {
+        match slot {
+            Slot::Reserved { deadline, .. } => {
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            }
+            Slot::Complete(element) => Some(
+                (element.expires_at() - Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+impl Expiry<String, Slot> for SlotExpiry {
+    fn expire_after_create(&self, _key: &String, slot: &Slot, _now: Instant) -> Option<Duration> {
+        Self::remaining(slot)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        slot: &Slot,
+        _now: Instant,
+        _current: Option<Duration>,
+    ) -> Option<Duration> {
+        Self::remaining(slot)
+    }
+}
+
+// Approximate number of bytes an entry occupies, which is what the capacity is measured in.
+fn weigh(key: &str, slot: &Slot) -> u32 {
+    let size = match slot {
+        Slot::Reserved { token, .. } => token.len(),
+        Slot::Complete(element) => {
+            element.body().len()
+                + element
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| name.len() + value.len())
+                    .sum::<usize>()
+        }
+    };
+
+    u32::try_from(key.len() + size + std::mem::size_of::<CacheElement>()).unwrap_or(u32::MAX)
+}
+
+fn stored(result: CompResult<String, Slot>) -> bool {
+    matches!(
+        result,
+        CompResult::Inserted(_) | CompResult::ReplacedWith(_)
+    )
+}
+
+/// In-memory store built on [moka](https://docs.rs/moka), for high request rates.
+///
+/// Unlike [`MemoryStore`](super::MemoryStore) it does not serialize every request on a single
+/// lock: reads are lock-free, entries expire on their own and the cache is bounded by an
+/// approximate size in bytes, evicting the least valuable entries once it is full. Under that
+/// pressure even reservations of requests still executing may be evicted, so size the cache
+/// generously.
+///
+/// Requires the `moka` feature.
+pub struct MokaStore {
+    cache: Cache<String, Slot>,
+    maintenance: MaintenanceLock,
+}
+
+impl MokaStore {
+    /// Store holding roughly `max_bytes` worth of responses.
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            cache: Cache::builder()
+                .max_capacity(max_bytes)
+                .weigher(|key: &String, slot: &Slot| weigh(key, slot))
+                .expire_after(SlotExpiry)
+                .build(),
+            maintenance: MaintenanceLock::default(),
+        }
+    }
+
+    // claims the key unless it holds a response or another reservation that has not lapsed
+    fn reserve_until(&self, reservation: &Reservation, deadline: Option<Instant>) -> bool {
+        stored(self.cache.entry_by_ref(reservation.key()).and_compute_with(
+            |current| match current {
+                Some(current)
+                    if current.value().is_live() && !current.value().is_held_by(reservation) =>
+                {
+                    Op::Nop
+                }
+                _ => Op::Put(Slot::reserved(reservation, deadline)),
+            },
+        ))
+    }
+}
+
+impl IdempotencyStore for MokaStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        let element = match self.cache.get(key) {
+            Some(Slot::Complete(element)) if !element.is_expired() => {
+                Some(CacheElement::clone(&element))
+            }
+            _ => None,
+        };
+
+        Box::pin(async move { Ok(element) })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        let reserved = self.reserve_until(reservation, None);
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        let reserved = self.reserve_until(reservation, Instant::now().checked_add(ttl));
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        self.cache
+            .entry_by_ref(reservation.key())
+            .and_compute_with(|current| match current {
+                Some(current) if current.value().is_held_by(reservation) => Op::Remove,
+                _ => Op::Nop,
+            });
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        let key = element.key().to_owned();
+        let slot = Slot::Complete(Arc::new(element));
+
+        let inserted = stored(
+            self.cache
+                .entry(key)
+                .and_compute_with(|current| match current {
+                    Some(current)
+                        if current.value().is_live()
+                            && !current.value().is_held_by(reservation) =>
+                    {
+                        Op::Nop
+                    }
+                    _ => Op::Put(slot),
+                }),
+        );
+
+        Box::pin(async move { Ok(inserted) })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        let removed = self.cache.remove(key).is_some();
+
+        Box::pin(async move { Ok(removed) })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        // the count is only exact once pending evictions have been applied
+        self.cache.run_pending_tasks();
+        let len = usize::try_from(self.cache.entry_count()).unwrap_or(usize::MAX);
+
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.cache.invalidate_all();
+
+        Box::pin(async { Ok(()) })
+    }
+
+    // entries are kept as values, not in any layout
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        let matching: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(_, slot)| {
+                matches!(slot, Slot::Complete(element) if invalidation.matches(element))
+            })
+            .map(|(key, _)| key)
+            .collect();
+
+        let mut invalidated = 0;
+        for key in matching {
+            // the entry may have been replaced since
+            let removed = self
+                .cache
+                .entry_by_ref(key.as_str())
+                .and_compute_with(|current| match current {
+                    Some(current)
+                        if matches!(current.value(), Slot::Complete(element) if invalidation.matches(element)) =>
+                    {
+                        Op::Remove
+                    }
+                    _ => Op::Nop,
+                });
+            invalidated += usize::from(matches!(removed, CompResult::Removed(_)));
+        }
+
+        Box::pin(async move { Ok(invalidated) })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.maintenance.lock(duration);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.maintenance.unlock();
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        let remaining = self.maintenance.remaining();
+
+        Box::pin(async move { Ok(remaining) })
+    }
+}
@@ -0,0 +1,266 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::{
+    BodyStream, CacheElement, IdempotencyStore, Invalidation, Reservation, StoreFuture, StoreStats,
+};
+
+const DEFAULT_THRESHOLD: usize = 1024 * 1024;
+
+// Names the object holding an offloaded body. Header names cannot start with a colon, so no
+// stored response can carry it.
+const OFFLOADED: &str = ":offloaded";
+
+/// Object storage that [`OffloadStore`] keeps large response bodies in, such as
+/// [`S3BlobStore`](crate::S3BlobStore).
+pub trait BlobStore: Send + Sync {
+    /// Stores `body` as the object `name`, needed until `expires_at`.
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        body: Bytes,
+        expires_at: DateTime<Utc>,
+    ) -> StoreFuture<'a, ()>;
+
+    /// Reads the object `name`, or `None` if it does not exist (anymore).
+    fn get<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Option<BodyStream>>;
+
+    /// Deletes the object `name`, if it exists.
+    fn delete<'a>(&'a self, name: &'a str) -> StoreFuture<'a, ()>;
+
+    /// Checks that the storage is reachable. Succeeds without checking anything by default.
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl<T: BlobStore + ?Sized> BlobStore for Arc<T> {
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        body: Bytes,
+        expires_at: DateTime<Utc>,
+    ) -> StoreFuture<'a, ()> {
+        (**self).put(name, body, expires_at)
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Option<BodyStream>> {
+        (**self).get(name)
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> StoreFuture<'a, ()> {
+        (**self).delete(name)
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        (**self).health()
+    }
+}
+
+/// Store keeping entries in another store, except for bodies of at least
+/// [`threshold`](Self::threshold) bytes, which go to a [`BlobStore`] instead.
+///
+/// Keeps large responses like generated documents from bloating a store such as Redis or MySQL,
+/// which then only holds a reference to the object. Replays stream the body straight from the
+/// object storage.
+///
+/// Objects are deleted along with their entry by [`remove`](IdempotencyStore::remove), but not
+/// when the entry expires or the store is cleared, so the object storage has to expire them on
+/// its own, e.g. through a lifecycle rule. Entries whose object is gone are treated as expired.
+///
+/// ```
+/// use actix_web_idempotency::{BlobStore, IdempotencyStore, OffloadStore};
+///
+/// fn hybrid(shared: impl IdempotencyStore, objects: impl BlobStore) -> impl IdempotencyStore {
+///     OffloadStore::new(shared, objects).threshold(256 * 1024)
+/// }
+/// ```
+pub struct OffloadStore<S, B> {
+    inner: S,
+    blobs: B,
+    threshold: usize,
+}
+
+impl<S: IdempotencyStore, B: BlobStore> OffloadStore<S, B> {
+    pub fn new(inner: S, blobs: B) -> Self {
+        Self {
+            inner,
+            blobs,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Size from which bodies are offloaded, as stored, i.e. after compression. Defaults to
+    /// 1 MiB, and is raised to 1 if 0, so that empty bodies are never offloaded.
+    pub fn threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes.max(1);
+        self
+    }
+
+    /// The store keeping the entries.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The storage keeping the offloaded bodies.
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+
+    // Splits the reference to an offloaded body off `element`.
+    fn object(element: &mut CacheElement) -> Option<String> {
+        let name = element.take_header(OFFLOADED)?;
+        Some(String::from_utf8_lossy(&name).into_owned())
+    }
+}
+
+impl<S: IdempotencyStore, B: BlobStore> IdempotencyStore for OffloadStore<S, B> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            let Some((mut element, body)) = self.get_streamed(key).await? else {
+                return Ok(None);
+            };
+
+            element.set_body(body.collect().await?);
+            Ok(Some(element))
+        })
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        Box::pin(async move {
+            let Some((mut element, body)) = self.inner.get_streamed(key).await? else {
+                return Ok(None);
+            };
+
+            let Some(name) = Self::object(&mut element) else {
+                return Ok(Some((element, body)));
+            };
+
+            match self.blobs.get(&name).await? {
+                Some(body) => Ok(Some((element, body))),
+                None => {
+                    log::warn!("body of the response to idempotency key {key} is gone");
+                    Ok(None)
+                }
+            }
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.inner.reserve(reservation)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        self.inner.reserve_for(reservation, ttl)
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        self.inner.abort(reservation)
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            if element.body().len() < self.threshold {
+                return self.inner.commit(reservation, element).await;
+            }
+
+            let mut element = element;
+            let name = Uuid::new_v4().to_string();
+            self.blobs
+                .put(&name, element.take_body(), element.expires_at())
+                .await?;
+            element.push_header(OFFLOADED, name.as_str());
+
+            let key = element.key().to_owned();
+            let inserted = self.inner.commit(reservation, element).await;
+
+            // nothing refers to the object unless the entry made it into the store
+            if !matches!(inserted, Ok(true)) {
+                if let Err(err) = self.blobs.delete(&name).await {
+                    log::warn!("failed to delete unused body for idempotency key {key}: {err}");
+                }
+            }
+
+            inserted
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let name = self
+                .inner
+                .get(key)
+                .await?
+                .and_then(|mut element| Self::object(&mut element));
+
+            let removed = self.inner.remove(key).await?;
+            if let Some(name) = name {
+                self.blobs.delete(&name).await?;
+            }
+
+            Ok(removed)
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        self.inner.is_empty()
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.inner.clear()
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        self.inner.purge_expired()
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        self.inner.migrate()
+    }
+
+    // the objects of removed entries are left to expire on their own
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        self.inner.invalidate(invalidation)
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.inner.maintenance_lock(duration)
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.inner.maintenance_unlock()
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        self.inner.maintenance_remaining()
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        self.inner.stats()
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            futures_util::future::try_join(self.inner.health(), self.blobs.health()).await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,571 @@
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use chrono::Utc;
+use futures_util::stream;
+use redis::{aio::ConnectionManager, Client, Script};
+
+use super::{
+    BodyStream, CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture,
+    WireFormat,
+};
+
+const DEFAULT_PREFIX: &str = "idempotency:";
+
+// Entries live below `{prefix}key:`, so that no idempotency key can collide with the maintenance
+// lock kept at `{prefix}maintenance`.
+const ENTRY: &str = "key:";
+
+// Bodies kept apart from their entries live below `{prefix}body:`.
+const BODY: &str = "body:";
+
+// Marks entries whose body is kept under its own key, so it can be read in chunks.
+const CHUNKED: &str = "chunked:";
+
+// How much of a separately kept body is read at once while replaying it.
+const CHUNK: usize = 256 * 1024;
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Reservations are stored under the same key as the response that later replaces them, so that a
+// single write decides which instance gets to execute the request. They hold the token of their
+// reservation.
+const RESERVATION: &str = "reserved:";
+
+// Takes the key unless it holds anything but our own reservation, which a retry finds there if
+// its first attempt got through.
+const RESERVE: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current and current ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return 1
+";
+
+// Replaces our reservation with the completed response. Anything else found under the key, be it
+// a stored response or the reservation of a request that took over after ours timed out, wins.
+// A body kept apart from the entry is written along with it.
+const COMMIT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current and current ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+if ARGV[4] then
+    redis.call('SET', KEYS[2], ARGV[4], 'PX', ARGV[3])
+end
+return 1
+";
+
+// Deletes the reservation only if it is still ours.
+const RELEASE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+// Rewrites an entry in another layout, unless it changed in the meantime. It keeps its expiry.
+const MIGRATE: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+local ttl = redis.call('PTTL', KEYS[1])
+if ttl <= 0 then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ttl)
+return 1
+";
+
+// Deletes an entry along with its body, unless it changed in the meantime.
+const INVALIDATE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1], KEYS[2])
+end
+return 0
+";
+
+/// Store keeping responses in Redis, shared by every instance connected to it.
+///
+/// Keys are reserved with a conditional `SET PX`, so only one instance executes a given request.
+/// A reservation expires after [`lock_timeout`](Self::lock_timeout) in case its holder dies, and
+/// is released as soon as the request completes. A request still running once its reservation
+/// expired and another one took the key over can neither release nor overwrite the new one. Stored responses expire through Redis' own TTLs.
+///
+/// Large bodies can be kept apart from the rest of the entry with
+/// [`streamed_bodies`](Self::streamed_bodies), so that they are replayed in chunks instead of
+/// being read into memory at once.
+///
+/// Requires the `redis` feature.
+pub struct RedisStore {
+    conn: ConnectionManager,
+    prefix: String,
+    lock_timeout: Duration,
+    format: WireFormat,
+    streamed_bodies: Option<usize>,
+    reserve: Script,
+    commit: Script,
+    release: Script,
+    migrate: Script,
+    invalidate: Script,
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub async fn open(url: &str) -> Result<Self, StoreError> {
+        let client = Client::open(url).map_err(StoreError::backend)?;
+        let conn = ConnectionManager::new(client)
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(Self::new(conn))
+    }
+
+    pub fn new(conn: ConnectionManager) -> Self {
+        Self {
+            conn,
+            prefix: DEFAULT_PREFIX.to_owned(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            format: WireFormat::default(),
+            streamed_bodies: None,
+            reserve: Script::new(RESERVE),
+            commit: Script::new(COMMIT),
+            release: Script::new(RELEASE),
+            migrate: Script::new(MIGRATE),
+            invalidate: Script::new(INVALIDATE),
+        }
+    }
+
+    /// Prefix prepended to every key. Defaults to `idempotency:`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// How long a reservation is held before another instance may take the key over. Defaults
+    /// to 30 seconds.
+    ///
+    /// This has to comfortably exceed the slowest handler, otherwise a retry arriving while the
+    /// original request is still running gets executed a second time. A middleware's
+    /// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout) takes its place for the
+    /// reservations it makes.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Encoding of the entries written from now on. Defaults to [`WireFormat::Json`].
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Keeps bodies of at least `min_size` bytes under a key of their own, from where they are
+    /// replayed in chunks of 256 KiB rather than read into memory in full. Off by default.
+    ///
+    /// Only bodies that are neither compressed nor encrypted are streamed, others are still read
+    /// in full before they are replayed.
+    pub fn streamed_bodies(mut self, min_size: usize) -> Self {
+        self.streamed_bodies = Some(min_size);
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{ENTRY}{key}", self.prefix)
+    }
+
+    fn body_key(&self, key: &str) -> String {
+        format!("{}{BODY}{key}", self.prefix)
+    }
+
+    fn maintenance_key(&self) -> String {
+        format!("{}maintenance", self.prefix)
+    }
+
+    // Reads the entry under `key`, and for entries keeping their body apart the length of that
+    // body, which is still to be read.
+    async fn entry(&self, key: &str) -> Result<Option<(CacheElement, Option<u64>)>, StoreError> {
+        let mut conn = self.conn.clone();
+
+        let raw: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(self.key(key))
+            .query_async(&mut conn)
+            .await
+            .map_err(StoreError::backend)?;
+
+        let Some(raw) = raw.filter(|raw| !raw.starts_with(RESERVATION.as_bytes())) else {
+            return Ok(None);
+        };
+
+        let Some(raw) = raw.strip_prefix(CHUNKED.as_bytes()) else {
+            let element = CacheElement::decode(&raw)?;
+            return Ok(Some((element, None)).filter(|(element, _)| !element.is_expired()));
+        };
+
+        let element = CacheElement::decode(raw)?;
+        if element.is_expired() {
+            return Ok(None);
+        }
+
+        // zero once the body key expired or was removed
+        let len: u64 = redis::cmd("STRLEN")
+            .arg(self.body_key(key))
+            .query_async(&mut conn)
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(Some((element, Some(len))).filter(|_| len > 0))
+    }
+
+    // every key matching `pattern` below the prefix
+    async fn keys(&self, pattern: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.conn.clone();
+
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}{pattern}*", self.prefix))
+                .query_async(&mut conn)
+                .await
+                .map_err(StoreError::backend)?;
+
+            keys.extend(batch);
+
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+}
+
+// glob matching `literal` and nothing else, for `SCAN MATCH`
+fn escape_glob(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// `PX` rejects zero, so the shortest expiry we can ask for is one millisecond.
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1)
+}
+
+// what is stored under the key while `reservation` holds it
+fn owner(reservation: &Reservation) -> String {
+    format!("{RESERVATION}{}", reservation.token())
+}
+
+impl IdempotencyStore for RedisStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            let Some((mut element, body)) = self.get_streamed(key).await? else {
+                return Ok(None);
+            };
+
+            element.set_body(body.collect().await?);
+            Ok(Some(element))
+        })
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        Box::pin(async move {
+            let Some((mut element, len)) = self.entry(key).await? else {
+                return Ok(None);
+            };
+
+            let Some(len) = len else {
+                let body = element.take_body();
+                return Ok(Some((element, BodyStream::ready(body))));
+            };
+
+            let conn = self.conn.clone();
+            let body_key = self.body_key(key);
+
+            let chunks = stream::try_unfold(0u64, move |start| {
+                let mut conn = conn.clone();
+                let body_key = body_key.clone();
+
+                async move {
+                    if start >= len {
+                        return Ok(None);
+                    }
+
+                    let end = (start + CHUNK as u64).min(len);
+                    let chunk: Vec<u8> = redis::cmd("GETRANGE")
+                        .arg(&body_key)
+                        .arg(start)
+                        .arg(end - 1)
+                        .query_async(&mut conn)
+                        .await
+                        .map_err(StoreError::backend)?;
+
+                    if chunk.len() as u64 != end - start {
+                        return Err(StoreError::backend(
+                            "stored body expired while being replayed",
+                        ));
+                    }
+
+                    Ok(Some((Bytes::from(chunk), end)))
+                }
+            });
+
+            Ok(Some((element, BodyStream::new(len, chunks))))
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.reserve_for(reservation, self.lock_timeout)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let reserved: i64 = self
+                .reserve
+                .key(self.key(reservation.key()))
+                .arg(owner(reservation))
+                .arg(millis(ttl))
+                .invoke_async(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(reserved == 1)
+        })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.release
+                .key(self.key(reservation.key()))
+                .arg(owner(reservation))
+                .invoke_async::<i64>(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let mut element = element;
+            let ttl = (element.expires_at() - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+
+            let mut commit = self.commit.key(self.key(element.key()));
+            commit.key(self.body_key(element.key()));
+            commit.arg(owner(reservation));
+
+            // empty bodies cannot be told apart from expired ones, and need no streaming anyway
+            let apart = self
+                .streamed_bodies
+                .is_some_and(|min| !element.body().is_empty() && element.body().len() >= min);
+
+            if apart {
+                let body = element.take_body();
+                let mut encoded = CHUNKED.as_bytes().to_vec();
+                encoded.extend(element.encode(self.format)?);
+                commit.arg(encoded).arg(millis(ttl)).arg(body.as_ref());
+            } else {
+                commit.arg(element.encode(self.format)?).arg(millis(ttl));
+            }
+
+            let inserted: i64 = commit
+                .invoke_async(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(inserted == 1)
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let removed: u64 = redis::cmd("DEL")
+                .arg(self.key(key))
+                .arg(self.body_key(key))
+                .query_async(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(removed > 0)
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move { Ok(self.keys(ENTRY).await?.len()) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let mut keys = self.keys(ENTRY).await?;
+            keys.extend(self.keys(BODY).await?);
+
+            if !keys.is_empty() {
+                redis::cmd("DEL")
+                    .arg(keys)
+                    .query_async::<()>(&mut self.conn.clone())
+                    .await
+                    .map_err(StoreError::backend)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let mut migrated = 0;
+
+            for key in self.keys(ENTRY).await? {
+                let raw: Option<Vec<u8>> = redis::cmd("GET")
+                    .arg(&key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                let Some(raw) = raw.filter(|raw| !raw.starts_with(RESERVATION.as_bytes())) else {
+                    continue;
+                };
+
+                // entries keeping their body apart keep their marker
+                let (marker, encoded) = match raw.strip_prefix(CHUNKED.as_bytes()) {
+                    Some(encoded) => (CHUNKED.as_bytes(), encoded),
+                    None => (&[][..], &raw[..]),
+                };
+                let Some((_, encoded)) = self.format.upgrade(encoded)? else {
+                    continue;
+                };
+                let upgraded = [marker, &encoded].concat();
+
+                let rewritten: i64 = self
+                    .migrate
+                    .key(&key)
+                    .arg(&raw)
+                    .arg(upgraded)
+                    .invoke_async(&mut conn)
+                    .await
+                    .map_err(StoreError::backend)?;
+                migrated += usize::from(rewritten == 1);
+            }
+
+            Ok(migrated)
+        })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let entries = format!("{}{ENTRY}", self.prefix);
+
+            let pattern = match &invalidation {
+                Invalidation::Scope(scope) => format!("{ENTRY}{}", escape_glob(scope)),
+                _ => ENTRY.to_owned(),
+            };
+
+            let mut invalidated = 0;
+            for key in self.keys(&pattern).await? {
+                let raw: Option<Vec<u8>> = redis::cmd("GET")
+                    .arg(&key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(StoreError::backend)?;
+
+                let Some(raw) = raw.filter(|raw| !raw.starts_with(RESERVATION.as_bytes())) else {
+                    continue;
+                };
+
+                let encoded = raw.strip_prefix(CHUNKED.as_bytes()).unwrap_or(&raw);
+                if !invalidation.matches(&CacheElement::decode(encoded)?) {
+                    continue;
+                }
+
+                let removed: i64 = self
+                    .invalidate
+                    .key(&key)
+                    .key(self.body_key(key.strip_prefix(&entries).unwrap_or(&key)))
+                    .arg(&raw)
+                    .invoke_async(&mut conn)
+                    .await
+                    .map_err(StoreError::backend)?;
+                invalidated += usize::from(removed > 0);
+            }
+
+            Ok(invalidated)
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            redis::cmd("SET")
+                .arg(self.maintenance_key())
+                .arg(1)
+                .arg("PX")
+                .arg(millis(duration))
+                .query_async::<()>(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            redis::cmd("DEL")
+                .arg(self.maintenance_key())
+                .query_async::<()>(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)
+        })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            // negative when the key does not exist or has no expiry
+            let remaining: i64 = redis::cmd("PTTL")
+                .arg(self.maintenance_key())
+                .query_async(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(u64::try_from(remaining)
+                .ok()
+                .filter(|&remaining| remaining > 0)
+                .map(Duration::from_millis))
+        })
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            redis::cmd("PING")
+                .query_async::<()>(&mut self.conn.clone())
+                .await
+                .map_err(StoreError::backend)
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use actix_web::rt;
+use futures_util::StreamExt;
+use redis::{
+    aio::{ConnectionManager, PubSub},
+    AsyncCommands, Client, RedisResult,
+};
+
+use super::{BusHandler, BusMessage, InvalidationBus, StoreError, StoreFuture};
+
+const DEFAULT_CHANNEL: &str = "idempotency:invalidations";
+
+// How long to wait before subscribing again after the connection dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// [`InvalidationBus`] publishing through Redis pub/sub, e.g. the server a
+/// [`RedisStore`](crate::RedisStore) behind a [`TieredStore`](crate::TieredStore) already uses.
+///
+/// ```no_run
+/// use actix_web_idempotency::{RedisInvalidationBus, RedisStore, StoreError, TieredStore};
+///
+/// # async fn run() -> Result<(), StoreError> {
+/// let url = "redis://127.0.0.1/";
+/// let store = TieredStore::new(RedisStore::open(url).await?)
+///     .invalidation_bus(RedisInvalidationBus::open(url).await?);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Subscribers listen on a connection of their own, on the runtime of the worker that first
+/// uses the store, and subscribe again whenever it drops. Messages published while it was down
+/// are missed.
+///
+/// Requires the `redis` feature.
+pub struct RedisInvalidationBus {
+    client: Client,
+    conn: ConnectionManager,
+    channel: String,
+}
+
+impl RedisInvalidationBus {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub async fn open(url: &str) -> Result<Self, StoreError> {
+        let client = Client::open(url).map_err(StoreError::backend)?;
+        let conn = ConnectionManager::new(client.clone())
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(Self {
+            client,
+            conn,
+            channel: DEFAULT_CHANNEL.to_owned(),
+        })
+    }
+
+    /// Channel messages are published on. Defaults to `idempotency:invalidations`.
+    pub fn channel(mut self, channel: impl Into<String>) -> Self {
+        self.channel = channel.into();
+        self
+    }
+}
+
+impl InvalidationBus for RedisInvalidationBus {
+    fn publish(&self, message: BusMessage) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let _: () = conn
+                .publish(&self.channel, message.encode())
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn subscribe(&self, handler: BusHandler) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            // the first subscription fails loudly, later ones are retried
+            let mut pubsub = subscribe(&self.client, &self.channel)
+                .await
+                .map_err(StoreError::backend)?;
+
+            let client = self.client.clone();
+            let channel = self.channel.clone();
+            rt::spawn(async move {
+                loop {
+                    let mut messages = pubsub.into_on_message();
+                    while let Some(message) = messages.next().await {
+                        match BusMessage::decode(message.get_payload_bytes()) {
+                            Some(message) => handler(message),
+                            None => log::warn!("ignoring unknown message on {channel}"),
+                        }
+                    }
+
+                    log::warn!("lost subscription to {channel}, subscribing again");
+                    pubsub = loop {
+                        rt::time::sleep(RESUBSCRIBE_DELAY).await;
+
+                        match subscribe(&client, &channel).await {
+                            Ok(pubsub) => break pubsub,
+                            Err(err) => log::warn!("failed to subscribe to {channel}: {err}"),
+                        }
+                    };
+                }
+            });
+
+            Ok(())
+        })
+    }
+}
+
+async fn subscribe(client: &Client, channel: &str) -> RedisResult<PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    Ok(pubsub)
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt;
+
+use super::{
+    BodyStream, CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture,
+    StoreStats,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+const DEFAULT_RETRIES: u32 = 2;
+
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(25);
+
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(2);
+
+/// Store bounding how long every operation on another store may take, so that a slow backend
+/// cannot hold up requests indefinitely.
+///
+/// Each attempt is given up after [`timeout`](Self::timeout) and failed attempts are retried up
+/// to [`retries`](Self::retries) times, waiting a jittered, exponentially growing
+/// [`backoff`](Self::backoff) in between. Once the [`deadline`](Self::deadline) of an operation
+/// passes, it fails with [`StoreError::Timeout`] and the middleware's
+/// [`failure_mode`](crate::IdempotencyBuilder::failure_mode) decides how the request is answered.
+///
+/// An attempt that timed out may still have reached the store. A retried reservation recognises
+/// its own claim, and a retried commit finding a response in its way checks whether it is the
+/// one an earlier attempt stored, so neither is mistaken for another request holding the key.
+/// Unsupported operations are not retried, nor those turned away by a
+/// [`CircuitBreakerStore`](crate::CircuitBreakerStore).
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use actix_web_idempotency::{
+///     ConfigError, FailureMode, Idempotency, IdempotencyStore, RetryStore,
+/// };
+///
+/// fn idempotency(shared: impl IdempotencyStore + 'static) -> Result<Idempotency, ConfigError> {
+///     Idempotency::builder()
+///         .store(RetryStore::new(shared).timeout(Duration::from_millis(100)))
+///         .failure_mode(FailureMode::Open)
+///         .build()
+/// }
+/// ```
+pub struct RetryStore<S> {
+    inner: S,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    deadline: Duration,
+    jitter: RandomState,
+}
+
+impl<S: IdempotencyStore> RetryStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            deadline: DEFAULT_DEADLINE,
+            jitter: RandomState::new(),
+        }
+    }
+
+    /// How long a single attempt may take. Defaults to 500 ms.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How often a failed attempt is retried. Defaults to 2, and 0 disables retries.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry, doubling for every further one. Defaults to
+    /// 25 ms.
+    ///
+    /// Each wait is picked at random between half and all of that, so that requests failing
+    /// together do not retry in lockstep.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How long an operation may take across all its attempts and waits. Defaults to 2 seconds.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The store operations are forwarded to.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn run<'a, T: Send + 'a>(
+        &'a self,
+        op: impl Fn() -> StoreFuture<'a, T> + Send + 'a,
+    ) -> StoreFuture<'a, T> {
+        Box::pin(async move {
+            let deadline = Instant::now() + self.deadline;
+            let mut attempt = 0;
+
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let err = match rt::time::timeout(self.timeout.min(remaining), op()).await {
+                    Ok(Ok(value)) => return Ok(value),
+                    Ok(Err(err)) => err,
+                    Err(_) => StoreError::Timeout,
+                };
+
+                let retryable = match err {
+                    StoreError::Unsupported | StoreError::CircuitOpen | StoreError::TooLarge => {
+                        false
+                    }
+                    StoreError::Timeout | StoreError::Backend(_) => true,
+                };
+                if !retryable || attempt >= self.retries {
+                    return Err(err);
+                }
+
+                let wait = self.wait(attempt);
+                if Instant::now() + wait >= deadline {
+                    return Err(StoreError::Timeout);
+                }
+
+                log::debug!("retrying idempotency store operation after {wait:?}: {err}");
+                rt::time::sleep(wait).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    // Between half and all of the backoff for the `attempt`th retry.
+    fn wait(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.deadline);
+        let random = self.jitter.hash_one(Instant::now()) as f64 / u64::MAX as f64;
+
+        backoff.mul_f64(0.5 + random / 2.0)
+    }
+}
+
+impl<S: IdempotencyStore> IdempotencyStore for RetryStore<S> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        self.run(move || self.inner.get(key))
+    }
+
+    // only covers getting hold of the body, not reading it
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        self.run(move || self.inner.get_streamed(key))
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.reserve(reservation))
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.reserve_for(reservation, ttl))
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        self.run(move || self.inner.abort(reservation))
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let attempts = AtomicU32::new(0);
+            let inserted = self
+                .run(|| {
+                    attempts.fetch_add(1, Ordering::Relaxed);
+                    self.inner.commit(reservation, element.clone())
+                })
+                .await?;
+            if inserted || attempts.into_inner() == 1 {
+                return Ok(inserted);
+            }
+
+            // an attempt that seemed to fail may have stored the response after all
+            let stored = self.run(|| self.inner.get(element.key())).await?;
+            Ok(stored.is_some_and(|stored| {
+                stored.created_at() == element.created_at()
+                    && stored.status() == element.status()
+                    && stored.fingerprint() == element.fingerprint()
+            }))
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        self.run(move || self.inner.remove(key))
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.len())
+    }
+
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        self.run(move || self.inner.is_empty())
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.clear())
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.purge_expired())
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.migrate())
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        self.run(move || self.inner.invalidate(invalidation.clone()))
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.maintenance_lock(duration))
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.maintenance_unlock())
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        self.run(move || self.inner.maintenance_remaining())
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        self.run(move || self.inner.stats())
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        self.run(move || self.inner.health())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        tests::{Faulty, SLOW},
+        wire::tests::element,
+    };
+
+    fn retrying() -> RetryStore<Faulty> {
+        RetryStore::new(Faulty::default())
+            .timeout(SLOW / 4)
+            .backoff(Duration::from_millis(1))
+    }
+
+    #[actix_web::test]
+    async fn failed_attempts_are_retried() {
+        let store = retrying().retries(2);
+
+        store.inner().fail(2);
+        assert!(store.reserve(&Reservation::new("key")).await.unwrap());
+        assert_eq!(store.inner().calls(), 3);
+
+        store.inner().fail(3);
+        assert!(matches!(
+            store.get("key").await,
+            Err(StoreError::Backend(_))
+        ));
+        assert_eq!(store.inner().calls(), 6);
+    }
+
+    #[actix_web::test]
+    async fn no_retries_with_zero_retries() {
+        let store = retrying().retries(0);
+
+        store.inner().fail(1);
+        assert!(matches!(
+            store.get("key").await,
+            Err(StoreError::Backend(_))
+        ));
+        assert_eq!(store.inner().calls(), 1);
+
+        store.inner().slow_down(1);
+        assert!(matches!(store.get("key").await, Err(StoreError::Timeout)));
+        assert_eq!(store.inner().calls(), 2);
+    }
+
+    #[actix_web::test]
+    async fn retries_stop_at_the_deadline() {
+        let store = retrying()
+            .retries(100)
+            .backoff(Duration::from_millis(10))
+            .deadline(Duration::from_millis(50));
+
+        store.inner().fail(100);
+        let started = Instant::now();
+        assert!(matches!(store.get("key").await, Err(StoreError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(store.inner().calls() < 5);
+    }
+
+    #[actix_web::test]
+    async fn timed_out_commit_that_was_stored_counts_as_inserted() {
+        let store = retrying();
+        let reservation = Reservation::new("key");
+        assert!(store.reserve(&reservation).await.unwrap());
+
+        store.inner().slow_down(1);
+        assert!(store.commit(&reservation, element("key")).await.unwrap());
+        // the slow commit, the one retrying it and the lookup telling both apart
+        assert_eq!(store.inner().calls(), 4);
+    }
+
+    #[actix_web::test]
+    async fn retried_commit_losing_the_key_is_not_inserted() {
+        let store = retrying();
+        let reservation = Reservation::new("key");
+        assert!(store.reserve(&reservation).await.unwrap());
+
+        // another request takes over the key and stores its response first
+        store.inner().inner.remove("key").await.unwrap();
+        let other = Reservation::new("key");
+        assert!(store.inner().inner.reserve(&other).await.unwrap());
+        let theirs = element("key").with_fingerprint(Some("theirs".to_owned()));
+        assert!(store.inner().inner.commit(&other, theirs).await.unwrap());
+
+        store.inner().fail(1);
+        assert!(!store.commit(&reservation, element("key")).await.unwrap());
+    }
+}
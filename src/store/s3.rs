@@ -0,0 +1,130 @@
+use actix_web::web::Bytes;
+use aws_sdk_s3::{primitives::ByteStream, Client};
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+
+use super::{BlobStore, BodyStream, StoreError, StoreFuture};
+
+const DEFAULT_PREFIX: &str = "idempotency/";
+
+/// [`BlobStore`] keeping bodies offloaded by an [`OffloadStore`](crate::OffloadStore) in an S3
+/// bucket, or any object storage speaking the S3 API.
+///
+/// S3 does not expire objects on its own, so add a lifecycle rule expiring objects below the
+/// [`prefix`](Self::prefix) some time after the middleware's
+/// [`ttl`](crate::IdempotencyBuilder::ttl).
+///
+/// Requires the `s3` feature.
+pub struct S3BlobStore {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3BlobStore {
+    pub fn new(client: Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            prefix: DEFAULT_PREFIX.to_owned(),
+        }
+    }
+
+    /// Prefix prepended to every object key. Defaults to `idempotency/`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    // the expiry is left to the bucket's lifecycle rules
+    fn put<'a>(
+        &'a self,
+        name: &'a str,
+        body: Bytes,
+        _expires_at: DateTime<Utc>,
+    ) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.key(name))
+                .body(ByteStream::from(body))
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str) -> StoreFuture<'a, Option<BodyStream>> {
+        Box::pin(async move {
+            let output = match self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.key(name))
+                .send()
+                .await
+            {
+                Ok(output) => output,
+                Err(err)
+                    if err
+                        .as_service_error()
+                        .is_some_and(|err| err.is_no_such_key()) =>
+                {
+                    return Ok(None);
+                }
+                Err(err) => return Err(StoreError::backend(err)),
+            };
+
+            let Some(len) = output
+                .content_length()
+                .and_then(|len| u64::try_from(len).ok())
+            else {
+                let body = output.body.collect().await.map_err(StoreError::backend)?;
+                return Ok(Some(BodyStream::ready(body.into_bytes())));
+            };
+
+            let chunks = stream::try_unfold(output.body, |mut body| async move {
+                let chunk = body.try_next().await.map_err(StoreError::backend)?;
+                Ok(chunk.map(|chunk| (chunk, body)))
+            });
+
+            Ok(Some(BodyStream::new(len, chunks)))
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(self.key(name))
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.client
+                .head_bucket()
+                .bucket(&self.bucket)
+                .send()
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+}
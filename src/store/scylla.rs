@@ -0,0 +1,503 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use scylla::{
+    client::{
+        caching_session::{CachingSession, CachingSessionBuilder},
+        session::Session,
+        session_builder::SessionBuilder,
+    },
+    response::query_result::QueryResult,
+    value::{CqlValue, Row},
+};
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture, WireFormat,
+};
+
+const DEFAULT_TABLE: &str = "idempotency";
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Longest TTL Cassandra accepts, 20 years.
+const MAX_TTL: i32 = 630_720_000;
+
+// Idempotency keys are visible ASCII, so this row can never collide with one.
+const MAINTENANCE: &str = "\0maintenance";
+
+/// Store keeping responses in a Cassandra or ScyllaDB table, shared by every instance connected
+/// to the cluster.
+///
+/// Keys are reserved with lightweight transactions (`INSERT ... IF NOT EXISTS`), so only one
+/// instance executes a given request. Rows are written with a TTL, so the cluster deletes
+/// responses once they expire and reservations after [`lock_timeout`](Self::lock_timeout) in
+/// case their holder dies. Reservations keep their token in the `owner` column, so that a request
+/// outliving its reservation cannot release or overwrite the one that took over. Statements are
+/// prepared once and reused.
+///
+/// Create the table with [`create_table`](Self::create_table) or an equivalent migration.
+///
+/// Requires the `scylla` feature.
+pub struct ScyllaStore {
+    session: CachingSession,
+    table: String,
+    lock_timeout: Duration,
+    format: WireFormat,
+}
+
+impl ScyllaStore {
+    /// Connects to the cluster through `node`, e.g. `127.0.0.1:9042`.
+    pub async fn open(node: &str) -> Result<Self, StoreError> {
+        let session = SessionBuilder::new()
+            .known_node(node)
+            .build()
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(Self::new(session))
+    }
+
+    pub fn new(session: impl Into<Arc<Session>>) -> Self {
+        Self {
+            session: CachingSessionBuilder::new_shared(session.into()).build(),
+            table: DEFAULT_TABLE.to_owned(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            format: WireFormat::default(),
+        }
+    }
+
+    /// Table holding the entries, like `payments.idempotency`. Defaults to `idempotency` in the
+    /// session's keyspace.
+    ///
+    /// The name is interpolated into every statement, so it must not come from untrusted input.
+    pub fn table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// How long a reservation is held before another instance may take the key over. Defaults
+    /// to 30 seconds, and is rounded up to whole seconds.
+    ///
+    /// This has to comfortably exceed the slowest handler, otherwise a retry arriving while the
+    /// original request is still running gets executed a second time. A middleware's
+    /// [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout) takes its place for the
+    /// reservations it makes.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Encoding of the entries written from now on. Defaults to [`WireFormat::Json`].
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Creates the table unless it exists already. Its keyspace has to exist.
+    pub async fn create_table(&self) -> Result<(), StoreError> {
+        self.session
+            .get_session()
+            .query_unpaged(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key text PRIMARY KEY, owner text, element blob)",
+                    self.table
+                ),
+                (),
+            )
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(())
+    }
+
+    // keys of every entry and reservation
+    async fn keys(&self) -> Result<Vec<String>, StoreError> {
+        let keys: Vec<(String,)> = self
+            .session
+            .execute_iter(format!("SELECT key FROM {}", self.table), ())
+            .await
+            .map_err(StoreError::backend)?
+            .rows_stream()
+            .map_err(StoreError::backend)?
+            .try_collect()
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(keys
+            .into_iter()
+            .map(|(key,)| key)
+            .filter(|key| key != MAINTENANCE)
+            .collect())
+    }
+}
+
+// Rounds up to whole seconds, as a zero TTL would keep a row forever.
+fn ttl(duration: Duration) -> i32 {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    i32::try_from(seconds).unwrap_or(MAX_TTL).clamp(1, MAX_TTL)
+}
+
+// The TTL of a row holding `element`.
+fn element_ttl(element: &CacheElement) -> i32 {
+    let remaining = (element.expires_at() - Utc::now())
+        .to_std()
+        .unwrap_or_default();
+    ttl(remaining)
+}
+
+// The stored response `raw` rewritten in `format` with the TTL it has left, unless there is none
+// yet or it is written in that format and the current layout already.
+fn upgrade(raw: Option<&[u8]>, format: WireFormat) -> Result<Option<(Vec<u8>, i32)>, StoreError> {
+    let Some(raw) = raw else {
+        return Ok(None);
+    };
+
+    Ok(format
+        .upgrade(raw)?
+        .map(|(element, upgraded)| (upgraded, element_ttl(&element))))
+}
+
+// Whether the condition of a lightweight transaction held, which Cassandra reports in the
+// `[applied]` column leading its result.
+fn applied(result: QueryResult) -> Result<bool, StoreError> {
+    let row = result
+        .into_rows_result()
+        .map_err(StoreError::backend)?
+        .maybe_first_row::<Row>()
+        .map_err(StoreError::backend)?;
+
+    Ok(row.is_some_and(|row| matches!(row.columns.first(), Some(Some(CqlValue::Boolean(true))))))
+}
+
+impl IdempotencyStore for ScyllaStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            let row = self
+                .session
+                .execute_unpaged(
+                    format!("SELECT element FROM {} WHERE key = ?", self.table),
+                    (key,),
+                )
+                .await
+                .map_err(StoreError::backend)?
+                .into_rows_result()
+                .map_err(StoreError::backend)?
+                .maybe_first_row::<(Option<Vec<u8>>,)>()
+                .map_err(StoreError::backend)?;
+
+            // reservations have no element yet
+            let Some((Some(raw),)) = row else {
+                return Ok(None);
+            };
+
+            let element = CacheElement::decode(&raw)?;
+            Ok(Some(element).filter(|element| !element.is_expired()))
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.reserve_for(reservation, self.lock_timeout)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        timeout: Duration,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let (key, token) = (reservation.key(), reservation.token());
+
+            let inserted = self
+                .session
+                .execute_unpaged(
+                    format!(
+                        "INSERT INTO {} (key, owner) VALUES (?, ?) IF NOT EXISTS USING TTL ?",
+                        self.table
+                    ),
+                    (key, token, ttl(timeout)),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            if applied(inserted)? {
+                return Ok(true);
+            }
+
+            // A retry finds its own reservation if the first attempt got through.
+            let renewed = self
+                .session
+                .execute_unpaged(
+                    format!(
+                        "UPDATE {} USING TTL ? SET owner = ? WHERE key = ? IF owner = ?",
+                        self.table
+                    ),
+                    (ttl(timeout), token, key, token),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            applied(renewed)
+        })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            // does nothing once someone else holds the key, which is theirs to release
+            self.session
+                .execute_unpaged(
+                    format!("DELETE FROM {} WHERE key = ? IF owner = ?", self.table),
+                    (reservation.key(), reservation.token()),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let encoded = element.encode(self.format)?;
+            let ttl = element_ttl(&element);
+
+            // Replaces our own reservation. The row it created outlives its TTL for as long as
+            // the element does.
+            let replaced = self
+                .session
+                .execute_unpaged(
+                    format!(
+                        "UPDATE {} USING TTL ? SET owner = null, element = ? \
+                         WHERE key = ? IF owner = ?",
+                        self.table
+                    ),
+                    (ttl, &encoded, element.key(), reservation.token()),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            if applied(replaced)? {
+                return Ok(true);
+            }
+
+            // Our reservation is gone. The key is ours only if nobody else claimed it since.
+            let inserted = self
+                .session
+                .execute_unpaged(
+                    format!(
+                        "INSERT INTO {} (key, element) VALUES (?, ?) IF NOT EXISTS USING TTL ?",
+                        self.table
+                    ),
+                    (element.key(), &encoded, ttl),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            applied(inserted)
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let result = self
+                .session
+                .execute_unpaged(
+                    format!("DELETE FROM {} WHERE key = ? IF EXISTS", self.table),
+                    (key,),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            applied(result)
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move { Ok(self.keys().await?.len()) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            for key in self.keys().await? {
+                self.session
+                    .execute_unpaged(
+                        format!("DELETE FROM {} WHERE key = ? IF EXISTS", self.table),
+                        (key,),
+                    )
+                    .await
+                    .map_err(StoreError::backend)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let entries: Vec<(String, Option<Vec<u8>>)> = self
+                .session
+                .execute_iter(format!("SELECT key, element FROM {}", self.table), ())
+                .await
+                .map_err(StoreError::backend)?
+                .rows_stream()
+                .map_err(StoreError::backend)?
+                .try_collect()
+                .await
+                .map_err(StoreError::backend)?;
+
+            let mut migrated = 0;
+            for (key, raw) in entries {
+                // reservations and the maintenance lock have no element
+                let Some((upgraded, ttl)) = upgrade(raw.as_deref(), self.format)? else {
+                    continue;
+                };
+
+                // left alone if it changed in the meantime
+                let result = self
+                    .session
+                    .execute_unpaged(
+                        format!(
+                            "UPDATE {} USING TTL ? SET element = ? WHERE key = ? IF element = ?",
+                            self.table
+                        ),
+                        (ttl, &upgraded, &key, &raw),
+                    )
+                    .await
+                    .map_err(StoreError::backend)?;
+                migrated += usize::from(applied(result)?);
+            }
+
+            Ok(migrated)
+        })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        Box::pin(async move {
+            let entries: Vec<(String, Option<Vec<u8>>)> = self
+                .session
+                .execute_iter(format!("SELECT key, element FROM {}", self.table), ())
+                .await
+                .map_err(StoreError::backend)?
+                .rows_stream()
+                .map_err(StoreError::backend)?
+                .try_collect()
+                .await
+                .map_err(StoreError::backend)?;
+
+            let mut invalidated = 0;
+            for (key, raw) in entries {
+                // reservations and the maintenance lock have no element
+                let Some(raw) = raw else {
+                    continue;
+                };
+                if !invalidation.matches(&CacheElement::decode(&raw)?) {
+                    continue;
+                }
+
+                // left alone if it changed in the meantime
+                let result = self
+                    .session
+                    .execute_unpaged(
+                        format!("DELETE FROM {} WHERE key = ? IF element = ?", self.table),
+                        (&key, &raw),
+                    )
+                    .await
+                    .map_err(StoreError::backend)?;
+                invalidated += usize::from(applied(result)?);
+            }
+
+            Ok(invalidated)
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.session
+                .execute_unpaged(
+                    format!(
+                        "INSERT INTO {} (key, owner) VALUES (?, ?) USING TTL ?",
+                        self.table
+                    ),
+                    (MAINTENANCE, MAINTENANCE, ttl(duration)),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.session
+                .execute_unpaged(
+                    format!("DELETE FROM {} WHERE key = ?", self.table),
+                    (MAINTENANCE,),
+                )
+                .await
+                .map_err(StoreError::backend)?;
+
+            Ok(())
+        })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            let row = self
+                .session
+                .execute_unpaged(
+                    format!("SELECT TTL(owner) FROM {} WHERE key = ?", self.table),
+                    (MAINTENANCE,),
+                )
+                .await
+                .map_err(StoreError::backend)?
+                .into_rows_result()
+                .map_err(StoreError::backend)?
+                .maybe_first_row::<(Option<i32>,)>()
+                .map_err(StoreError::backend)?;
+
+            Ok(row
+                .and_then(|(remaining,)| u64::try_from(remaining?).ok())
+                .filter(|&remaining| remaining > 0)
+                .map(Duration::from_secs))
+        })
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.session
+                .execute_unpaged("SELECT release_version FROM system.local", ())
+                .await
+                .map(|_| ())
+                .map_err(StoreError::backend)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::wire::tests::{assert_same, legacy_entries};
+
+    #[test]
+    fn migrate_rewrites_entries_of_older_versions() {
+        for (element, raw) in legacy_entries() {
+            let (upgraded, ttl) = upgrade(Some(&raw), WireFormat::Json).unwrap().unwrap();
+            assert_eq!(upgraded[..2], [4, b'j']);
+            assert_same(&CacheElement::decode(&upgraded).unwrap(), &element);
+            // the element expires within the hour, and the row with it
+            assert!((3590..=3600).contains(&ttl));
+
+            // and leaves them alone from then on
+            assert!(upgrade(Some(&upgraded), WireFormat::Json)
+                .unwrap()
+                .is_none());
+        }
+
+        assert!(upgrade(None, WireFormat::Json).unwrap().is_none());
+    }
+}
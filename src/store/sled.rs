@@ -0,0 +1,560 @@
+use std::{path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sled::{Db, IVec, Tree};
+
+use super::{
+    CacheElement, IdempotencyStore, Invalidation, MaintenanceLock, Reservation, StoreError,
+    StoreFuture, WireFormat,
+};
+
+// Every value starts with a tag byte telling reservations and completed responses apart, so both
+// live in one tree and can be swapped for one another atomically. A reservation is followed by
+// its token, or if it lapses by the deadline in milliseconds since the epoch, as 8 big-endian
+// bytes, and then its token.
+const RESERVED: u8 = 0;
+const COMPLETE: u8 = 1;
+const RESERVED_UNTIL: u8 = 2;
+
+enum Slot {
+    Reserved {
+        token: Vec<u8>,
+        deadline: Option<DateTime<Utc>>,
+    },
+    Complete(Box<CacheElement>),
+}
+
+impl Slot {
+    fn is_live(&self) -> bool {
+        match self {
+            Self::Reserved { deadline, .. } => {
+                deadline.is_none_or(|deadline| deadline > Utc::now())
+            }
+            Self::Complete(element) => !element.is_expired(),
+        }
+    }
+
+    // held by a reservation other than `reservation` that has not lapsed
+    fn is_held_against(&self, reservation: &Reservation) -> bool {
+        match self {
+            Self::Reserved { token, .. } if token == reservation.token().as_bytes() => false,
+            slot => slot.is_live(),
+        }
+    }
+}
+
+/// Store persisting responses to disk with [sled](https://docs.rs/sled), so that replays survive
+/// restarts.
+///
+/// Requires the `sled` feature.
+pub struct SledStore {
+    tree: Tree,
+    format: WireFormat,
+    maintenance: MaintenanceLock,
+}
+
+impl SledStore {
+    /// Opens (or creates) a database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let db = sled::open(path).map_err(StoreError::backend)?;
+        Self::from_db(&db)
+    }
+
+    /// Keeps entries in a dedicated tree of an already opened database.
+    ///
+    /// Entries that expired while the process was down are dropped. Reservations found on
+    /// startup were left behind by a process that died while their request was executing, which
+    /// may have had its effects already. They stay in place, so that retries are held up rather
+    /// than executed again, until they are [`remove`](IdempotencyStore::remove)d or, if taken
+    /// with a [`pending_timeout`](crate::IdempotencyBuilder::pending_timeout), until they lapse.
+    pub fn from_db(db: &Db) -> Result<Self, StoreError> {
+        let store = Self {
+            tree: db.open_tree("idempotency").map_err(StoreError::backend)?,
+            format: WireFormat::default(),
+            maintenance: MaintenanceLock::default(),
+        };
+
+        store.sweep(|slot| !slot.is_live())?;
+
+        Ok(store)
+    }
+
+    /// Encoding of the entries written from now on. Defaults to [`WireFormat::Json`].
+    pub fn format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // A reservation is what keeps a retry arriving after a crash from executing again, so it has
+    // to reach the disk before the handler runs.
+    async fn flushed(&self, reserved: Result<bool, StoreError>) -> Result<bool, StoreError> {
+        if reserved? {
+            self.tree.flush_async().await.map_err(StoreError::backend)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn read(&self, key: &str) -> Result<Option<(IVec, Slot)>, StoreError> {
+        let Some(raw) = self.tree.get(key.as_bytes()).map_err(StoreError::backend)? else {
+            return Ok(None);
+        };
+
+        let slot = decode(&raw)?;
+        Ok(Some((raw, slot)))
+    }
+
+    // atomically replaces `current` with `new`, reporting whether nobody changed it in between
+    fn swap(
+        &self,
+        key: &str,
+        current: Option<&IVec>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool, StoreError> {
+        let swapped = self
+            .tree
+            .compare_and_swap(key.as_bytes(), current, new)
+            .map_err(StoreError::backend)?;
+
+        Ok(swapped.is_ok())
+    }
+
+    fn sweep(&self, remove: impl Fn(&Slot) -> bool) -> Result<usize, StoreError> {
+        let mut removed = 0;
+
+        for item in self.tree.iter() {
+            let (key, raw) = item.map_err(StoreError::backend)?;
+
+            if remove(&decode(&raw)?)
+                && self
+                    .tree
+                    .compare_and_swap(&key, Some(&raw), None as Option<IVec>)
+                    .map_err(StoreError::backend)?
+                    .is_ok()
+            {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn migrate_now(&self) -> Result<usize, StoreError> {
+        let mut migrated = 0;
+
+        for item in self.tree.iter() {
+            let (key, raw) = item.map_err(StoreError::backend)?;
+
+            let Some((&COMPLETE, encoded)) = raw.split_first() else {
+                continue;
+            };
+            let Some((element, encoded)) = self.format.upgrade(encoded)? else {
+                continue;
+            };
+            if element.is_expired() {
+                continue;
+            }
+
+            let upgraded = [&[COMPLETE], encoded.as_slice()].concat();
+
+            if self
+                .tree
+                .compare_and_swap(&key, Some(&raw), Some(upgraded))
+                .map_err(StoreError::backend)?
+                .is_ok()
+            {
+                migrated += 1;
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    fn invalidate_now(&self, invalidation: &Invalidation) -> Result<usize, StoreError> {
+        let entries = match invalidation {
+            Invalidation::Scope(scope) => self.tree.scan_prefix(scope.as_bytes()),
+            _ => self.tree.iter(),
+        };
+
+        let mut invalidated = 0;
+        for item in entries {
+            let (key, raw) = item.map_err(StoreError::backend)?;
+
+            if matches!(decode(&raw)?, Slot::Complete(element) if invalidation.matches(&element))
+                && self
+                    .tree
+                    .compare_and_swap(&key, Some(&raw), None as Option<IVec>)
+                    .map_err(StoreError::backend)?
+                    .is_ok()
+            {
+                invalidated += 1;
+            }
+        }
+
+        Ok(invalidated)
+    }
+
+    fn get_now(&self, key: &str) -> Result<Option<CacheElement>, StoreError> {
+        match self.read(key)? {
+            Some((raw, Slot::Complete(element))) if element.is_expired() => {
+                self.swap(key, Some(&raw), None)?;
+                Ok(None)
+            }
+            Some((_, Slot::Complete(element))) => Ok(Some(*element)),
+            _ => Ok(None),
+        }
+    }
+
+    fn reserve_now(
+        &self,
+        reservation: &Reservation,
+        deadline: Option<DateTime<Utc>>,
+    ) -> Result<bool, StoreError> {
+        let key = reservation.key();
+
+        match self.read(key)? {
+            Some((_, slot)) if slot.is_held_against(reservation) => Ok(false),
+            current => {
+                let mut reserved = match deadline {
+                    Some(deadline) => {
+                        let mut reserved = vec![RESERVED_UNTIL];
+                        reserved.extend(deadline.timestamp_millis().to_be_bytes());
+                        reserved
+                    }
+                    None => vec![RESERVED],
+                };
+                reserved.extend_from_slice(reservation.token().as_bytes());
+
+                self.swap(key, current.as_ref().map(|(raw, _)| raw), Some(reserved))
+            }
+        }
+    }
+
+    fn abort_now(&self, reservation: &Reservation) -> Result<(), StoreError> {
+        let key = reservation.key();
+        if let Some((raw, Slot::Reserved { token, .. })) = self.read(key)? {
+            if token == reservation.token().as_bytes() {
+                self.swap(key, Some(&raw), None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit_now(
+        &self,
+        reservation: &Reservation,
+        element: &CacheElement,
+    ) -> Result<bool, StoreError> {
+        let mut encoded = vec![COMPLETE];
+        encoded.extend(element.encode(self.format)?);
+
+        loop {
+            let current = match self.read(element.key())? {
+                Some((_, slot)) if slot.is_held_against(reservation) => return Ok(false),
+                current => current.map(|(raw, _)| raw),
+            };
+
+            if self.swap(element.key(), current.as_ref(), Some(encoded.clone()))? {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+fn decode(raw: &[u8]) -> Result<Slot, StoreError> {
+    match raw.split_first() {
+        Some((&RESERVED, token)) => Ok(Slot::Reserved {
+            token: token.to_vec(),
+            deadline: None,
+        }),
+        Some((&RESERVED_UNTIL, reserved)) => {
+            let (deadline, token) = reserved
+                .split_first_chunk()
+                .ok_or_else(|| StoreError::backend("truncated reservation in sled store"))?;
+
+            Ok(Slot::Reserved {
+                token: token.to_vec(),
+                deadline: DateTime::from_timestamp_millis(i64::from_be_bytes(*deadline)),
+            })
+        }
+        Some((&COMPLETE, element)) => {
+            CacheElement::decode(element).map(|element| Slot::Complete(Box::new(element)))
+        }
+        _ => Err(StoreError::backend("unrecognized entry in sled store")),
+    }
+}
+
+impl IdempotencyStore for SledStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        let result = self.get_now(key);
+
+        Box::pin(async move { result })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        let result = self.reserve_now(reservation, None);
+
+        Box::pin(self.flushed(result))
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+        let deadline = Utc::now().checked_add_signed(ttl);
+        let result = self.reserve_now(
+            reservation,
+            Some(deadline.unwrap_or(DateTime::<Utc>::MAX_UTC)),
+        );
+
+        Box::pin(self.flushed(result))
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        let result = self.abort_now(reservation);
+
+        Box::pin(async move { result })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let inserted = self.commit_now(reservation, &element)?;
+
+            // a committed response is what keeps a retry from executing twice, so make sure it
+            // reached the disk before the client is told about it
+            self.tree.flush_async().await.map_err(StoreError::backend)?;
+
+            Ok(inserted)
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        let result = self
+            .tree
+            .remove(key.as_bytes())
+            .map(|removed| removed.is_some())
+            .map_err(StoreError::backend);
+
+        Box::pin(async move { result })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        let len = self.tree.len();
+
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        let result = self.tree.clear().map_err(StoreError::backend);
+
+        Box::pin(async move { result })
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        let result = self.sweep(|slot| !slot.is_live());
+
+        Box::pin(async move { result })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        let result = self.migrate_now();
+
+        Box::pin(async move { result })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        let result = self.invalidate_now(&invalidation);
+
+        Box::pin(async move { result })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        self.maintenance.lock(duration);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        self.maintenance.unlock();
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        let remaining = self.maintenance.remaining();
+
+        Box::pin(async move { Ok(remaining) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use actix_web::{http::StatusCode, rt, web::Bytes, HttpResponse};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{store::wire, HeaderFilter};
+
+    // a database directory of its own, removed once the test is done
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Self {
+            Self(env::temp_dir().join(format!("idempotency-{}", Uuid::new_v4())))
+        }
+
+        // sled releases its lock on the directory from a background thread, so a store dropped
+        // just before may still hold it for a moment
+        fn open(&self) -> SledStore {
+            for _ in 0..50 {
+                if let Ok(store) = SledStore::open(&self.0) {
+                    return store;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+
+            SledStore::open(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn element(key: &str, body: &'static str) -> CacheElement {
+        CacheElement::capture(
+            key.to_owned(),
+            &HttpResponse::with_body(StatusCode::CREATED, ()),
+            Bytes::from_static(body.as_bytes()),
+            chrono::Duration::hours(1),
+            &HeaderFilter::default(),
+            Utc::now(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn reservation_survives_crash() {
+        let dir = Dir::new();
+
+        let store = dir.open();
+        assert!(store.reserve(&Reservation::new("order-1")).await.unwrap());
+        // the process dies between reserving and committing
+        drop(store);
+
+        let store = dir.open();
+        assert!(!store.reserve(&Reservation::new("order-1")).await.unwrap());
+        assert!(store.get("order-1").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn retry_takes_over_lapsed_reservation_after_crash() {
+        let dir = Dir::new();
+        // long enough for the store to be reopened before it lapses
+        let timeout = Duration::from_millis(500);
+
+        let store = dir.open();
+        assert!(store
+            .reserve_for(&Reservation::new("order-1"), timeout)
+            .await
+            .unwrap());
+        drop(store);
+
+        let store = dir.open();
+        let retry = Reservation::new("order-1");
+        assert!(!store.reserve_for(&retry, timeout).await.unwrap());
+
+        rt::time::sleep(timeout).await;
+        assert!(store.reserve_for(&retry, timeout).await.unwrap());
+        assert!(store
+            .commit(&retry, element("order-1", "retry"))
+            .await
+            .unwrap());
+        drop(store);
+
+        let store = dir.open();
+        let stored = store.get("order-1").await.unwrap().unwrap();
+        assert_eq!(stored.body(), b"retry");
+    }
+
+    #[actix_web::test]
+    async fn commit_after_lapse_is_stored_unless_taken_over() {
+        let dir = Dir::new();
+        let timeout = Duration::from_millis(50);
+        let store = dir.open();
+
+        let slow = Reservation::new("order-1");
+        assert!(store.reserve_for(&slow, timeout).await.unwrap());
+
+        rt::time::sleep(timeout).await;
+        assert!(store
+            .commit(&slow, element("order-1", "slow"))
+            .await
+            .unwrap());
+        let stored = store.get("order-1").await.unwrap().unwrap();
+        assert_eq!(stored.body(), b"slow");
+    }
+
+    #[actix_web::test]
+    async fn commit_after_lapse_loses_to_retry() {
+        let dir = Dir::new();
+        let timeout = Duration::from_millis(50);
+        let store = dir.open();
+
+        let slow = Reservation::new("order-1");
+        assert!(store.reserve_for(&slow, timeout).await.unwrap());
+        rt::time::sleep(timeout).await;
+        let retry = Reservation::new("order-1");
+        assert!(store.reserve(&retry).await.unwrap());
+
+        // the original request neither overwrites nor releases the retry's reservation
+        assert!(!store
+            .commit(&slow, element("order-1", "slow"))
+            .await
+            .unwrap());
+        store.abort(&slow).await.unwrap();
+        assert!(!store.reserve(&Reservation::new("order-1")).await.unwrap());
+
+        assert!(store
+            .commit(&retry, element("order-1", "retry"))
+            .await
+            .unwrap());
+        let stored = store.get("order-1").await.unwrap().unwrap();
+        assert_eq!(stored.body(), b"retry");
+    }
+
+    #[actix_web::test]
+    async fn migrate_rewrites_entries_of_older_versions() {
+        let dir = Dir::new();
+        let store = dir.open();
+
+        let written = wire::tests::legacy_entries();
+        for (element, raw) in &written {
+            let slot = [&[COMPLETE], raw.as_slice()].concat();
+            store.tree.insert(element.key(), slot).unwrap();
+        }
+
+        assert_eq!(store.migrate().await.unwrap(), written.len());
+        assert_eq!(store.migrate().await.unwrap(), 0);
+
+        // rewritten in the current layout, and in JSON like every entry the store writes
+        for (element, _) in &written {
+            let raw = store.tree.get(element.key()).unwrap().unwrap();
+            assert_eq!(raw[..3], [COMPLETE, 4, b'j']);
+
+            let stored = store.get(element.key()).await.unwrap().unwrap();
+            wire::tests::assert_same(&stored, element);
+        }
+    }
+}
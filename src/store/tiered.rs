@@ -0,0 +1,412 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::OnceCell;
+
+use crate::{Clock, SystemClock};
+
+use super::{
+    BodyStream, BusMessage, CacheElement, IdempotencyStore, Invalidation, InvalidationBus,
+    Reservation, StoreFuture, StoreStats,
+};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+const DEFAULT_L1_TTL: Duration = Duration::from_secs(5);
+
+// how long the maintenance state of the shared store is taken as given
+const MAINTENANCE_TTL: Duration = Duration::from_secs(1);
+
+/// Store keeping the most recently used responses in process, in front of a shared store such
+/// as Redis, so that hot keys are replayed without a round trip.
+///
+/// Writes go through to the shared store, which alone decides reservations, so requests are
+/// still executed once across instances. An entry removed from the shared store may however be
+/// replayed from another instance's local copy for up to [`l1_ttl`](Self::l1_ttl), so keep that
+/// short, or have instances tell each other through an
+/// [`invalidation_bus`](Self::invalidation_bus).
+///
+/// Whether the shared store is under maintenance is likewise only asked once a second, so a
+/// maintenance window started by another instance may take that long to be noticed.
+pub struct TieredStore<S> {
+    l2: S,
+    l1: Arc<Mutex<Lru>>,
+    capacity: usize,
+    l1_ttl: Duration,
+    clock: Arc<dyn Clock>,
+    bus: Option<Arc<dyn InvalidationBus>>,
+    subscribed: OnceCell<()>,
+    // what the shared store last said about maintenance, and when
+    maintenance: Mutex<Option<(Instant, Option<Duration>)>>,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Local>,
+    // keys by when they were last used, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct Local {
+    element: CacheElement,
+    cached_at: Instant,
+    used: u64,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str, ttl: Duration, now: DateTime<Utc>) -> Option<CacheElement> {
+        let local = self.entries.get(key)?;
+        if local.cached_at.elapsed() >= ttl || local.element.is_expired_at(now) {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let local = self.entries.get_mut(key)?;
+        self.order.remove(&local.used);
+        local.used = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+
+        Some(local.element.clone())
+    }
+
+    fn insert(&mut self, element: CacheElement, capacity: usize) {
+        self.remove(element.key());
+
+        while self.entries.len() >= capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, element.key().to_owned());
+        self.entries.insert(
+            element.key().to_owned(),
+            Local {
+                element,
+                cached_at: Instant::now(),
+                used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(local) = self.entries.remove(key) {
+            self.order.remove(&local.used);
+        }
+    }
+
+    fn invalidate(&mut self, invalidation: &Invalidation) {
+        let order = &mut self.order;
+        self.entries.retain(|_, local| {
+            let keep = !invalidation.matches(&local.element);
+            if !keep {
+                order.remove(&local.used);
+            }
+            keep
+        });
+    }
+
+    fn apply(&mut self, message: &BusMessage) {
+        match message {
+            BusMessage::Removed(key) => self.remove(key),
+            BusMessage::Invalidated(invalidation) => self.invalidate(invalidation),
+            BusMessage::Cleared => *self = Self::default(),
+        }
+    }
+
+    fn purge_expired(&mut self, ttl: Duration, now: DateTime<Utc>) {
+        let order = &mut self.order;
+        self.entries.retain(|_, local| {
+            let live = local.cached_at.elapsed() < ttl && !local.element.is_expired_at(now);
+            if !live {
+                order.remove(&local.used);
+            }
+            live
+        });
+    }
+}
+
+impl<S: IdempotencyStore> TieredStore<S> {
+    /// Store caching the responses of `l2` locally.
+    pub fn new(l2: S) -> Self {
+        Self {
+            l2,
+            l1: Arc::default(),
+            capacity: DEFAULT_CAPACITY,
+            l1_ttl: DEFAULT_L1_TTL,
+            clock: Arc::new(SystemClock),
+            bus: None,
+            subscribed: OnceCell::new(),
+            maintenance: Mutex::default(),
+        }
+    }
+
+    /// How many responses are kept locally, evicting the least recently used beyond that.
+    /// Defaults to 1024. A capacity of 0 disables the local cache.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// How long a response is replayed from the local copy before it is fetched again. Defaults
+    /// to 5 seconds.
+    pub fn l1_ttl(mut self, ttl: Duration) -> Self {
+        self.l1_ttl = ttl;
+        self
+    }
+
+    /// Clock deciding when the local copies of responses expire. Defaults to [`SystemClock`];
+    /// give it the one the middleware uses.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Tells the other instances through `bus` whenever responses are removed from the shared
+    /// store, and drops the local copies of those they removed, so that no instance keeps
+    /// replaying them.
+    ///
+    /// The subscription is set up on first use of the store. Until it is, or while it fails,
+    /// local copies still expire after [`l1_ttl`](Self::l1_ttl).
+    pub fn invalidation_bus(mut self, bus: impl InvalidationBus + 'static) -> Self {
+        self.bus = Some(Arc::new(bus));
+        self
+    }
+
+    /// The shared store behind the local cache.
+    pub fn l2(&self) -> &S {
+        &self.l2
+    }
+
+    // subscribes to the bus unless done already, retrying on the next call if that fails
+    async fn listen(&self) {
+        let Some(bus) = &self.bus else {
+            return;
+        };
+
+        let l1 = Arc::clone(&self.l1);
+        let subscribed = self
+            .subscribed
+            .get_or_try_init(|| {
+                bus.subscribe(Arc::new(move |message| l1.lock().unwrap().apply(&message)))
+            })
+            .await;
+
+        if let Err(err) = subscribed {
+            log::warn!("failed to subscribe to idempotency invalidations: {err}");
+        }
+    }
+
+    // the removal already happened, so a failure only leaves other instances waiting it out
+    async fn broadcast(&self, message: BusMessage) {
+        if let Some(bus) = &self.bus {
+            if let Err(err) = bus.publish(message).await {
+                log::warn!("failed to publish idempotency invalidation: {err}");
+            }
+        }
+    }
+
+    fn cache(&self, element: &CacheElement) {
+        if self.capacity > 0 {
+            self.l1
+                .lock()
+                .unwrap()
+                .insert(element.clone(), self.capacity);
+        }
+    }
+}
+
+impl<S: IdempotencyStore> IdempotencyStore for TieredStore<S> {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        Box::pin(async move {
+            self.listen().await;
+
+            if let Some(element) = self
+                .l1
+                .lock()
+                .unwrap()
+                .get(key, self.l1_ttl, self.clock.now())
+            {
+                return Ok(Some(element));
+            }
+
+            let element = self.l2.get(key).await?;
+            if let Some(element) = &element {
+                self.cache(element);
+            }
+
+            Ok(element)
+        })
+    }
+
+    fn get_streamed<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> StoreFuture<'a, Option<(CacheElement, BodyStream)>> {
+        Box::pin(async move {
+            self.listen().await;
+
+            let cached = self
+                .l1
+                .lock()
+                .unwrap()
+                .get(key, self.l1_ttl, self.clock.now());
+            let mut element = match cached {
+                Some(element) => element,
+                None => match self.l2.get_streamed(key).await? {
+                    // bodies the shared store streams are too large to be worth keeping locally
+                    Some((element, body)) if !body.is_ready() => {
+                        return Ok(Some((element, body)));
+                    }
+                    Some((mut element, body)) => {
+                        element.set_body(body.collect().await?);
+                        self.cache(&element);
+                        element
+                    }
+                    None => return Ok(None),
+                },
+            };
+
+            let body = element.take_body();
+            Ok(Some((element, BodyStream::ready(body))))
+        })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        self.l2.reserve(reservation)
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        self.l2.reserve_for(reservation, ttl)
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        self.l2.abort(reservation)
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            self.listen().await;
+
+            let local = element.clone();
+
+            let inserted = self.l2.commit(reservation, element).await?;
+            if inserted {
+                self.cache(&local);
+            }
+
+            Ok(inserted)
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        self.l1.lock().unwrap().remove(key);
+
+        Box::pin(async move {
+            let removed = self.l2.remove(key).await?;
+            self.broadcast(BusMessage::Removed(key.to_owned())).await;
+
+            Ok(removed)
+        })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        self.l2.len()
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        *self.l1.lock().unwrap() = Lru::default();
+
+        Box::pin(async move {
+            self.l2.clear().await?;
+            self.broadcast(BusMessage::Cleared).await;
+
+            Ok(())
+        })
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        let now = self.clock.now();
+        self.l1.lock().unwrap().purge_expired(self.l1_ttl, now);
+
+        self.l2.purge_expired()
+    }
+
+    // the local copies are kept as values
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        self.l2.migrate()
+    }
+
+    // without a bus, other instances keep their copies until their local TTL passes
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        self.l1.lock().unwrap().invalidate(&invalidation);
+
+        Box::pin(async move {
+            let removed = self.l2.invalidate(invalidation.clone()).await?;
+            self.broadcast(BusMessage::Invalidated(invalidation)).await;
+
+            Ok(removed)
+        })
+    }
+
+    fn maintenance_lock(&self, duration: Duration) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.l2.maintenance_lock(duration).await?;
+            *self.maintenance.lock().unwrap() = None;
+
+            Ok(())
+        })
+    }
+
+    fn maintenance_unlock(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.l2.maintenance_unlock().await?;
+            *self.maintenance.lock().unwrap() = None;
+
+            Ok(())
+        })
+    }
+
+    // asked on every request, so only passed on to the shared store once in a while
+    fn maintenance_remaining(&self) -> StoreFuture<'_, Option<Duration>> {
+        Box::pin(async move {
+            if let Some((asked_at, remaining)) = *self.maintenance.lock().unwrap() {
+                let elapsed = asked_at.elapsed();
+                if elapsed < MAINTENANCE_TTL {
+                    return Ok(remaining
+                        .and_then(|remaining| remaining.checked_sub(elapsed))
+                        .filter(|remaining| !remaining.is_zero()));
+                }
+            }
+
+            let remaining = self.l2.maintenance_remaining().await?;
+            *self.maintenance.lock().unwrap() = Some((Instant::now(), remaining));
+
+            Ok(remaining)
+        })
+    }
+
+    fn stats(&self) -> StoreFuture<'_, StoreStats> {
+        self.l2.stats()
+    }
+
+    fn health(&self) -> StoreFuture<'_, ()> {
+        self.l2.health()
+    }
+}
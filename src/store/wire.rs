@@ -0,0 +1,490 @@
+#[cfg(feature = "bincode")]
+use std::collections::HashMap;
+
+#[cfg(feature = "bincode")]
+use actix_web::http::StatusCode;
+#[cfg(feature = "bincode")]
+use chrono::{DateTime, Utc};
+#[cfg(feature = "bincode")]
+use serde::Deserialize;
+#[cfg(feature = "bincode")]
+use serde_json::Value;
+
+#[cfg(feature = "bincode")]
+use crate::compression::Codec;
+
+use super::{CacheElement, StoreError};
+
+// Bumped whenever the layout of `CacheElement` changes in a way a codec cannot absorb, e.g. a new
+// field for bincode, which does not know about defaults. Decoding then dispatches on it to read
+// entries written by older versions.
+//
+// 2: `metadata` was added.
+// 3: `key_header` was added.
+// 4: `method` and `route` were added.
+const VERSION: u8 = 4;
+
+/// Encoding external stores use for the entries they hold.
+///
+/// Entries are wrapped in an envelope recording the layout version and the format, so a store
+/// reads entries written by older versions of this crate and in any other format as well.
+/// Formats other than JSON require the cargo feature of the same name (`bincode`, `msgpack`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Human readable and the most forgiving. The default.
+    #[default]
+    Json,
+    /// The most compact, and the fastest to encode and decode.
+    Bincode,
+    /// MessagePack with named fields, a compact middle ground.
+    MessagePack,
+}
+
+impl WireFormat {
+    fn tag(self) -> u8 {
+        match self {
+            Self::Json => b'j',
+            Self::Bincode => b'b',
+            Self::MessagePack => b'm',
+        }
+    }
+
+    /// Whether `raw`, an entry written by [`CacheElement::encode`], was written by an older
+    /// version of this crate or in another format, and is rewritten by
+    /// [`migrate`](super::IdempotencyStore::migrate).
+    pub fn is_outdated(self, raw: &[u8]) -> bool {
+        !matches!(raw, [version, tag, ..] if *version == VERSION && *tag == self.tag())
+    }
+
+    /// `raw`, an entry written by [`CacheElement::encode`], rewritten in this format and the
+    /// current layout along with the entry it holds, unless it is in both already. What
+    /// [`migrate`](super::IdempotencyStore::migrate) writes back.
+    pub fn upgrade(self, raw: &[u8]) -> Result<Option<(CacheElement, Vec<u8>)>, StoreError> {
+        if !self.is_outdated(raw) {
+            return Ok(None);
+        }
+
+        let element = decode(raw)?;
+        let upgraded = encode(&element, self)?;
+        Ok(Some((element, upgraded)))
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            b'j' => Some(Self::Json),
+            b'b' => Some(Self::Bincode),
+            b'm' => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+pub(super) fn encode(element: &CacheElement, format: WireFormat) -> Result<Vec<u8>, StoreError> {
+    let mut raw = vec![VERSION, format.tag()];
+
+    match format {
+        WireFormat::Json => {
+            serde_json::to_writer(&mut raw, element).map_err(StoreError::backend)?
+        }
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode => {
+            bincode::serde::encode_into_std_write(element, &mut raw, bincode::config::standard())
+                .map_err(StoreError::backend)?;
+        }
+        #[cfg(feature = "msgpack")]
+        WireFormat::MessagePack => {
+            rmp_serde::encode::write_named(&mut raw, element).map_err(StoreError::backend)?;
+        }
+        #[allow(unreachable_patterns)]
+        format => return Err(unsupported(format)),
+    }
+
+    Ok(raw)
+}
+
+pub(super) fn decode(raw: &[u8]) -> Result<CacheElement, StoreError> {
+    let (version, tag, payload) = match raw {
+        // written before entries were wrapped in an envelope
+        [b'{', ..] => return serde_json::from_slice(raw).map_err(StoreError::backend),
+        [version, tag, payload @ ..] => (*version, *tag, payload),
+        _ => return Err(StoreError::backend("truncated entry")),
+    };
+
+    if version > VERSION {
+        return Err(StoreError::backend(format!(
+            "entry has layout version {version}, which is newer than this crate understands"
+        )));
+    }
+
+    let Some(format) = WireFormat::from_tag(tag) else {
+        return Err(StoreError::backend(format!(
+            "entry has unknown format {tag:#04x}"
+        )));
+    };
+
+    match format {
+        // the self-describing formats fill in fields older versions lacked with their defaults
+        WireFormat::Json => serde_json::from_slice(payload).map_err(StoreError::backend),
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode if version == 1 => {
+            bincode::serde::decode_from_slice::<V1, _>(payload, bincode::config::standard())
+                .map(|(element, _)| V3::from(V2::from(element)).into())
+                .map_err(StoreError::backend)
+        }
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode if version == 2 => {
+            bincode::serde::decode_from_slice::<V2, _>(payload, bincode::config::standard())
+                .map(|(element, _)| V3::from(element).into())
+                .map_err(StoreError::backend)
+        }
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode if version == 3 => {
+            bincode::serde::decode_from_slice::<V3, _>(payload, bincode::config::standard())
+                .map(|(element, _)| element.into())
+                .map_err(StoreError::backend)
+        }
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode => {
+            bincode::serde::decode_from_slice(payload, bincode::config::standard())
+                .map(|(element, _)| element)
+                .map_err(StoreError::backend)
+        }
+        #[cfg(feature = "msgpack")]
+        WireFormat::MessagePack => rmp_serde::from_slice(payload).map_err(StoreError::backend),
+        #[allow(unreachable_patterns)]
+        format => Err(unsupported(format)),
+    }
+}
+
+// unused once every format is enabled
+#[allow(dead_code)]
+fn unsupported(format: WireFormat) -> StoreError {
+    StoreError::backend(format!(
+        "{format:?} entries require the corresponding cargo feature"
+    ))
+}
+
+// Layouts of earlier versions, for bincode entries written before they changed. Each converts
+// into its successor.
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct V1 {
+    key: String,
+    #[serde(with = "super::status_code")]
+    status: StatusCode,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    request_id: Option<String>,
+    fingerprint: Option<String>,
+    compression: Option<Codec>,
+    encrypted: bool,
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct V2 {
+    key: String,
+    #[serde(with = "super::status_code")]
+    status: StatusCode,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    request_id: Option<String>,
+    fingerprint: Option<String>,
+    compression: Option<Codec>,
+    encrypted: bool,
+    #[serde(with = "super::metadata")]
+    metadata: HashMap<String, Value>,
+}
+
+#[cfg(feature = "bincode")]
+impl From<V1> for V2 {
+    fn from(v1: V1) -> Self {
+        Self {
+            key: v1.key,
+            status: v1.status,
+            headers: v1.headers,
+            body: v1.body,
+            created_at: v1.created_at,
+            expires_at: v1.expires_at,
+            request_id: v1.request_id,
+            fingerprint: v1.fingerprint,
+            compression: v1.compression,
+            encrypted: v1.encrypted,
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct V3 {
+    key: String,
+    #[serde(with = "super::status_code")]
+    status: StatusCode,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    request_id: Option<String>,
+    fingerprint: Option<String>,
+    compression: Option<Codec>,
+    encrypted: bool,
+    #[serde(with = "super::metadata")]
+    metadata: HashMap<String, Value>,
+    key_header: Option<String>,
+}
+
+#[cfg(feature = "bincode")]
+impl From<V2> for V3 {
+    fn from(v2: V2) -> Self {
+        Self {
+            key: v2.key,
+            status: v2.status,
+            headers: v2.headers,
+            body: v2.body,
+            created_at: v2.created_at,
+            expires_at: v2.expires_at,
+            request_id: v2.request_id,
+            fingerprint: v2.fingerprint,
+            compression: v2.compression,
+            encrypted: v2.encrypted,
+            metadata: v2.metadata,
+            key_header: None,
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl From<V3> for CacheElement {
+    fn from(v3: V3) -> Self {
+        Self {
+            key: v3.key,
+            status: v3.status,
+            headers: v3.headers,
+            body: v3.body.into(),
+            created_at: v3.created_at,
+            expires_at: v3.expires_at,
+            request_id: v3.request_id,
+            fingerprint: v3.fingerprint,
+            compression: v3.compression,
+            encrypted: v3.encrypted,
+            metadata: v3.metadata,
+            key_header: v3.key_header,
+            method: None,
+            route: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use actix_web::{http::StatusCode, web::Bytes, HttpResponse};
+    use chrono::Utc;
+
+    use super::*;
+    use crate::HeaderFilter;
+
+    // A response holding nothing but what every layout version could.
+    pub(in crate::store) fn element(key: &str) -> CacheElement {
+        let response = HttpResponse::build(StatusCode::CREATED)
+            .insert_header(("location", "/orders/1"))
+            .finish()
+            .drop_body();
+
+        CacheElement::capture(
+            key.to_owned(),
+            &response,
+            Bytes::from_static(b"{\"id\":1}"),
+            chrono::Duration::hours(1),
+            &HeaderFilter::default(),
+            Utc::now(),
+        )
+        .with_request_id(Some("request-1".to_owned()))
+        .with_fingerprint(Some("fingerprint".to_owned()))
+    }
+
+    // `element` as written in JSON by layout `version`, 0 being from before the envelope
+    pub(in crate::store) fn legacy_json(element: &CacheElement, version: u8) -> Vec<u8> {
+        let mut json = serde_json::to_value(element).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        let added = [
+            (2, "metadata"),
+            (3, "key_header"),
+            (4, "method"),
+            (4, "route"),
+        ];
+        for (since, field) in added {
+            if version < since {
+                fields.remove(field);
+            }
+        }
+
+        let payload = serde_json::to_vec(&json).unwrap();
+        if version == 0 {
+            return payload;
+        }
+        [vec![version, WireFormat::Json.tag()], payload].concat()
+    }
+
+    // `element` as written in bincode by layout `version`
+    #[cfg(feature = "bincode")]
+    pub(in crate::store) fn legacy_bincode(element: &CacheElement, version: u8) -> Vec<u8> {
+        let v3 = V3 {
+            key: element.key.clone(),
+            status: element.status,
+            headers: element.headers.clone(),
+            body: element.body.to_vec(),
+            created_at: element.created_at,
+            expires_at: element.expires_at,
+            request_id: element.request_id.clone(),
+            fingerprint: element.fingerprint.clone(),
+            compression: element.compression,
+            encrypted: element.encrypted,
+            metadata: element.metadata.clone(),
+            key_header: element.key_header.clone(),
+        };
+
+        let config = bincode::config::standard();
+        let payload = match version {
+            1 => {
+                let v1 = V1 {
+                    key: v3.key,
+                    status: v3.status,
+                    headers: v3.headers,
+                    body: v3.body,
+                    created_at: v3.created_at,
+                    expires_at: v3.expires_at,
+                    request_id: v3.request_id,
+                    fingerprint: v3.fingerprint,
+                    compression: v3.compression,
+                    encrypted: v3.encrypted,
+                };
+                bincode::serde::encode_to_vec(v1, config)
+            }
+            2 => {
+                let v2 = V2 {
+                    key: v3.key,
+                    status: v3.status,
+                    headers: v3.headers,
+                    body: v3.body,
+                    created_at: v3.created_at,
+                    expires_at: v3.expires_at,
+                    request_id: v3.request_id,
+                    fingerprint: v3.fingerprint,
+                    compression: v3.compression,
+                    encrypted: v3.encrypted,
+                    metadata: v3.metadata,
+                };
+                bincode::serde::encode_to_vec(v2, config)
+            }
+            3 => bincode::serde::encode_to_vec(v3, config),
+            _ => unreachable!("no legacy layout {version}"),
+        };
+
+        [vec![version, WireFormat::Bincode.tag()], payload.unwrap()].concat()
+    }
+
+    // an entry of every layout older than the current one, as written in every format
+    pub(in crate::store) fn legacy_entries() -> Vec<(CacheElement, Vec<u8>)> {
+        let mut entries = Vec::new();
+        for version in 0..VERSION {
+            let element = element(&format!("json-{version}"));
+            let raw = legacy_json(&element, version);
+            entries.push((element, raw));
+        }
+        #[cfg(feature = "bincode")]
+        for version in 1..VERSION {
+            let element = element(&format!("bincode-{version}"));
+            let raw = legacy_bincode(&element, version);
+            entries.push((element, raw));
+        }
+
+        entries
+    }
+
+    // decodes `raw`, checks it reads back as `element`, and that it is rewritten in the current
+    // layout
+    fn assert_upgraded(raw: &[u8], element: &CacheElement, format: WireFormat) {
+        assert!(format.is_outdated(raw));
+
+        let decoded = decode(raw).unwrap();
+        assert_same(&decoded, element);
+
+        let upgraded = encode(&decoded, format).unwrap();
+        assert_eq!(upgraded[0], VERSION);
+        assert!(!format.is_outdated(&upgraded));
+        assert_same(&decode(&upgraded).unwrap(), element);
+    }
+
+    pub(in crate::store) fn assert_same(decoded: &CacheElement, element: &CacheElement) {
+        assert_eq!(
+            serde_json::to_value(decoded).unwrap(),
+            serde_json::to_value(element).unwrap()
+        );
+    }
+
+    #[test]
+    fn upgrade_rewrites_only_outdated_entries() {
+        for (element, raw) in legacy_entries() {
+            let (decoded, upgraded) = WireFormat::Json.upgrade(&raw).unwrap().unwrap();
+            assert_same(&decoded, &element);
+            assert!(WireFormat::Json.upgrade(&upgraded).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn json_entries_of_every_version_are_upgraded() {
+        let element = element("order-1");
+
+        for version in 0..VERSION {
+            assert_upgraded(&legacy_json(&element, version), &element, WireFormat::Json);
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_entries_of_every_version_are_upgraded() {
+        let element = element("order-1");
+
+        for version in 1..VERSION {
+            let raw = legacy_bincode(&element, version);
+            assert_upgraded(&raw, &element, WireFormat::Bincode);
+        }
+    }
+
+    #[test]
+    fn current_entries_read_back_in_every_format() {
+        let mut element = element("order-1");
+        element.key_header = Some("Idempotency-Key".to_owned());
+        element.method = Some("POST".to_owned());
+        element.route = Some("/orders".to_owned());
+
+        let formats = [
+            WireFormat::Json,
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode,
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack,
+        ];
+        for format in formats {
+            let raw = encode(&element, format).unwrap();
+            assert!(!format.is_outdated(&raw));
+            assert_same(&decode(&raw).unwrap(), &element);
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut raw = encode(&element("order-1"), WireFormat::Json).unwrap();
+        raw[0] = VERSION + 1;
+
+        assert!(decode(&raw).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! Utilities for testing endpoints wrapped in the middleware with [`actix_web::test`].
+//!
+//! [`TestStore`] keeps everything in memory where tests can inspect it and runs on a
+//! [`MockClock`], so entries can be expired without waiting for their TTL. Give the middleware
+//! the same clock, so that the responses it stores are stamped with that time as well.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use actix_web::{test, web, App, HttpResponse};
+//! use actix_web_idempotency::{
+//!     test::{assert_not_replayed, assert_replayed, request, TestStore},
+//!     Idempotency,
+//! };
+//!
+//! # #[actix_web::main]
+//! # async fn main() {
+//! let store = TestStore::new();
+//! let idempotency = Idempotency::builder()
+//!     .store(store.clone())
+//!     .clock(store.clock().clone())
+//!     .build()
+//!     .unwrap();
+//! let app = test::init_service(
+//!     App::new()
+//!         .wrap(idempotency)
+//!         .route("/orders", web::post().to(|| async { HttpResponse::Created().finish() })),
+//! )
+//! .await;
+//!
+//! let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+//! assert_not_replayed(&res);
+//! assert!(store.entry("order-1").is_some());
+//!
+//! let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+//! assert_replayed(&res);
+//!
+//! store.clock().advance(Duration::from_secs(24 * 60 * 60));
+//! let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+//! assert_not_replayed(&res);
+//! # }
+//! ```
+//!
+//! Requires the `test-util` feature.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{dev::ServiceResponse, http::header, test::TestRequest};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    CacheElement, Clock, IdempotencyStore, Invalidation, Reservation, StoreError, StoreFuture,
+    HEADER_KEY,
+};
+
+/// Clock that stands still until moved forward, so that tests decide exactly when entries
+/// expire, however long they take to run.
+///
+/// It starts at midnight UTC on 1 January 2024. Clones share the same time, so hand one to the
+/// middleware with [`IdempotencyBuilder::clock`](crate::IdempotencyBuilder::clock) and keep
+/// another to control it, e.g. to check the `Age` of replays.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::starting_at(DateTime::from_timestamp(1_704_067_200, 0).unwrap())
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clock starting at `now` instead.
+    pub fn starting_at(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// The time according to this clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += chrono::Duration::from_std(duration).unwrap();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        MockClock::now(self)
+    }
+}
+
+#[derive(Clone)]
+enum Slot {
+    // with the token of the reservation and the deadline it lapses at, if any
+    Reserved {
+        token: String,
+        deadline: Option<DateTime<Utc>>,
+    },
+    Complete(Box<CacheElement>),
+}
+
+#[derive(Default)]
+struct State {
+    slots: HashMap<String, Slot>,
+    inserts: usize,
+    crashed: bool,
+}
+
+/// In-memory store exposing what the middleware did with it.
+///
+/// Entries expire according to its [`MockClock`]. Clones share the same entries, so keep one
+/// around to inspect the store after handing another to the middleware. Unlike other stores,
+/// its [`len`](IdempotencyStore::len) only counts the responses that have not expired, leaving
+/// out reservations of requests still executing.
+#[derive(Clone, Default)]
+pub struct TestStore {
+    state: Arc<Mutex<State>>,
+    clock: MockClock,
+}
+
+impl TestStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store running on `clock`, e.g. to share one clock between several stores.
+    pub fn with_clock(clock: MockClock) -> Self {
+        Self {
+            state: Arc::default(),
+            clock,
+        }
+    }
+
+    /// The clock deciding when entries expire.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// The response stored for `key`, if it has not expired.
+    pub fn entry(&self, key: &str) -> Option<CacheElement> {
+        match self.state.lock().unwrap().slots.get(key) {
+            Some(Slot::Complete(element)) if !self.expired(element) => Some((**element).clone()),
+            _ => None,
+        }
+    }
+
+    /// Every stored response that has not expired.
+    pub fn entries(&self) -> Vec<CacheElement> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .slots
+            .values()
+            .filter_map(|slot| match slot {
+                Slot::Complete(element) if !self.expired(element) => Some((**element).clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether a request holding `key` is currently executing.
+    pub fn is_reserved(&self, key: &str) -> bool {
+        matches!(
+            self.state.lock().unwrap().slots.get(key),
+            Some(slot @ Slot::Reserved { .. }) if self.live(Some(slot))
+        )
+    }
+
+    /// How many responses were stored so far. Commits the store turned down, e.g. of a request
+    /// whose reservation was taken over by a retry, do not count.
+    pub fn inserts(&self) -> usize {
+        self.state.lock().unwrap().inserts
+    }
+
+    /// Simulates the process dying while requests are executing: until [`restart`](Self::restart)
+    /// is called, commits and aborts fail without reaching the store, so the reservations taken
+    /// in the meantime stay behind as they would after a crash.
+    ///
+    /// ```
+    /// use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    /// use actix_web_idempotency::{
+    ///     test::{request, TestStore},
+    ///     Idempotency,
+    /// };
+    ///
+    /// # #[actix_web::main]
+    /// # async fn main() {
+    /// let store = TestStore::new();
+    /// let app = test::init_service(
+    ///     App::new()
+    ///         .wrap(Idempotency::builder().store(store.clone()).build().unwrap())
+    ///         .route("/orders", web::post().to(|| async { HttpResponse::Created().finish() })),
+    /// )
+    /// .await;
+    ///
+    /// // the order is placed, but its response never makes it into the store
+    /// store.crash();
+    /// test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+    /// store.restart();
+    /// assert!(store.is_reserved("order-1"));
+    ///
+    /// // so a retry is held up instead of placing the order again
+    /// let res = test::call_service(&app, request("order-1").uri("/orders").to_request()).await;
+    /// assert_eq!(res.status(), StatusCode::CONFLICT);
+    /// assert_eq!(store.inserts(), 0);
+    /// # }
+    /// ```
+    pub fn crash(&self) {
+        self.state.lock().unwrap().crashed = true;
+    }
+
+    /// Ends a simulated [`crash`](Self::crash).
+    pub fn restart(&self) {
+        self.state.lock().unwrap().crashed = false;
+    }
+
+    fn expired(&self, element: &CacheElement) -> bool {
+        element.is_expired_at(self.clock.now())
+    }
+
+    // claims the key unless it holds a response or another reservation that has not lapsed
+    fn reserve_until(&self, reservation: &Reservation, deadline: Option<DateTime<Utc>>) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let reserved = self.claimable(state.slots.get(reservation.key()), reservation);
+        if reserved {
+            let token = reservation.token().to_owned();
+            state.slots.insert(
+                reservation.key().to_owned(),
+                Slot::Reserved { token, deadline },
+            );
+        }
+
+        reserved
+    }
+
+    // whether `slot` is free, or held by `reservation` itself
+    fn claimable(&self, slot: Option<&Slot>, reservation: &Reservation) -> bool {
+        match slot {
+            Some(Slot::Reserved { token, .. }) if token == reservation.token() => true,
+            slot => !self.live(slot),
+        }
+    }
+
+    fn live(&self, slot: Option<&Slot>) -> bool {
+        match slot {
+            Some(Slot::Reserved { deadline, .. }) => {
+                deadline.is_none_or(|deadline| deadline > self.clock.now())
+            }
+            Some(Slot::Complete(element)) => !self.expired(element),
+            None => false,
+        }
+    }
+}
+
+impl IdempotencyStore for TestStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CacheElement>> {
+        let element = self.entry(key);
+
+        Box::pin(async move { Ok(element) })
+    }
+
+    fn reserve<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, bool> {
+        let reserved = self.reserve_until(reservation, None);
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn reserve_for<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        ttl: Duration,
+    ) -> StoreFuture<'a, bool> {
+        let reserved = self.reserve_until(reservation, Some(self.clock.now() + ttl));
+
+        Box::pin(async move { Ok(reserved) })
+    }
+
+    fn abort<'a>(&'a self, reservation: &'a Reservation) -> StoreFuture<'a, ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Box::pin(async { Err(crashed()) });
+        }
+
+        if matches!(
+            state.slots.get(reservation.key()),
+            Some(Slot::Reserved { token, .. }) if token == reservation.token()
+        ) {
+            state.slots.remove(reservation.key());
+        }
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        reservation: &'a Reservation,
+        element: CacheElement,
+    ) -> StoreFuture<'a, bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Box::pin(async { Err(crashed()) });
+        }
+
+        let inserted = self.claimable(state.slots.get(element.key()), reservation);
+        if inserted {
+            state.inserts += 1;
+            state
+                .slots
+                .insert(element.key().to_owned(), Slot::Complete(Box::new(element)));
+        }
+
+        Box::pin(async move { Ok(inserted) })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        let removed = self.state.lock().unwrap().slots.remove(key).is_some();
+
+        Box::pin(async move { Ok(removed) })
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        let len = self
+            .state
+            .lock()
+            .unwrap()
+            .slots
+            .values()
+            .filter(|slot| matches!(slot, Slot::Complete(element) if !self.expired(element)))
+            .count();
+
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.state.lock().unwrap().slots.clear();
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn purge_expired(&self) -> StoreFuture<'_, usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.slots.len();
+        state.slots.retain(|_, slot| self.live(Some(slot)));
+        let reclaimed = before - state.slots.len();
+
+        Box::pin(async move { Ok(reclaimed) })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) -> StoreFuture<'_, usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.slots.len();
+        state.slots.retain(|_, slot| match slot {
+            Slot::Reserved { .. } => true,
+            Slot::Complete(element) => !invalidation.matches(element),
+        });
+        let invalidated = before - state.slots.len();
+
+        Box::pin(async move { Ok(invalidated) })
+    }
+}
+
+fn crashed() -> StoreError {
+    StoreError::backend("the test store crashed")
+}
+
+/// A `POST` request carrying `key` as its idempotency key.
+pub fn request(key: &str) -> TestRequest {
+    TestRequest::post().insert_header((HEADER_KEY, key))
+}
+
+/// Whether `res` was replayed from the store rather than produced by the handler.
+///
+/// Replays are recognized by their `Age` header, which the middleware adds to every replay.
+pub fn is_replayed<B>(res: &ServiceResponse<B>) -> bool {
+    res.headers().contains_key(header::AGE)
+}
+
+/// Panics unless `res` was replayed from the store.
+#[track_caller]
+pub fn assert_replayed<B>(res: &ServiceResponse<B>) {
+    assert!(
+        is_replayed(res),
+        "expected a replayed response, but the handler produced it ({})",
+        res.status()
+    );
+}
+
+/// Panics if `res` was replayed from the store.
+#[track_caller]
+pub fn assert_not_replayed<B>(res: &ServiceResponse<B>) {
+    assert!(
+        !is_replayed(res),
+        "expected the handler to produce the response, but it was replayed ({})",
+        res.status()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, web::Bytes, HttpResponse};
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::HeaderFilter;
+
+    fn ready<T>(future: StoreFuture<'_, T>) -> T {
+        future.now_or_never().unwrap().unwrap()
+    }
+
+    #[test]
+    fn len_counts_only_live_responses() {
+        let store = TestStore::new();
+        let stored = Reservation::new("order-1");
+        assert!(ready(store.reserve(&stored)));
+        assert!(ready(store.reserve(&Reservation::new("order-2"))));
+        assert_eq!(ready(store.len()), 0);
+
+        let element = CacheElement::capture(
+            "order-1".to_owned(),
+            &HttpResponse::with_body(StatusCode::CREATED, ()),
+            Bytes::new(),
+            chrono::Duration::hours(1),
+            &HeaderFilter::default(),
+            store.clock().now(),
+        );
+        assert!(ready(store.commit(&stored, element)));
+        assert_eq!(ready(store.len()), 1);
+
+        store.clock().advance(Duration::from_secs(2 * 60 * 60));
+        assert_eq!(ready(store.len()), 0);
+        assert!(ready(store.is_empty()));
+    }
+}
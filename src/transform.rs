@@ -0,0 +1,78 @@
+use actix_web::{
+    http::{
+        header::{self, HeaderMap},
+        StatusCode,
+    },
+    web::Bytes,
+    Error, HttpRequest, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::CacheElement;
+
+/// A stored response about to be replayed, for a [`ReplayTransformer`] to rewrite.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Replay {
+    pub status: StatusCode,
+    /// Headers as stored, along with `Date` and `Age`. `Content-Length` is left out, as it is
+    /// derived from the body.
+    pub headers: HeaderMap,
+    /// The body as it was sent to the client, i.e. decompressed and decrypted.
+    pub body: Bytes,
+}
+
+impl Replay {
+    pub(crate) fn new(status: StatusCode, mut headers: HeaderMap, body: Bytes) -> Self {
+        headers.remove(header::CONTENT_LENGTH);
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub(crate) fn into_response(self) -> HttpResponse {
+        let mut res = HttpResponse::with_body(self.status, self.body).map_into_boxed_body();
+        *res.headers_mut() = self.headers;
+        res
+    }
+}
+
+/// Hook rewriting stored responses before they are replayed, e.g. to refresh presigned URLs in
+/// the body that expired since the response was stored.
+///
+/// The stored entry is left unchanged, so every replay starts out from the original response.
+/// Errors are sent to the client like those of a handler.
+///
+/// ```
+/// use actix_web::{Error, HttpRequest};
+/// use actix_web_idempotency::{CacheElement, Replay, ReplayTransformer};
+/// use futures_util::future::LocalBoxFuture;
+///
+/// struct Presign;
+///
+/// impl ReplayTransformer for Presign {
+///     fn transform<'a>(
+///         &'a self,
+///         _req: &'a HttpRequest,
+///         _element: &'a CacheElement,
+///         replay: &'a mut Replay,
+///     ) -> LocalBoxFuture<'a, Result<(), Error>> {
+///         Box::pin(async move {
+///             let body = String::from_utf8_lossy(&replay.body).replace("?expires=1", "?expires=2");
+///             replay.body = body.into();
+///             Ok(())
+///         })
+///     }
+/// }
+/// ```
+pub trait ReplayTransformer: Send + Sync {
+    /// Rewrites `replay`, the response stored as `element`, in answer to `req`.
+    fn transform<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        element: &'a CacheElement,
+        replay: &'a mut Replay,
+    ) -> LocalBoxFuture<'a, Result<(), Error>>;
+}
@@ -0,0 +1,38 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+/// Wakes requests waiting for another request with the same key to complete in this process.
+#[derive(Default)]
+pub(crate) struct Waiters {
+    keys: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl Waiters {
+    /// The notifier woken once the request executing `key` completed.
+    pub(crate) fn subscribe(&self, key: &str) -> Arc<Notify> {
+        let mut keys = self.keys.lock().unwrap();
+        Arc::clone(keys.entry(key.to_owned()).or_default())
+    }
+
+    /// Drops the notifier for `key` once its last waiter gave up.
+    pub(crate) fn unsubscribe(&self, key: &str, notify: Arc<Notify>) {
+        let mut keys = self.keys.lock().unwrap();
+
+        // one reference is ours, the other the map's
+        let current = keys.get(key).is_some_and(|held| Arc::ptr_eq(held, &notify));
+        if current && Arc::strong_count(&notify) <= 2 {
+            keys.remove(key);
+        }
+    }
+
+    /// Wakes everyone waiting for `key`.
+    pub(crate) fn notify(&self, key: &str) {
+        if let Some(notify) = self.keys.lock().unwrap().remove(key) {
+            notify.notify_waiters();
+        }
+    }
+}